@@ -0,0 +1,154 @@
+use std::net::IpAddr;
+use std::path::PathBuf;
+use clap::{Parser, Subcommand};
+
+use crate::config::Config;
+use crate::logging::LogFormat;
+
+// Used when --config is not given; a missing default file is not an error
+pub const DEFAULT_CONFIG_PATH: &str = "custom_config.json";
+
+/// Passive network inventory with active protocol probing
+#[derive(Parser)]
+#[command(name = "netflex", version)]
+pub struct Cli {
+    /// Configuration file, JSON, YAML or TOML by extension [default: custom_config.json]
+    #[arg(long, global = true)]
+    pub config: Option<PathBuf>,
+
+    /// Interface to capture on; repeat to capture on several (replaces the configured interfaces)
+    #[arg(long = "interface", short = 'i', global = true)]
+    pub interfaces: Vec<String>,
+
+    /// Saved .pcap/.pcapng capture to replay; repeatable
+    #[arg(long = "read-pcap", short = 'r', global = true)]
+    pub read_pcap: Vec<String>,
+
+    /// Disable active probing; only passive analysis is performed
+    #[arg(long, global = true)]
+    pub no_probe: bool,
+
+    /// Write discoveries as newline-delimited JSON to this file instead of stdout
+    #[arg(long, short = 'o', global = true)]
+    pub output: Option<PathBuf>,
+
+    /// Log filter, e.g. "debug" or "warn,NetFlex::probe=debug" (overrides settings.log_level)
+    #[arg(long, global = true)]
+    pub log_level: Option<String>,
+
+    /// Log line format (overrides settings.log_format)
+    #[arg(long, value_enum, global = true)]
+    pub log_format: Option<LogFormat>,
+
+    /// List the capture devices pcap can open and exit
+    #[arg(long)]
+    pub list_interfaces: bool,
+
+    #[command(subcommand)]
+    pub command: Option<Command>,
+}
+
+#[derive(Subcommand)]
+pub enum Command {
+    /// Capture live traffic from the configured interfaces (default)
+    Capture,
+    /// Replay saved captures instead of capturing live traffic
+    Replay {
+        /// .pcap/.pcapng files to replay
+        #[arg(required = true)]
+        files: Vec<String>,
+        /// Keep the original inter-packet timing
+        #[arg(long)]
+        realtime: bool,
+    },
+    /// Validate the configuration (after command line overrides) and exit
+    CheckConfig,
+    /// Process traffic until the sources end (or Ctrl-C), then print the discovered inventory
+    Inventory {
+        /// Only include flows and services involving this IP
+        #[arg(long)]
+        ip: Option<IpAddr>,
+        /// Only include flows and services on this port
+        #[arg(long)]
+        port: Option<u16>,
+        /// Only include services speaking this protocol (HTTP, COAP, MQTT)
+        #[arg(long)]
+        protocol: Option<String>,
+    },
+}
+
+impl Cli {
+    // Applies command line overrides on top of the loaded configuration
+    pub fn apply_overrides(&self, config: &mut Config) {
+        if !self.interfaces.is_empty() {
+            config.network.interfaces = self.interfaces.clone();
+        }
+        if !self.read_pcap.is_empty() {
+            config.network.pcap_files = Some(self.read_pcap.clone());
+        }
+        if let Some(Command::Replay { files, realtime }) = &self.command {
+            // Replaying never touches live interfaces
+            config.network.interfaces.clear();
+            config.network.pcap_files = Some(files.clone());
+            config.network.replay_realtime = Some(*realtime);
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use clap::CommandFactory;
+
+    fn config() -> Config {
+        serde_json::from_str(
+            r#"{"network": {"interfaces": ["eth0"], "pcap_files": ["old.pcap"], "replay_realtime": true}}"#,
+        )
+        .unwrap()
+    }
+
+    fn overridden(args: &[&str]) -> Config {
+        let cli = Cli::try_parse_from([&["netflex"], args].concat()).unwrap();
+        let mut config = config();
+        cli.apply_overrides(&mut config);
+        config
+    }
+
+    #[test]
+    fn definition_is_consistent() {
+        Cli::command().debug_assert();
+    }
+
+    #[test]
+    fn file_values_stand_without_flags() {
+        let config = overridden(&[]);
+        assert_eq!(config.network.interfaces, ["eth0"]);
+        assert_eq!(config.network.pcap_files, Some(vec!["old.pcap".to_string()]));
+        assert_eq!(config.network.replay_realtime, Some(true));
+    }
+
+    #[test]
+    fn flags_replace_file_values() {
+        let config = overridden(&["-i", "eth1", "--interface", "wlan0", "capture", "-r", "new.pcap"]);
+        assert_eq!(config.network.interfaces, ["eth1", "wlan0"]);
+        assert_eq!(config.network.pcap_files, Some(vec!["new.pcap".to_string()]));
+        assert_eq!(config.network.replay_realtime, Some(true));
+    }
+
+    #[test]
+    fn replay_replaces_interfaces_and_read_pcap() {
+        let config = overridden(&["-i", "eth1", "-r", "flag.pcap", "replay", "a.pcap", "b.pcap"]);
+        assert!(config.network.interfaces.is_empty());
+        assert_eq!(config.network.pcap_files, Some(vec!["a.pcap".to_string(), "b.pcap".to_string()]));
+        // The file asked for realtime replay, the subcommand did not
+        assert_eq!(config.network.replay_realtime, Some(false));
+
+        let config = overridden(&["replay", "--realtime", "a.pcap"]);
+        assert_eq!(config.network.replay_realtime, Some(true));
+    }
+
+    #[test]
+    fn replay_needs_a_file() {
+        assert!(Cli::try_parse_from(["netflex", "replay"]).is_err());
+    }
+}
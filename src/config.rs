@@ -1,28 +1,646 @@
-use serde::Deserialize;
+use std::collections::HashMap;
+use std::error::Error;
+use std::fmt;
+use std::fs::{self as std_fs, File};
+use std::io;
+use std::path::{Path, PathBuf};
+use serde::de::DeserializeOwned;
+use serde::{Deserialize, Deserializer};
+use serde_json::{Map, Value};
+use pcap::Linktype;
 use tokio::fs;
 
-#[derive(Deserialize)]
+use crate::logging::{self, LogFormat};
+
+#[derive(Deserialize, Default)]
+#[serde(deny_unknown_fields)]
 pub struct Config {
+    #[serde(default)]
     pub network: NetworkConfig,
     pub settings: Option<SettingsConfig>,  // Optional for future configurations
+    pub probe: Option<ProbeConfig>,        // Active probing limits, defaults apply when omitted
+    pub flows: Option<FlowConfig>,         // Flow table timeouts and size, defaults apply when omitted
+    pub reassembly: Option<ReassemblyConfig>, // IP fragment buffering limits, defaults apply when omitted
+    #[serde(skip)]
+    pub origin: Option<ConfigOrigin>,      // File the config was read from, None when built from defaults
+    #[serde(skip)]
+    pub ignored_env: Vec<String>,          // NETFLEX_* variables naming no config field, for the caller to warn about
 }
 
-#[derive(Deserialize)]
+#[derive(Deserialize, Default, PartialEq)]
+#[serde(deny_unknown_fields)]
 pub struct NetworkConfig {
+    #[serde(default, deserialize_with = "string_list")]
     pub interfaces: Vec<String>,
+    #[serde(default, deserialize_with = "optional_string_list")]
+    pub pcap_files: Option<Vec<String>>, // Saved .pcap/.pcapng captures to replay through the pipeline
+    pub replay_realtime: Option<bool>,   // Keep the original packet timing when replaying (default: as fast as possible)
+    pub bpf_filter: Option<String>,      // Default BPF capture filter for every interface and pcap file
+    pub interface_filters: Option<HashMap<String, String>>, // BPF filter per interface name, replaces bpf_filter
 }
 
-#[derive(Deserialize)]
+impl NetworkConfig {
+    // The capture filter for an interface: its own filter if set, otherwise the default
+    pub fn filter_for(&self, interface: &str) -> Option<&str> {
+        self.interface_filters
+            .as_ref()
+            .and_then(|filters| filters.get(interface))
+            .or(self.bpf_filter.as_ref())
+            .map(String::as_str)
+    }
+}
+
+#[derive(Deserialize, PartialEq)]
+#[serde(deny_unknown_fields)]
 pub struct SettingsConfig {
-    pub log_level: Option<String>,
-    pub storage_path: Option<String>,
+    pub log_level: Option<String>,       // Log filter, e.g. "info" or "warn,NetFlex::probe=debug"
+    pub log_format: Option<LogFormat>,   // "text" (default) or "json"
+    pub storage_path: Option<String>,            // Directory where discoveries are persisted and reloaded from
+    pub storage_max_file_bytes: Option<u64>,     // Size at which a storage file is rotated (default 64 MiB)
+    pub storage_max_files: Option<usize>,        // Rotated files kept per record kind (default 8)
+}
+
+#[derive(Deserialize, PartialEq)]
+#[serde(deny_unknown_fields)]
+pub struct ProbeConfig {
+    pub max_probes_per_second: Option<f64>, // Global rate across all endpoints
+    pub per_host_interval_secs: Option<u64>, // Minimum gap between probes to the same IP
+    pub reprobe_interval_secs: Option<u64>,  // Age after which a probed endpoint is probed again
+    pub max_concurrent: Option<usize>,       // Probes allowed in flight at once
+}
+
+#[derive(Deserialize, PartialEq)]
+#[serde(deny_unknown_fields)]
+pub struct FlowConfig {
+    pub tcp_timeout_secs: Option<u64>,   // Idle time before a TCP flow is dropped
+    pub udp_timeout_secs: Option<u64>,   // Idle time before a UDP flow is dropped
+    pub other_timeout_secs: Option<u64>, // Idle time for ICMP, ARP and other flows
+    pub max_flows: Option<usize>,        // Hard cap, least recently seen flows are evicted first
+}
+
+#[derive(Deserialize, PartialEq)]
+#[serde(deny_unknown_fields)]
+pub struct ReassemblyConfig {
+    pub fragment_timeout_secs: Option<u64>, // Time to wait for the missing fragments of a datagram
+    pub max_fragment_bytes: Option<usize>,  // Fragment payload held at once, oldest datagrams are dropped first
+    pub max_stream_bytes: Option<usize>,    // Buffered bytes per TCP connection direction before decoding gives up
+    pub max_streams: Option<usize>,         // TCP connections reassembled at once, later ones are not decoded
+    pub stream_timeout_secs: Option<u64>,   // Idle time before a TCP connection's reassembly state is dropped
+}
+
+// Lists may be written as arrays or as one comma separated string, which is
+// also how they arrive from environment variables
+#[derive(Deserialize)]
+#[serde(untagged)]
+enum StringList {
+    List(Vec<String>),
+    Joined(String),
+}
+
+impl From<StringList> for Vec<String> {
+    fn from(list: StringList) -> Self {
+        match list {
+            StringList::List(list) => list,
+            StringList::Joined(joined) => joined
+                .split(',')
+                .map(str::trim)
+                .filter(|item| !item.is_empty())
+                .map(String::from)
+                .collect(),
+        }
+    }
+}
+
+fn string_list<'de, D: Deserializer<'de>>(deserializer: D) -> Result<Vec<String>, D::Error> {
+    StringList::deserialize(deserializer).map(Vec::from)
+}
+
+fn optional_string_list<'de, D: Deserializer<'de>>(deserializer: D) -> Result<Option<Vec<String>>, D::Error> {
+    Ok(Option::<StringList>::deserialize(deserializer)?.map(Vec::from))
+}
+
+// Syntax of a config file, chosen by its extension
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum ConfigFormat {
+    Json,
+    Yaml,
+    Toml,
+}
+
+impl ConfigFormat {
+    // .yaml/.yml and .toml are recognised, anything else is read as JSON
+    pub fn from_path(path: &Path) -> Self {
+        match path.extension().and_then(|e| e.to_str()).map(str::to_ascii_lowercase).as_deref() {
+            Some("yaml") | Some("yml") => ConfigFormat::Yaml,
+            Some("toml") => ConfigFormat::Toml,
+            _ => ConfigFormat::Json,
+        }
+    }
+
+    fn parse<T: DeserializeOwned>(self, text: &str, path: &Path) -> Result<T, ConfigError> {
+        let parse_error = |line: Option<usize>, column: Option<usize>, message: String| ConfigError::Parse {
+            path: path.to_path_buf(),
+            line,
+            column,
+            message,
+        };
+        match self {
+            ConfigFormat::Json => serde_json::from_str(text)
+                .map_err(|e| parse_error(Some(e.line()), Some(e.column()), e.to_string())),
+            ConfigFormat::Yaml => serde_yaml::from_str(text).map_err(|e| {
+                let location = e.location();
+                parse_error(location.as_ref().map(|l| l.line()), location.as_ref().map(|l| l.column()), e.to_string())
+            }),
+            ConfigFormat::Toml => toml::from_str(text).map_err(|e| {
+                let (line, column) = match e.span() {
+                    Some(span) => {
+                        let (line, column) = line_and_column(text, span.start);
+                        (Some(line), Some(column))
+                    }
+                    None => (None, None),
+                };
+                parse_error(line, column, e.message().to_string())
+            }),
+        }
+    }
+}
+
+// 1-based line and column of a byte offset
+fn line_and_column(text: &str, offset: usize) -> (usize, usize) {
+    let before = &text[..offset.min(text.len())];
+    let line_start = before.rfind('\n').map_or(0, |i| i + 1);
+    (before.matches('\n').count() + 1, before[line_start..].chars().count() + 1)
+}
+
+// The file a config was loaded from, named by validation errors and watched for reloads
+pub struct ConfigOrigin {
+    pub path: PathBuf,
+}
+
+// Environment variables starting with this prefix override config fields
+const ENV_PREFIX: &str = "NETFLEX_";
+
+// One probe per nanosecond, the finest period the scheduler can wait for
+const MAX_PROBES_PER_SECOND: f64 = 1e9;
+
+#[derive(Debug)]
+pub enum ConfigError {
+    // The file could not be read
+    Read { path: PathBuf, source: io::Error },
+    // The file cannot be parsed or does not match the expected structure (including unknown keys)
+    Parse { path: PathBuf, line: Option<usize>, column: Option<usize>, message: String },
+    // A NETFLEX_* variable names a config field but its value does not fit it
+    Env { variable: String, message: String },
+    // A value parsed but is unusable. Named by its field rather than a line, since the
+    // value may come from the command line or the environment; path is None without a file.
+    Invalid { path: Option<PathBuf>, field: String, message: String },
+}
+
+impl fmt::Display for ConfigError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            ConfigError::Read { path, source } => {
+                write!(f, "{}: cannot read config: {}", path.display(), source)
+            }
+            ConfigError::Parse { path, line, column, message } => {
+                match (line, column) {
+                    (Some(line), Some(column)) => write!(f, "{}:{}:{}: {}", path.display(), line, column, message),
+                    _ => write!(f, "{}: {}", path.display(), message),
+                }
+            }
+            ConfigError::Env { variable, message } => write!(f, "{}: {}", variable, message),
+            ConfigError::Invalid { path, field, message } => {
+                if let Some(path) = path {
+                    write!(f, "{}: ", path.display())?;
+                }
+                write!(f, "{}: {}", field, message)
+            }
+        }
+    }
+}
+
+impl Error for ConfigError {
+    fn source(&self) -> Option<&(dyn Error + 'static)> {
+        match self {
+            ConfigError::Read { source, .. } => Some(source),
+            _ => None,
+        }
+    }
 }
 
-// Function to load and parse the configuration file
-pub async fn load_config(file_path: &str) -> Config {
+// Function to load and parse the configuration file. The format follows the file
+// extension and NETFLEX_* environment variables are applied on top.
+pub async fn load_config(file_path: &Path) -> Result<Config, ConfigError> {
     let config_data = fs::read_to_string(file_path)
         .await
-        .expect("Failed to read custom config file.");
-    serde_json::from_str(&config_data)
-        .expect("Failed to parse custom config.")
+        .map_err(|source| ConfigError::Read { path: file_path.to_path_buf(), source })?;
+    let format = ConfigFormat::from_path(file_path);
+
+    // The file is deserialized on its own first so its errors keep their position
+    let mut config: Config = format.parse(&config_data, file_path)?;
+    let overrides = env_overrides();
+    if !overrides.is_empty() {
+        let mut document: Value = format.parse(&config_data, file_path)?;
+        apply_env_overrides(&mut document, &mut config, &overrides)?;
+    }
+    config.origin = Some(ConfigOrigin { path: file_path.to_path_buf() });
+    Ok(config)
+}
+
+// Builds a config from defaults and NETFLEX_* environment variables alone
+pub fn config_from_env() -> Result<Config, ConfigError> {
+    let mut config = Config::default();
+    apply_env_overrides(&mut Value::Object(Map::new()), &mut config, &env_overrides())?;
+    Ok(config)
+}
+
+// Fields of each config section, which NETFLEX_* variables are checked against.
+// The config_fields_match_the_structs test keeps it in step with the structs above.
+const CONFIG_FIELDS: [(&str, &[&str]); 5] = [
+    ("network", &["interfaces", "pcap_files", "replay_realtime", "bpf_filter", "interface_filters"]),
+    ("settings", &["log_level", "log_format", "storage_path", "storage_max_file_bytes", "storage_max_files"]),
+    ("probe", &["max_probes_per_second", "per_host_interval_secs", "reprobe_interval_secs", "max_concurrent"]),
+    ("flows", &["tcp_timeout_secs", "udp_timeout_secs", "other_timeout_secs", "max_flows"]),
+    ("reassembly", &["fragment_timeout_secs", "max_fragment_bytes", "max_stream_bytes", "max_streams", "stream_timeout_secs"]),
+];
+
+// A section, a field, or an interface under network.interface_filters
+fn is_config_field(path: &[String]) -> bool {
+    let fields = |section: &str| CONFIG_FIELDS.iter().find(|(name, _)| *name == section).map(|(_, fields)| *fields);
+    match path {
+        [section] => fields(section).is_some(),
+        [section, field] => fields(section).is_some_and(|fields| fields.contains(&field.as_str())),
+        [section, field, _] => section == "network" && field == "interface_filters",
+        _ => false,
+    }
+}
+
+// NETFLEX_* variables in a stable order
+fn env_overrides() -> Vec<(String, String)> {
+    let mut overrides: Vec<(String, String)> = std::env::vars()
+        .filter(|(name, _)| name.starts_with(ENV_PREFIX))
+        .collect();
+    overrides.sort();
+    overrides
+}
+
+// Sets one field per variable and updates `config` to match. Nested fields are separated
+// by a double underscore, e.g. NETFLEX_NETWORK__INTERFACES=eth0,eth1 or
+// NETFLEX_PROBE__MAX_CONCURRENT=4. Variables that name no config field are left out and
+// listed in config.ignored_env.
+fn apply_env_overrides(document: &mut Value, config: &mut Config, overrides: &[(String, String)]) -> Result<(), ConfigError> {
+    let mut ignored = Vec::new();
+    for (variable, raw) in overrides {
+        let env_error = |message: String| ConfigError::Env { variable: variable.clone(), message };
+        let path: Vec<String> = variable[ENV_PREFIX.len()..]
+            .split("__")
+            .map(str::to_ascii_lowercase)
+            .collect();
+        if !is_config_field(&path) {
+            ignored.push(variable.clone());
+            continue;
+        }
+
+        // The value takes the type of the field it lands in: each reading is tried
+        // against the whole config and the first that fits is kept
+        let mut error = None;
+        for value in env_values(raw) {
+            set_field(document, &path, value).map_err(|e| env_error(e.to_string()))?;
+            match serde_json::from_value::<Config>(document.clone()) {
+                Ok(overridden) => {
+                    *config = overridden;
+                    error = None;
+                    break;
+                }
+                Err(e) => error = Some(e),
+            }
+        }
+        if let Some(e) = error {
+            return Err(env_error(e.to_string()));
+        }
+    }
+    config.ignored_env = ignored;
+    Ok(())
+}
+
+fn set_field(document: &mut Value, path: &[String], value: Value) -> Result<(), &'static str> {
+    let mut node = document;
+    for segment in path {
+        // Sections missing from the file (or left empty in YAML) are created
+        if node.is_null() {
+            *node = Value::Object(Map::new());
+        }
+        node = node
+            .as_object_mut()
+            .ok_or("overrides a field inside a value that is not a section")?
+            .entry(segment.clone())
+            .or_insert(Value::Null);
+    }
+    *node = value;
+    Ok(())
+}
+
+// Environment values are untyped; a boolean or number reading comes first, then the raw string
+fn env_values(raw: &str) -> Vec<Value> {
+    let typed = raw
+        .parse::<bool>()
+        .ok()
+        .map(Value::Bool)
+        .or_else(|| raw.parse::<u64>().ok().map(Value::from))
+        .or_else(|| raw.parse::<f64>().ok().and_then(serde_json::Number::from_f64).map(Value::Number));
+    typed.into_iter().chain([Value::String(raw.to_string())]).collect()
+}
+
+impl Config {
+    // Checks everything that parsing cannot: that sources exist, paths are usable
+    // and limits are sane. Every problem found is returned, not just the first.
+    pub fn validate(&self) -> Result<(), Vec<ConfigError>> {
+        let mut errors = Vec::new();
+        let mut invalid = |field: &str, message: String| {
+            errors.push(ConfigError::Invalid {
+                path: self.origin.as_ref().map(|o| o.path.clone()),
+                field: field.to_string(),
+                message,
+            });
+        };
+
+        let pcap_files = self.network.pcap_files.as_deref().unwrap_or_default();
+        if self.network.interfaces.is_empty() && pcap_files.is_empty() {
+            invalid("network", "no interfaces or pcap_files to read".to_string());
+        }
+        if !self.network.interfaces.is_empty() {
+            match pcap::Device::list() {
+                Ok(devices) => {
+                    for interface in &self.network.interfaces {
+                        if !devices.iter().any(|d| &d.name == interface) {
+                            let message = format!("capture device {:?} does not exist (see --list-interfaces)", interface);
+                            invalid("network.interfaces", message);
+                        }
+                    }
+                }
+                Err(e) => invalid("network.interfaces", format!("cannot list capture devices: {}", e)),
+            }
+        }
+        for path in pcap_files {
+            if let Err(e) = File::open(path) {
+                invalid("network.pcap_files", format!("cannot open {:?}: {}", path, e));
+            }
+        }
+        // Filters are compiled against the datalink of every capture they apply to
+        let own_filters = self.network.interface_filters.as_ref();
+        let mut default_checked = false;
+        for interface in &self.network.interfaces {
+            let Some(filter) = self.network.filter_for(interface) else { continue };
+            let own = own_filters.is_some_and(|filters| filters.contains_key(interface));
+            default_checked |= !own;
+            if let Err(e) = check_filter(filter, interface_linktype(interface)) {
+                let message = format!("invalid filter {:?} for {}: {}", filter, interface, e);
+                if own {
+                    invalid(&format!("network.interface_filters.{}", interface), message);
+                } else {
+                    invalid("network.bpf_filter", message);
+                }
+            }
+        }
+        if let Some(filter) = &self.network.bpf_filter {
+            for path in pcap_files {
+                // Files that cannot be opened were reported above
+                let Ok(capture) = pcap::Capture::from_file(path) else { continue };
+                default_checked = true;
+                if let Err(e) = check_filter(filter, capture.get_datalink()) {
+                    invalid("network.bpf_filter", format!("invalid filter {:?} for {}: {}", filter, path, e));
+                }
+            }
+            if !default_checked {
+                if let Err(e) = check_filter(filter, Linktype::ETHERNET) {
+                    invalid("network.bpf_filter", format!("invalid filter {:?}: {}", filter, e));
+                }
+            }
+        }
+        for (interface, filter) in own_filters.into_iter().flatten() {
+            if !self.network.interfaces.contains(interface) {
+                let field = format!("network.interface_filters.{}", interface);
+                invalid(&field, format!("{:?} is not one of the configured interfaces", interface));
+                if let Err(e) = check_filter(filter, Linktype::ETHERNET) {
+                    invalid(&field, format!("invalid filter {:?}: {}", filter, e));
+                }
+            }
+        }
+
+        if let Some(settings) = &self.settings {
+            if let Some(level) = &settings.log_level {
+                if let Err(e) = logging::parse_filter(level) {
+                    invalid("settings.log_level", format!("invalid filter {:?}: {}", level, e));
+                }
+            }
+            if let Some(path) = &settings.storage_path {
+                if let Err(e) = check_writable(Path::new(path)) {
+                    invalid("settings.storage_path", format!("{:?} is not writable: {}", path, e));
+                }
+            }
+            if settings.storage_max_file_bytes == Some(0) {
+                invalid("settings.storage_max_file_bytes", "must be greater than 0".to_string());
+            }
+            if settings.storage_max_files == Some(0) {
+                invalid("settings.storage_max_files", "must be greater than 0".to_string());
+            }
+        }
+
+        if let Some(probe) = &self.probe {
+            if let Some(rate) = probe.max_probes_per_second {
+                if !rate.is_finite() || rate <= 0.0 || rate > MAX_PROBES_PER_SECOND {
+                    let message = format!("must be a positive number up to {}, got {}", MAX_PROBES_PER_SECOND, rate);
+                    invalid("probe.max_probes_per_second", message);
+                }
+            }
+            if probe.reprobe_interval_secs == Some(0) {
+                invalid("probe.reprobe_interval_secs", "must be greater than 0".to_string());
+            }
+            if probe.max_concurrent == Some(0) {
+                invalid("probe.max_concurrent", "must be greater than 0".to_string());
+            }
+        }
+
+        if let Some(flows) = &self.flows {
+            let timeouts = [
+                ("flows.tcp_timeout_secs", flows.tcp_timeout_secs),
+                ("flows.udp_timeout_secs", flows.udp_timeout_secs),
+                ("flows.other_timeout_secs", flows.other_timeout_secs),
+            ];
+            for (field, value) in timeouts {
+                if value == Some(0) {
+                    invalid(field, "must be greater than 0".to_string());
+                }
+            }
+            if flows.max_flows == Some(0) {
+                invalid("flows.max_flows", "must be greater than 0".to_string());
+            }
+        }
+
+        if let Some(reassembly) = &self.reassembly {
+            if reassembly.fragment_timeout_secs == Some(0) {
+                invalid("reassembly.fragment_timeout_secs", "must be greater than 0".to_string());
+            }
+            let limits = [
+                ("reassembly.max_fragment_bytes", reassembly.max_fragment_bytes),
+                ("reassembly.max_stream_bytes", reassembly.max_stream_bytes),
+                ("reassembly.max_streams", reassembly.max_streams),
+            ];
+            for (field, value) in limits {
+                if value == Some(0) {
+                    invalid(field, "must be greater than 0".to_string());
+                }
+            }
+            if reassembly.stream_timeout_secs == Some(0) {
+                invalid("reassembly.stream_timeout_secs", "must be greater than 0".to_string());
+            }
+        }
+
+        if errors.is_empty() {
+            Ok(())
+        } else {
+            Err(errors)
+        }
+    }
+}
+
+// Compiles a BPF expression for a capture of the given datalink
+fn check_filter(filter: &str, linktype: Linktype) -> Result<(), pcap::Error> {
+    pcap::Capture::dead(linktype)?.compile(filter, true).map(|_| ())
+}
+
+// Datalink a capture of the device will have, e.g. Linux cooked capture for "any".
+// Taken from the hardware type the kernel reports, so no device is opened and no
+// capture rights are needed; Ethernet is assumed when it is unknown.
+fn interface_linktype(name: &str) -> Linktype {
+    if name == "any" {
+        return Linktype::LINUX_SLL;
+    }
+    // ARPHRD_* values of <linux/if_arp.h>, mapped to datalinks the way libpcap does
+    let hardware_type = std_fs::read_to_string(Path::new("/sys/class/net").join(name).join("type"));
+    match hardware_type.ok().and_then(|t| t.trim().parse::<u16>().ok()) {
+        Some(768 | 776 | 65534) => Linktype::RAW, // IP-in-IP, SIT and tun devices
+        Some(801) => Linktype::IEEE802_11,
+        Some(803) => Linktype::IEEE802_11_RADIOTAP,
+        _ => Linktype::ETHERNET, // Including loopback, which libpcap presents as Ethernet
+    }
+}
+
+// Proves a file can be written in the directory, or in its nearest existing ancestor
+// when it is yet to be created. Nothing is left behind.
+fn check_writable(dir: &Path) -> io::Result<()> {
+    // A relative path with no existing component lives in the working directory
+    let existing = dir.ancestors().find(|ancestor| ancestor.exists()).unwrap_or(Path::new("."));
+    if !existing.is_dir() {
+        return Err(io::Error::other(format!("{} is not a directory", existing.display())));
+    }
+    let probe = existing.join(format!(".netflex-write-test-{}", std::process::id()));
+    File::create_new(&probe)?;
+    std_fs::remove_file(&probe)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn check_writable_leaves_missing_directories_alone() {
+        let dir = std::env::temp_dir().join(format!("netflex-config-{}", std::process::id())).join("storage");
+        check_writable(&dir).unwrap();
+        assert!(!dir.parent().unwrap().exists());
+    }
+
+    #[test]
+    fn invalid_values_are_reported_by_field() {
+        let mut config: Config =
+            serde_json::from_str(r#"{"network": {"pcap_files": []}, "flows": {"max_flows": 0}}"#).unwrap();
+        config.origin = Some(ConfigOrigin { path: PathBuf::from("netflex.json") });
+        let messages: Vec<String> = config.validate().unwrap_err().iter().map(ToString::to_string).collect();
+        assert_eq!(
+            messages,
+            [
+                "netflex.json: network: no interfaces or pcap_files to read",
+                "netflex.json: flows.max_flows: must be greater than 0",
+            ]
+        );
+        assert_eq!(interface_linktype("any"), Linktype::LINUX_SLL);
+    }
+
+    fn overridden(overrides: &[(&str, &str)]) -> Result<Config, ConfigError> {
+        let overrides: Vec<(String, String)> =
+            overrides.iter().map(|(name, value)| (name.to_string(), value.to_string())).collect();
+        let mut config = Config::default();
+        apply_env_overrides(&mut Value::Object(Map::new()), &mut config, &overrides)?;
+        Ok(config)
+    }
+
+    #[test]
+    fn env_values_take_the_type_of_their_field() {
+        let config = overridden(&[
+            ("NETFLEX_NETWORK__INTERFACES", "eth0,eth1"),
+            ("NETFLEX_NETWORK__INTERFACE_FILTERS__ETH0", "tcp"),
+            ("NETFLEX_PROBE__MAX_CONCURRENT", "4"),
+            ("NETFLEX_PROBE__MAX_PROBES_PER_SECOND", "2"),
+            ("NETFLEX_SETTINGS__STORAGE_PATH", "2024"),
+        ])
+        .unwrap();
+        assert_eq!(config.network.interfaces, vec!["eth0", "eth1"]);
+        assert_eq!(config.network.filter_for("eth0"), Some("tcp"));
+        let probe = config.probe.unwrap();
+        assert_eq!(probe.max_concurrent, Some(4));
+        assert_eq!(probe.max_probes_per_second, Some(2.0));
+        assert_eq!(config.settings.unwrap().storage_path.as_deref(), Some("2024"));
+        assert!(config.ignored_env.is_empty());
+    }
+
+    #[test]
+    fn unknown_env_fields_are_ignored() {
+        let config = overridden(&[
+            ("NETFLEX_PROBE__MAX_CONCURRENT", "4"),
+            ("NETFLEX_PROBE__NO_SUCH_FIELD", "1"),
+            ("NETFLEX_NO_SUCH_SECTION__FIELD", "x"),
+            // Interfaces is a list, not a section
+            ("NETFLEX_NETWORK__INTERFACES__ETH0", "x"),
+            ("NETFLEX_PROBE____MAX_CONCURRENT", "5"),
+        ])
+        .unwrap();
+        assert_eq!(config.probe.unwrap().max_concurrent, Some(4));
+        assert_eq!(
+            config.ignored_env,
+            [
+                "NETFLEX_PROBE__NO_SUCH_FIELD",
+                "NETFLEX_NO_SUCH_SECTION__FIELD",
+                "NETFLEX_NETWORK__INTERFACES__ETH0",
+                "NETFLEX_PROBE____MAX_CONCURRENT",
+            ]
+        );
+    }
+
+    #[test]
+    fn mistyped_env_values_name_their_variable() {
+        let Err(error) = overridden(&[("NETFLEX_PROBE__MAX_CONCURRENT", "many")]) else {
+            panic!("expected an error");
+        };
+        assert!(matches!(error, ConfigError::Env { ref variable, .. } if variable == "NETFLEX_PROBE__MAX_CONCURRENT"));
+        assert!(error.to_string().starts_with("NETFLEX_PROBE__MAX_CONCURRENT: "));
+    }
+
+    #[test]
+    fn config_fields_match_the_structs() {
+        // Every listed field is accepted, with a value of any type that it rejects for its type instead
+        for (section, fields) in CONFIG_FIELDS {
+            for field in fields {
+                let document = serde_json::json!({ section: { *field: { "not": "a valid value" } } });
+                let message = serde_json::from_value::<Config>(document).err().map(|e| e.to_string()).unwrap_or_default();
+                assert!(!message.contains("unknown field"), "{}.{}: {}", section, field, message);
+            }
+        }
+        // A field missing from the list shows up among the expected ones of an unknown field error
+        for (section, fields) in CONFIG_FIELDS {
+            let document = serde_json::json!({ section: { "no_such_field": 1 } });
+            let message = serde_json::from_value::<Config>(document).err().unwrap().to_string();
+            let expected = message.split("expected one of").nth(1).unwrap();
+            assert_eq!(expected.matches('`').count() / 2, fields.len(), "{}", section);
+        }
+    }
 }
@@ -1,5 +1,7 @@
-use std::net::Ipv4Addr;
-use dashmap::DashSet; // Use DashSet for uniqueness
+use std::net::IpAddr;
+use pnet::util::MacAddr;
+use pnet::packet::tcp::TcpFlags;
+use serde::{Deserialize, Serialize};
 use pnet::packet::{
     ethernet::EthernetPacket, 
     ipv4::Ipv4Packet, 
@@ -10,28 +12,79 @@ use pnet::packet::{
     Packet
 };
 use std::sync::Arc;
-use crate::packet::ClonablePacket;
+use crate::packet::{ClonablePacket, Ipv6Extension, Tunnel, TunnelKind};
+use crate::l7::{self, Layer7Message};
+use crate::config::{FlowConfig, ReassemblyConfig};
+use crate::flow::{FlowKey, FlowTable};
+use crate::fragment::FragmentReassembler;
+use crate::icmp::{self, IcmpInfo};
+use crate::stream::StreamTable;
 use std::hash::{Hash, Hasher};
 
 // Implement Hash and PartialEq for PacketInfo to ensure uniqueness
-#[derive(Debug, Clone, Eq)]
+// Addresses are kept typed; serde writes them in their usual string forms
+#[derive(Debug, Clone, Eq, Serialize, Deserialize)]
 pub struct PacketInfo {
-    pub src_mac: Option<String>,
-    pub dst_mac: Option<String>,
-    pub src_ip: Option<String>,
-    pub dst_ip: Option<String>,
+    #[serde(with = "mac_string")]
+    pub src_mac: Option<MacAddr>,
+    #[serde(with = "mac_string")]
+    pub dst_mac: Option<MacAddr>,
+    #[serde(default, with = "mac_string", skip_serializing_if = "Option::is_none")]
+    pub bssid: Option<MacAddr>, // Access point of 802.11 frames
+    pub src_ip: Option<IpAddr>,
+    pub dst_ip: Option<IpAddr>,
+    pub protocol: Option<u8>, // IP protocol number (6 = TCP, 17 = UDP, ...), after any IPv6 extension headers
     pub src_port: Option<u16>,
     pub dst_port: Option<u16>,
+    #[serde(default, skip_serializing_if = "Vec::is_empty")]
+    pub vlan_ids: Vec<u16>, // 802.1Q/802.1ad tags, outermost first
+    #[serde(default, skip_serializing_if = "Vec::is_empty")]
+    pub mpls_labels: Vec<u32>, // MPLS label stack, top label first
+    #[serde(default, skip_serializing_if = "Vec::is_empty")]
+    pub ipv6_extensions: Vec<Ipv6Extension>, // Extension headers seen before the upper-layer header
+    #[serde(default, skip_serializing_if = "Vec::is_empty")]
+    pub tunnels: Vec<TunnelInfo>, // Outermost first; the fields above are the innermost packet's
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub icmp: Option<IcmpInfo>, // Type and code of ICMP/ICMPv6 packets, with quoted headers and NDP details
+}
+
+// Tunnel a packet was carried in, with the endpoints of its outer IP header
+#[derive(Debug, Clone, PartialEq, Eq, Hash, Serialize, Deserialize)]
+pub struct TunnelInfo {
+    pub kind: TunnelKind,
+    pub id: Option<u32>, // GRE key, ERSPAN session ID or VNI
+    pub src_ip: Option<IpAddr>,
+    pub dst_ip: Option<IpAddr>,
+}
+
+impl TunnelInfo {
+    fn new(tunnel: &Tunnel) -> Self {
+        let (src_ip, dst_ip) = if let Some(ipv4) = tunnel.ipv4.as_ref().and_then(|p| p.parse()) {
+            (Some(IpAddr::V4(ipv4.get_source())), Some(IpAddr::V4(ipv4.get_destination())))
+        } else if let Some(ipv6) = tunnel.ipv6.as_ref().and_then(|p| p.parse()) {
+            (Some(IpAddr::V6(ipv6.get_source())), Some(IpAddr::V6(ipv6.get_destination())))
+        } else {
+            (None, None)
+        };
+        TunnelInfo { kind: tunnel.kind, id: tunnel.id, src_ip, dst_ip }
+    }
 }
 
 impl Hash for PacketInfo {
     fn hash<H: Hasher>(&self, state: &mut H) {
         self.src_mac.hash(state);
         self.dst_mac.hash(state);
+        self.bssid.hash(state);
         self.src_ip.hash(state);
         self.dst_ip.hash(state);
+        self.protocol.hash(state);
         self.src_port.hash(state);
         self.dst_port.hash(state);
+        self.vlan_ids.hash(state);
+        self.mpls_labels.hash(state);
+        self.ipv6_extensions.hash(state);
+        self.tunnels.hash(state);
+        self.icmp.hash(state);
     }
 }
 
@@ -39,52 +92,104 @@ impl PartialEq for PacketInfo {
     fn eq(&self, other: &Self) -> bool {
         self.src_mac == other.src_mac &&
         self.dst_mac == other.dst_mac &&
+        self.bssid == other.bssid &&
         self.src_ip == other.src_ip &&
         self.dst_ip == other.dst_ip &&
+        self.protocol == other.protocol &&
         self.src_port == other.src_port &&
-        self.dst_port == other.dst_port
+        self.dst_port == other.dst_port &&
+        self.vlan_ids == other.vlan_ids &&
+        self.mpls_labels == other.mpls_labels &&
+        self.ipv6_extensions == other.ipv6_extensions &&
+        self.tunnels == other.tunnels &&
+        self.icmp == other.icmp
     }
 }
 
+// Serialises MAC addresses as "00:1b:44:11:3a:b7" strings
+pub mod mac_string {
+    use pnet::util::MacAddr;
+    use serde::{de::Error, Deserialize, Deserializer, Serializer};
+
+    pub fn serialize<S: Serializer>(mac: &Option<MacAddr>, serializer: S) -> Result<S::Ok, S::Error> {
+        match mac {
+            Some(mac) => serializer.collect_str(mac),
+            None => serializer.serialize_none(),
+        }
+    }
+
+    pub fn deserialize<'de, D: Deserializer<'de>>(deserializer: D) -> Result<Option<MacAddr>, D::Error> {
+        Option::<String>::deserialize(deserializer)?
+            .map(|mac| mac.parse().map_err(D::Error::custom))
+            .transpose()
+    }
+}
+
+// What a single packet contributed to the extractor's stores
+pub struct Extraction {
+    pub info: PacketInfo,
+    pub layer7: Vec<Layer7Message>, // Messages this packet completed; a TCP segment can complete several
+    pub responder: Option<PacketInfo>, // Set when the packet opened a new flow: the packet as addressed to the server side
+}
+
 pub struct InformationExtractor {
-    pub db: Arc<DashSet<PacketInfo>>, // Use DashSet for storing unique PacketInfo
+    pub flows: Arc<FlowTable>, // Bounded table of active flows, idle ones expire
+    pub fragments: FragmentReassembler, // Rebuilds fragmented datagrams before they are extracted
+    pub streams: StreamTable, // Reassembles TCP payloads for the layer 7 stream decoders
 }
 
 impl InformationExtractor {
-    pub fn new() -> Self {
+    pub fn new(flow_config: Option<&FlowConfig>, reassembly_config: Option<&ReassemblyConfig>) -> Self {
         InformationExtractor {
-            db: Arc::new(DashSet::new()), // Initialize the DashSet
+            flows: Arc::new(FlowTable::new(flow_config)),
+            fragments: FragmentReassembler::new(reassembly_config),
+            streams: StreamTable::new(reassembly_config),
         }
     }
 
-    pub fn extract_and_store(&self, packet: &ClonablePacket) {
+    // Stores the packet metadata and returns what was extracted from it
+    pub fn extract_and_store(&self, packet: &ClonablePacket) -> Extraction {
         let mut info = PacketInfo {
             src_mac: None,
             dst_mac: None,
+            bssid: None,
             src_ip: None,
             dst_ip: None,
+            protocol: None,
             src_port: None,
             dst_port: None,
+            vlan_ids: packet.vlan_ids.clone(),
+            mpls_labels: packet.mpls_labels.clone(),
+            ipv6_extensions: Vec::new(),
+            tunnels: packet.tunnels.iter().map(TunnelInfo::new).collect(),
+            icmp: icmp::decode(packet),
         };
 
         // Extract MAC addresses
         if let Some(eth_packet) = &packet.ethernet {
             if let Some(eth) = eth_packet.parse() {
-                info.src_mac = Some(format!("{}", eth.get_source()));
-                info.dst_mac = Some(format!("{}", eth.get_destination()));
+                info.src_mac = Some(eth.get_source());
+                info.dst_mac = Some(eth.get_destination());
             }
+        } else if let Some(wlan) = &packet.ieee80211 {
+            info.src_mac = Some(wlan.src);
+            info.dst_mac = Some(wlan.dst);
+            info.bssid = wlan.bssid;
         }
 
-        // Extract IP addresses and transport layer ports
+        // Extract IP addresses and the IP protocol number
         if let Some(ipv4_packet) = &packet.ipv4 {
             if let Some(ipv4) = ipv4_packet.parse() {
-                info.src_ip = Some(Ipv4Addr::from(ipv4.get_source()).to_string());
-                info.dst_ip = Some(Ipv4Addr::from(ipv4.get_destination()).to_string());
+                info.src_ip = Some(IpAddr::V4(ipv4.get_source()));
+                info.dst_ip = Some(IpAddr::V4(ipv4.get_destination()));
+                info.protocol = Some(ipv4.get_next_level_protocol().0);
             }
         } else if let Some(ipv6_packet) = &packet.ipv6 {
             if let Some(ipv6) = ipv6_packet.parse() {
-                info.src_ip = Some(format!("{}", ipv6.get_source()));
-                info.dst_ip = Some(format!("{}", ipv6.get_destination()));
+                info.src_ip = Some(IpAddr::V6(ipv6.get_source()));
+                info.dst_ip = Some(IpAddr::V6(ipv6.get_destination()));
+                info.protocol = Some(ipv6_packet.upper_protocol);
+                info.ipv6_extensions = ipv6_packet.extensions.clone();
             }
         }
 
@@ -104,20 +209,93 @@ impl InformationExtractor {
         // Extract ARP information
         if let Some(arp_packet) = &packet.arp {
             if let Some(arp) = arp_packet.parse() {
-                info.src_mac = Some(format!("{}", arp.get_sender_hw_addr()));
-                info.dst_mac = Some(format!("{}", arp.get_target_hw_addr()));
-                info.src_ip = Some(Ipv4Addr::from(arp.get_sender_proto_addr()).to_string());
-                info.dst_ip = Some(Ipv4Addr::from(arp.get_target_proto_addr()).to_string());
+                info.src_mac = Some(arp.get_sender_hw_addr());
+                info.dst_mac = Some(arp.get_target_hw_addr());
+                info.src_ip = Some(IpAddr::V4(arp.get_sender_proto_addr()));
+                info.dst_ip = Some(IpAddr::V4(arp.get_target_proto_addr()));
             }
         }
-       // println!("info: {info:?}");
-        // Store extracted information in the DashSet (only unique entries will be stored)
-        self.db.insert(info);
+        // Decode application layer payloads so they can be attached to the flow;
+        // TCP segments go through stream reassembly first
+        let key = FlowKey::new(&info);
+        let mut layer7: Vec<Layer7Message> = l7::decode(packet).into_iter().collect();
+        if let Some(tcp) = packet.tcp.as_ref().and_then(|t| t.parse()) {
+            layer7.extend(self.streams.record(key, &tcp, packet.timestamp));
+        }
+
+        // Account the packet to its flow (both directions share one entry)
+        let new_flow = self.flows.record(key, &info, packet, &layer7);
+        let responder = new_flow.then(|| responder(&info, packet));
+        Extraction { info, layer7, responder }
     }
+}
 
-    // Method to retrieve all the unique packet information
-    pub fn get_all_packet_info(&self) -> Vec<PacketInfo> {
-        self.db.iter().map(|p| p.clone()).collect()
+// The server side of a flow is the target of its SYN, or else the destination of its
+// first packet. A flow first seen at its SYN-ACK is addressed back to the sender.
+fn responder(info: &PacketInfo, packet: &ClonablePacket) -> PacketInfo {
+    let flags = packet.tcp.as_ref().and_then(|t| t.parse()).map_or(0, |t| t.get_flags());
+    if flags & (TcpFlags::SYN | TcpFlags::ACK) != TcpFlags::SYN | TcpFlags::ACK {
+        return info.clone();
+    }
+    PacketInfo {
+        src_mac: info.dst_mac,
+        dst_mac: info.src_mac,
+        src_ip: info.dst_ip,
+        dst_ip: info.src_ip,
+        src_port: info.dst_port,
+        dst_port: info.src_port,
+        ..info.clone()
     }
 }
 
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::net::Ipv6Addr;
+
+    fn info() -> PacketInfo {
+        PacketInfo {
+            src_mac: Some(MacAddr::new(0x00, 0x1b, 0x44, 0x11, 0x3a, 0xb7)),
+            dst_mac: None,
+            bssid: None,
+            src_ip: Some(IpAddr::V6(Ipv6Addr::new(0x2001, 0xdb8, 0, 0, 0, 0, 0, 1))),
+            dst_ip: Some(IpAddr::V4([10, 0, 0, 1].into())),
+            protocol: Some(17),
+            src_port: Some(5353),
+            dst_port: Some(5353),
+            vlan_ids: Vec::new(),
+            mpls_labels: Vec::new(),
+            ipv6_extensions: Vec::new(),
+            tunnels: Vec::new(),
+            icmp: None,
+        }
+    }
+
+    #[test]
+    fn addresses_round_trip_in_their_string_forms() {
+        let json = serde_json::to_value(info()).unwrap();
+        assert_eq!(json["src_mac"], "00:1b:44:11:3a:b7");
+        assert!(json["dst_mac"].is_null());
+        assert!(json.get("bssid").is_none());
+        assert_eq!(json["src_ip"], "2001:db8::1");
+        assert_eq!(json["dst_ip"], "10.0.0.1");
+        assert_eq!(serde_json::from_value::<PacketInfo>(json).unwrap(), info());
+
+        let mut with_bssid = info();
+        with_bssid.bssid = Some(MacAddr::new(0x02, 0, 0, 0, 0, 0xff));
+        let json = serde_json::to_string(&with_bssid).unwrap();
+        assert!(json.contains(r#""bssid":"02:00:00:00:00:ff""#));
+        assert_eq!(serde_json::from_str::<PacketInfo>(&json).unwrap(), with_bssid);
+    }
+
+    #[test]
+    fn malformed_mac_addresses_are_rejected() {
+        let mut json = serde_json::to_value(info()).unwrap();
+        json["src_mac"] = "00:1b:44".into();
+        assert!(serde_json::from_value::<PacketInfo>(json.clone()).is_err());
+        json["src_mac"] = "not a mac".into();
+        assert!(serde_json::from_value::<PacketInfo>(json.clone()).is_err());
+        json["src_mac"] = 42.into();
+        assert!(serde_json::from_value::<PacketInfo>(json).is_err());
+    }
+}
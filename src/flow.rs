@@ -0,0 +1,347 @@
+use std::collections::VecDeque;
+use std::net::IpAddr;
+use std::sync::{Mutex, RwLock};
+use std::time::{Duration, SystemTime};
+use dashmap::DashMap;
+use pnet::packet::tcp::TcpFlags;
+use serde::Serialize;
+
+use crate::config::FlowConfig;
+use crate::extractor::PacketInfo;
+use crate::l7::Layer7Message;
+use crate::l7::tls::TlsSession;
+use crate::packet::ClonablePacket;
+
+const DEFAULT_TCP_TIMEOUT_SECS: u64 = 600;
+const DEFAULT_UDP_TIMEOUT_SECS: u64 = 120;
+const DEFAULT_OTHER_TIMEOUT_SECS: u64 = 60;
+const DEFAULT_MAX_FLOWS: usize = 100_000;
+
+// TCP flows that have seen a FIN or RST are dropped after this much idle time
+const CLOSED_TCP_TIMEOUT: Duration = Duration::from_secs(30);
+// Minimum packet time between two sweeps for idle flows
+const SWEEP_INTERVAL: Duration = Duration::from_secs(1);
+// Number of decoded layer 7 messages kept per flow, oldest are dropped first
+const MAX_LAYER7_MESSAGES: usize = 32;
+
+const IP_PROTOCOL_TCP: u8 = 6;
+const IP_PROTOCOL_UDP: u8 = 17;
+
+// Identifies a flow by its 5-tuple as seen from the side that sent the first packet.
+// The innermost VLAN ID and tunnel ID keep overlapping address spaces on different
+// VLANs or virtual networks apart.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, PartialOrd, Ord, Hash, Serialize)]
+pub struct FlowKey {
+    pub protocol: u8, // IP protocol number, 0 for non-IP frames such as ARP
+    pub src_ip: Option<IpAddr>,
+    pub src_port: Option<u16>,
+    pub dst_ip: Option<IpAddr>,
+    pub dst_port: Option<u16>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub vlan_id: Option<u16>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub tunnel_id: Option<u32>,
+}
+
+impl FlowKey {
+    pub fn new(info: &PacketInfo) -> Self {
+        FlowKey {
+            protocol: info.protocol.unwrap_or(0),
+            src_ip: info.src_ip,
+            src_port: info.src_port,
+            dst_ip: info.dst_ip,
+            dst_port: info.dst_port,
+            vlan_id: info.vlan_ids.last().copied(),
+            tunnel_id: info.tunnels.last().and_then(|tunnel| tunnel.id),
+        }
+    }
+
+    // The same flow as seen from the other side
+    pub fn reversed(&self) -> Self {
+        FlowKey {
+            protocol: self.protocol,
+            src_ip: self.dst_ip,
+            src_port: self.dst_port,
+            dst_ip: self.src_ip,
+            dst_port: self.src_port,
+            vlan_id: self.vlan_id,
+            tunnel_id: self.tunnel_id,
+        }
+    }
+
+    // The same key for both directions, under which the flow is stored
+    pub fn canonical(&self) -> Self {
+        (*self).min(self.reversed())
+    }
+}
+
+#[derive(Debug, Clone, Serialize)]
+pub struct Flow {
+    pub info: PacketInfo, // Addresses of the first packet, i.e. initiator to responder
+    pub first_seen: SystemTime,
+    pub last_seen: SystemTime,
+    pub packets_forward: u64, // Initiator to responder
+    pub bytes_forward: u64,
+    pub packets_reverse: u64, // Responder to initiator
+    pub bytes_reverse: u64,
+    pub tcp_flags: u8, // Union of all TCP flags seen in either direction
+    pub layer7: Vec<Layer7Message>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub tls: Option<TlsSession>, // SNI, fingerprints and certificate from the handshake, kept after layer7 rolls over
+}
+
+impl Flow {
+    fn new(info: &PacketInfo, now: SystemTime) -> Self {
+        Flow {
+            info: info.clone(),
+            first_seen: now,
+            last_seen: now,
+            packets_forward: 0,
+            bytes_forward: 0,
+            packets_reverse: 0,
+            bytes_reverse: 0,
+            tcp_flags: 0,
+            layer7: Vec::new(),
+            tls: None,
+        }
+    }
+}
+
+// Timeouts and size from FlowConfig with defaults filled in
+#[derive(Debug, Clone, Copy)]
+struct FlowLimits {
+    tcp_timeout: Duration,
+    udp_timeout: Duration,
+    other_timeout: Duration,
+    max_flows: usize,
+}
+
+impl FlowLimits {
+    fn new(config: Option<&FlowConfig>) -> Self {
+        let secs = |value: Option<u64>, default| Duration::from_secs(value.unwrap_or(default));
+        FlowLimits {
+            tcp_timeout: secs(config.and_then(|c| c.tcp_timeout_secs), DEFAULT_TCP_TIMEOUT_SECS),
+            udp_timeout: secs(config.and_then(|c| c.udp_timeout_secs), DEFAULT_UDP_TIMEOUT_SECS),
+            other_timeout: secs(config.and_then(|c| c.other_timeout_secs), DEFAULT_OTHER_TIMEOUT_SECS),
+            max_flows: config.and_then(|c| c.max_flows).unwrap_or(DEFAULT_MAX_FLOWS).max(1),
+        }
+    }
+}
+
+pub struct FlowTable {
+    flows: DashMap<FlowKey, Flow>, // Keyed by FlowKey::canonical
+    // Flows in creation order with the last_seen they were queued with. Eviction
+    // walks it like a clock, giving flows active since they were queued a second pass.
+    age_order: Mutex<VecDeque<(FlowKey, SystemTime)>>,
+    limits: RwLock<FlowLimits>,
+    last_sweep: Mutex<SystemTime>,
+}
+
+impl FlowTable {
+    pub fn new(config: Option<&FlowConfig>) -> Self {
+        FlowTable {
+            flows: DashMap::new(),
+            age_order: Mutex::new(VecDeque::new()),
+            limits: RwLock::new(FlowLimits::new(config)),
+            last_sweep: Mutex::new(SystemTime::UNIX_EPOCH),
+        }
+    }
+
+    // Applies new limits to the running table; a smaller max_flows takes effect as flows are added
+    pub fn reconfigure(&self, config: Option<&FlowConfig>) {
+        *self.limits.write().unwrap() = FlowLimits::new(config);
+    }
+
+    fn limits(&self) -> FlowLimits {
+        *self.limits.read().unwrap()
+    }
+
+    // Accounts a packet to its flow in whichever direction it travels, creating the flow if needed.
+    // Returns true when the packet opened a new flow.
+    pub fn record(&self, key: FlowKey, info: &PacketInfo, packet: &ClonablePacket, layer7: &[Layer7Message]) -> bool {
+        let now = packet.timestamp;
+        self.expire_idle(now);
+
+        let flow_key = key.canonical();
+        let max_flows = self.limits().max_flows;
+        if self.flows.len() >= max_flows && !self.flows.contains_key(&flow_key) {
+            self.evict_oldest(max_flows);
+        }
+
+        let tcp_flags = packet.tcp.as_ref().and_then(|t| t.parse()).map_or(0, |t| t.get_flags());
+        let bytes = packet.raw.len() as u64;
+
+        let mut created = false;
+        // Both directions are looked up and created under the one entry lock
+        let mut flow = self.flows.entry(flow_key).or_insert_with(|| {
+            created = true;
+            Flow::new(info, now)
+        });
+        flow.last_seen = flow.last_seen.max(now);
+        flow.tcp_flags |= tcp_flags;
+        if FlowKey::new(&flow.info) == key {
+            flow.packets_forward += 1;
+            flow.bytes_forward += bytes;
+        } else {
+            flow.packets_reverse += 1;
+            flow.bytes_reverse += bytes;
+        }
+        for message in layer7 {
+            if let Layer7Message::Tls(tls) = message {
+                flow.tls.get_or_insert_with(TlsSession::default).update(tls);
+            }
+            if flow.layer7.len() >= MAX_LAYER7_MESSAGES {
+                flow.layer7.remove(0);
+            }
+            flow.layer7.push(message.clone());
+        }
+        drop(flow);
+
+        if created {
+            self.age_order.lock().unwrap().push_back((flow_key, now));
+        }
+        created
+    }
+
+    // Reinstates a flow recorded by an earlier run. It starts without packet counts
+    // and ages like any other flow from the time it is restored.
+    pub fn restore(&self, info: &PacketInfo) {
+        let flow_key = FlowKey::new(info).canonical();
+        let max_flows = self.limits().max_flows;
+        if self.flows.len() >= max_flows || self.flows.contains_key(&flow_key) {
+            return;
+        }
+        let now = SystemTime::now();
+        self.flows.insert(flow_key, Flow::new(info, now));
+        self.age_order.lock().unwrap().push_back((flow_key, now));
+    }
+
+    pub fn len(&self) -> usize {
+        self.flows.len()
+    }
+
+    // Copies out every active flow
+    pub fn snapshot(&self) -> Vec<Flow> {
+        self.flows.iter().map(|f| f.value().clone()).collect()
+    }
+
+    // Returns the active flows with either endpoint matching every filter that is set
+    pub fn find_flows(&self, ip: Option<IpAddr>, port: Option<u16>) -> Vec<Flow> {
+        self.snapshot()
+            .into_iter()
+            .filter(|flow| {
+                let info = &flow.info;
+                ip.is_none_or(|ip| info.src_ip == Some(ip) || info.dst_ip == Some(ip))
+                    && port.is_none_or(|port| info.src_port == Some(port) || info.dst_port == Some(port))
+            })
+            .collect()
+    }
+
+    fn idle_timeout(limits: &FlowLimits, key: &FlowKey, flow: &Flow) -> Duration {
+        match key.protocol {
+            IP_PROTOCOL_TCP if flow.tcp_flags & (TcpFlags::FIN | TcpFlags::RST) != 0 => {
+                CLOSED_TCP_TIMEOUT.min(limits.tcp_timeout)
+            }
+            IP_PROTOCOL_TCP => limits.tcp_timeout,
+            IP_PROTOCOL_UDP => limits.udp_timeout,
+            _ => limits.other_timeout,
+        }
+    }
+
+    // Drops flows that have been idle for longer than their timeout. Time is taken
+    // from packet timestamps so replayed captures age the same way live traffic does.
+    fn expire_idle(&self, now: SystemTime) {
+        {
+            let mut last_sweep = self.last_sweep.lock().unwrap();
+            if now.duration_since(*last_sweep).unwrap_or_default() < SWEEP_INTERVAL {
+                return;
+            }
+            *last_sweep = now;
+        }
+        let limits = self.limits();
+        self.flows.retain(|key, flow| {
+            now.duration_since(flow.last_seen).unwrap_or_default() < Self::idle_timeout(&limits, key, flow)
+        });
+        self.age_order.lock().unwrap().retain(|(key, _)| self.flows.contains_key(key));
+    }
+
+    // Makes room when the table is full by dropping flows that have been idle the longest,
+    // oldest first among those not seen since they were queued
+    fn evict_oldest(&self, max_flows: usize) {
+        // Also catches up after max_flows was lowered by a config reload
+        let batch = (self.flows.len() + 1).saturating_sub(max_flows).max(max_flows / 100).max(1);
+        let mut age_order = self.age_order.lock().unwrap();
+        // After one full turn every flow has had its second pass
+        let mut second_passes = age_order.len();
+        let mut evicted = 0;
+        while evicted < batch {
+            let Some((key, queued_seen)) = age_order.pop_front() else {
+                break;
+            };
+            let Some(last_seen) = self.flows.get(&key).map(|flow| flow.last_seen) else {
+                continue; // Already expired
+            };
+            if last_seen > queued_seen && second_passes > 0 {
+                second_passes -= 1;
+                age_order.push_back((key, last_seen));
+                continue;
+            }
+            self.flows.remove(&key);
+            evicted += 1;
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use pcap::Linktype;
+    use crate::extractor::InformationExtractor;
+
+    // Ethernet + IPv4 + UDP packet between 10.0.0.<host> addresses, captured `secs` after the epoch
+    fn udp_packet(src: (u8, u16), dst: (u8, u16), secs: u64) -> ClonablePacket {
+        let mut frame = vec![0x02, 0, 0, 0, 0, dst.0, 0x02, 0, 0, 0, 0, src.0, 0x08, 0x00];
+        frame.extend_from_slice(&[0x45, 0x00, 0x00, 0x1c, 0x00, 0x01, 0x00, 0x00, 0x40, 17, 0x00, 0x00]);
+        frame.extend_from_slice(&[10, 0, 0, src.0, 10, 0, 0, dst.0]);
+        frame.extend_from_slice(&src.1.to_be_bytes());
+        frame.extend_from_slice(&dst.1.to_be_bytes());
+        frame.extend_from_slice(&[0x00, 0x08, 0x00, 0x00]);
+        let mut packet = ClonablePacket::new(frame, Linktype::ETHERNET);
+        packet.timestamp = SystemTime::UNIX_EPOCH + Duration::from_secs(secs);
+        packet
+    }
+
+    #[test]
+    fn both_directions_share_one_flow() {
+        let extractor = InformationExtractor::new(None, None);
+        assert!(extractor.extract_and_store(&udp_packet((2, 50000), (1, 53), 0)).responder.is_some());
+        assert!(extractor.extract_and_store(&udp_packet((1, 53), (2, 50000), 0)).responder.is_none());
+        assert!(extractor.extract_and_store(&udp_packet((1, 53), (2, 50000), 1)).responder.is_none());
+
+        let flows = extractor.flows.snapshot();
+        assert_eq!(flows.len(), 1);
+        assert_eq!(flows[0].info.dst_port, Some(53));
+        assert_eq!((flows[0].packets_forward, flows[0].packets_reverse), (1, 2));
+    }
+
+    #[test]
+    fn evicts_idle_flows_first_when_full() {
+        let config = FlowConfig {
+            tcp_timeout_secs: None,
+            udp_timeout_secs: None,
+            other_timeout_secs: None,
+            max_flows: Some(3),
+        };
+        let extractor = InformationExtractor::new(Some(&config), None);
+        for port in 1..=3 {
+            extractor.extract_and_store(&udp_packet((2, 50000), (1, port), port as u64));
+        }
+        // Flow 1 is the oldest but active again, so flow 2 goes instead
+        extractor.extract_and_store(&udp_packet((1, 1), (2, 50000), 4));
+        extractor.extract_and_store(&udp_packet((2, 50000), (1, 4), 5));
+
+        let mut ports: Vec<_> = extractor.flows.snapshot().iter().filter_map(|f| f.info.dst_port).collect();
+        ports.sort_unstable();
+        assert_eq!(ports, vec![1, 3, 4]);
+    }
+}
@@ -0,0 +1,401 @@
+use std::collections::{BTreeMap, HashMap};
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::sync::{Mutex, RwLock};
+use std::time::{Duration, SystemTime};
+use tracing::debug;
+
+use crate::config::ReassemblyConfig;
+use crate::packet::{ClonablePacket, FragmentKey};
+
+const DEFAULT_FRAGMENT_TIMEOUT_SECS: u64 = 30;
+const DEFAULT_MAX_FRAGMENT_BYTES: usize = 4 * 1024 * 1024;
+
+// A datagram split into more pieces than this is dropped; real senders stay far below it
+const MAX_FRAGMENTS_PER_DATAGRAM: usize = 256;
+// Largest datagram IPv4 and (without jumbograms) IPv6 can describe
+const MAX_DATAGRAM_BYTES: usize = 65_535;
+
+#[derive(Clone, Copy)]
+struct ReassemblyLimits {
+    timeout: Duration,
+    max_bytes: usize,
+}
+
+impl ReassemblyLimits {
+    fn new(config: Option<&ReassemblyConfig>) -> Self {
+        ReassemblyLimits {
+            timeout: Duration::from_secs(
+                config.and_then(|c| c.fragment_timeout_secs).unwrap_or(DEFAULT_FRAGMENT_TIMEOUT_SECS),
+            ),
+            max_bytes: config.and_then(|c| c.max_fragment_bytes).unwrap_or(DEFAULT_MAX_FRAGMENT_BYTES).max(1),
+        }
+    }
+}
+
+// Fragments received so far for one datagram, keyed by offset
+struct PartialDatagram {
+    first_seen: SystemTime,
+    pieces: BTreeMap<usize, Vec<u8>>,
+    total_len: Option<usize>, // Known once the last fragment arrived
+    bytes: usize,
+}
+
+// What adding a fragment to a datagram did
+struct Added {
+    overlapping: bool, // Shared bytes with earlier fragments, all of them identical
+    grown: usize,      // Bytes added to the buffer
+}
+
+// Why a fragment made its whole datagram unusable
+enum Rejected {
+    Conflicting(&'static str),
+    Invalid(&'static str),
+}
+
+impl PartialDatagram {
+    fn add(&mut self, offset: usize, more: bool, payload: Vec<u8>) -> Result<Added, Rejected> {
+        let end = offset + payload.len();
+        if end > MAX_DATAGRAM_BYTES {
+            return Err(Rejected::Invalid("fragment past the maximum datagram size"));
+        }
+
+        // Compare against every received piece that shares bytes with this one
+        let mut overlapping = false;
+        for (&start, data) in self.pieces.range(..end) {
+            let overlap_start = start.max(offset);
+            let overlap_end = (start + data.len()).min(end);
+            if overlap_start >= overlap_end {
+                continue;
+            }
+            if data[overlap_start - start..overlap_end - start] != payload[overlap_start - offset..overlap_end - offset] {
+                return Err(Rejected::Conflicting("fragment overlap rewrites received bytes"));
+            }
+            overlapping = true;
+        }
+
+        // Nothing may extend past the last fragment, and there is only one last fragment
+        let conflicting_end = match self.total_len {
+            Some(total_len) => end > total_len || (!more && end != total_len),
+            None => !more && self.pieces.iter().any(|(&start, data)| start + data.len() > end),
+        };
+        if conflicting_end {
+            return Err(Rejected::Conflicting("fragments disagree on datagram length"));
+        }
+        if self.pieces.len() >= MAX_FRAGMENTS_PER_DATAGRAM {
+            return Err(Rejected::Invalid("too many fragments"));
+        }
+
+        if !more {
+            self.total_len = Some(end);
+        }
+        // A piece at an offset already held is kept only if it is longer; the
+        // overlap check above guarantees the shared bytes are the same
+        let held = self.pieces.get(&offset).map_or(0, Vec::len);
+        let grown = payload.len().saturating_sub(held);
+        if grown > 0 || !self.pieces.contains_key(&offset) {
+            self.pieces.insert(offset, payload);
+        }
+        self.bytes += grown;
+        Ok(Added { overlapping, grown })
+    }
+
+    // The reassembled payload once every byte up to the last fragment is present
+    fn assemble(&self) -> Option<Vec<u8>> {
+        let total_len = self.total_len?;
+        let mut payload = Vec::with_capacity(total_len);
+        for (&offset, data) in &self.pieces {
+            if offset > payload.len() {
+                return None;
+            }
+            // Overlaps are identical, so only the part past what is assembled is copied
+            let skip = payload.len() - offset;
+            payload.extend_from_slice(data.get(skip..).unwrap_or_default());
+        }
+        (payload.len() >= total_len).then(|| {
+            payload.truncate(total_len);
+            payload
+        })
+    }
+}
+
+// Counters of what happened to fragments since startup
+#[derive(Debug, Clone, Copy, Default)]
+pub struct FragmentStats {
+    pub reassembled: u64, // Datagrams completed and handed on
+    pub timed_out: u64,   // Incomplete datagrams dropped after fragment_timeout_secs
+    pub evicted: u64,     // Incomplete datagrams dropped to stay within max_fragment_bytes
+    pub overlapping: u64, // Fragments that overlapped earlier ones with the same bytes
+    pub conflicting: u64, // Overlaps that rewrote earlier bytes; the datagram is dropped
+    pub invalid: u64,     // Fragments past the maximum datagram size or count
+}
+
+#[derive(Default)]
+struct Counters {
+    reassembled: AtomicU64,
+    timed_out: AtomicU64,
+    evicted: AtomicU64,
+    overlapping: AtomicU64,
+    conflicting: AtomicU64,
+    invalid: AtomicU64,
+}
+
+#[derive(Default)]
+struct Pending {
+    datagrams: HashMap<FragmentKey, PartialDatagram>,
+    bytes: usize, // Payload bytes buffered across all datagrams
+}
+
+impl Pending {
+    fn remove(&mut self, key: &FragmentKey) -> Option<PartialDatagram> {
+        let datagram = self.datagrams.remove(key)?;
+        self.bytes -= datagram.bytes;
+        Some(datagram)
+    }
+}
+
+// Holds IPv4 and IPv6 fragments until their datagram is complete, so transport and
+// layer 7 decoding only ever see whole datagrams. Overlapping fragments that change
+// bytes already received are treated as an evasion attempt and drop the datagram.
+pub struct FragmentReassembler {
+    pending: Mutex<Pending>,
+    limits: RwLock<ReassemblyLimits>,
+    counters: Counters,
+}
+
+impl FragmentReassembler {
+    pub fn new(config: Option<&ReassemblyConfig>) -> Self {
+        FragmentReassembler {
+            pending: Mutex::new(Pending::default()),
+            limits: RwLock::new(ReassemblyLimits::new(config)),
+            counters: Counters::default(),
+        }
+    }
+
+    // Applies new limits; a smaller max_fragment_bytes takes effect with the next fragment
+    pub fn reconfigure(&self, config: Option<&ReassemblyConfig>) {
+        *self.limits.write().unwrap() = ReassemblyLimits::new(config);
+    }
+
+    // Passes unfragmented packets through. Fragments are held back until the last
+    // missing one arrives, which is then returned with the whole datagram decoded.
+    pub fn reassemble(&self, mut packet: ClonablePacket) -> Option<ClonablePacket> {
+        let Some(fragment) = packet.fragment.take() else {
+            return Some(packet);
+        };
+        let limits = *self.limits.read().unwrap();
+        let now = packet.timestamp;
+        let key = fragment.key;
+        let mut pending = self.pending.lock().unwrap();
+        self.expire(&mut pending, now, limits.timeout);
+
+        let datagram = pending.datagrams.entry(key).or_insert_with(|| PartialDatagram {
+            first_seen: now,
+            pieces: BTreeMap::new(),
+            total_len: None,
+            bytes: 0,
+        });
+        let added = datagram.add(fragment.offset, fragment.more, fragment.payload);
+        let assembled = match &added {
+            Ok(_) => datagram.assemble(),
+            Err(_) => None,
+        };
+
+        match added {
+            Ok(added) => {
+                pending.bytes += added.grown;
+                if added.overlapping {
+                    self.counters.overlapping.fetch_add(1, Ordering::Relaxed);
+                }
+            }
+            Err(rejected) => {
+                let (counter, reason) = match rejected {
+                    Rejected::Conflicting(reason) => (&self.counters.conflicting, reason),
+                    Rejected::Invalid(reason) => (&self.counters.invalid, reason),
+                };
+                pending.remove(&key);
+                counter.fetch_add(1, Ordering::Relaxed);
+                debug!(src = %key.src, dst = %key.dst, id = key.id, reason, "dropped fragmented datagram");
+                return None;
+            }
+        }
+
+        if let Some(payload) = assembled {
+            pending.remove(&key);
+            drop(pending);
+            self.counters.reassembled.fetch_add(1, Ordering::Relaxed);
+            packet.complete_fragment(&key, &payload);
+            return Some(packet);
+        }
+
+        self.evict_oldest(&mut pending, &key, limits.max_bytes);
+        None
+    }
+
+    pub fn stats(&self) -> FragmentStats {
+        let load = |counter: &AtomicU64| counter.load(Ordering::Relaxed);
+        FragmentStats {
+            reassembled: load(&self.counters.reassembled),
+            timed_out: load(&self.counters.timed_out),
+            evicted: load(&self.counters.evicted),
+            overlapping: load(&self.counters.overlapping),
+            conflicting: load(&self.counters.conflicting),
+            invalid: load(&self.counters.invalid),
+        }
+    }
+
+    // Number of datagrams waiting for more fragments
+    pub fn pending(&self) -> usize {
+        self.pending.lock().unwrap().datagrams.len()
+    }
+
+    // Drops datagrams whose first fragment is older than the timeout. Time is taken
+    // from packet timestamps so replayed captures age the same way live traffic does.
+    fn expire(&self, pending: &mut Pending, now: SystemTime, timeout: Duration) {
+        let before = pending.datagrams.len();
+        let mut freed = 0;
+        pending.datagrams.retain(|_, datagram| {
+            let keep = now.duration_since(datagram.first_seen).unwrap_or_default() < timeout;
+            if !keep {
+                freed += datagram.bytes;
+            }
+            keep
+        });
+        pending.bytes -= freed;
+        let expired = before - pending.datagrams.len();
+        self.counters.timed_out.fetch_add(expired as u64, Ordering::Relaxed);
+    }
+
+    // Drops the oldest incomplete datagrams until the buffered bytes fit max_bytes.
+    // The datagram that just received a fragment goes last.
+    fn evict_oldest(&self, pending: &mut Pending, current: &FragmentKey, max_bytes: usize) {
+        while pending.bytes > max_bytes {
+            let oldest = pending
+                .datagrams
+                .iter()
+                .filter(|(key, _)| *key != current)
+                .min_by_key(|(_, datagram)| datagram.first_seen)
+                .map(|(key, _)| *key)
+                .unwrap_or(*current);
+            pending.remove(&oldest);
+            self.counters.evicted.fetch_add(1, Ordering::Relaxed);
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use pcap::Linktype;
+    use pnet::packet::Packet;
+    use crate::packet::Ipv6Extension;
+
+    // UDP 1000 -> 2000 with a 24 byte payload, 32 bytes in all
+    fn udp_datagram() -> Vec<u8> {
+        let mut datagram = vec![0x03, 0xe8, 0x07, 0xd0, 0x00, 0x20, 0x00, 0x00];
+        datagram.extend((0..24).map(|i| b'a' + i));
+        datagram
+    }
+
+    // IPv4 fragment of `udp_datagram()` with identification 7, captured `secs` after the epoch
+    fn ipv4_fragment(offset: usize, more: bool, payload: &[u8], secs: u64) -> ClonablePacket {
+        let flags_offset = (offset / 8) as u16 | if more { 0x2000 } else { 0 };
+        let mut raw = vec![0x45, 0x00];
+        raw.extend_from_slice(&(20 + payload.len() as u16).to_be_bytes());
+        raw.extend_from_slice(&[0x00, 0x07]);
+        raw.extend_from_slice(&flags_offset.to_be_bytes());
+        raw.extend_from_slice(&[0x40, 17, 0x00, 0x00, 10, 0, 0, 1, 10, 0, 0, 2]);
+        raw.extend_from_slice(payload);
+        let mut packet = ClonablePacket::new(raw, Linktype::RAW);
+        packet.timestamp = SystemTime::UNIX_EPOCH + Duration::from_secs(secs);
+        packet
+    }
+
+    fn udp_payload(packet: &ClonablePacket) -> Option<Vec<u8>> {
+        let udp = packet.udp.as_ref()?.parse()?;
+        assert_eq!((udp.get_source(), udp.get_destination()), (1000, 2000));
+        Some(udp.payload().to_vec())
+    }
+
+    #[test]
+    fn reassembles_out_of_order_fragments() {
+        let reassembler = FragmentReassembler::new(None);
+        let datagram = udp_datagram();
+
+        let last = ipv4_fragment(16, false, &datagram[16..], 0);
+        assert!(last.fragment.is_some() && last.udp.is_none());
+        assert!(reassembler.reassemble(last).is_none());
+        assert_eq!(reassembler.pending(), 1);
+
+        let packet = reassembler.reassemble(ipv4_fragment(0, true, &datagram[..16], 0)).unwrap();
+        assert_eq!(udp_payload(&packet).unwrap(), datagram[8..]);
+        assert_eq!(reassembler.pending(), 0);
+        assert_eq!(reassembler.stats().reassembled, 1);
+    }
+
+    #[test]
+    fn passes_whole_datagrams_through() {
+        let reassembler = FragmentReassembler::new(None);
+        let packet = reassembler.reassemble(ipv4_fragment(0, false, &udp_datagram(), 0)).unwrap();
+        assert!(udp_payload(&packet).is_some());
+        assert_eq!(reassembler.stats().reassembled, 0);
+    }
+
+    #[test]
+    fn accepts_identical_overlaps() {
+        let reassembler = FragmentReassembler::new(None);
+        let datagram = udp_datagram();
+        assert!(reassembler.reassemble(ipv4_fragment(0, true, &datagram[..24], 0)).is_none());
+        let packet = reassembler.reassemble(ipv4_fragment(8, false, &datagram[8..], 0)).unwrap();
+        assert_eq!(udp_payload(&packet).unwrap(), datagram[8..]);
+        assert_eq!(reassembler.stats().overlapping, 1);
+    }
+
+    #[test]
+    fn drops_datagrams_with_rewritten_bytes() {
+        let reassembler = FragmentReassembler::new(None);
+        let datagram = udp_datagram();
+        let mut rewritten = datagram[8..].to_vec();
+        rewritten[0] ^= 0xff;
+        assert!(reassembler.reassemble(ipv4_fragment(0, true, &datagram[..16], 0)).is_none());
+        assert!(reassembler.reassemble(ipv4_fragment(8, false, &rewritten, 0)).is_none());
+        assert_eq!(reassembler.stats().conflicting, 1);
+        assert_eq!(reassembler.pending(), 0);
+    }
+
+    #[test]
+    fn expires_incomplete_datagrams() {
+        let reassembler = FragmentReassembler::new(None);
+        let datagram = udp_datagram();
+        assert!(reassembler.reassemble(ipv4_fragment(0, true, &datagram[..16], 0)).is_none());
+        let late = DEFAULT_FRAGMENT_TIMEOUT_SECS + 1;
+        assert!(reassembler.reassemble(ipv4_fragment(16, false, &datagram[16..], late)).is_none());
+        assert_eq!(reassembler.stats().timed_out, 1);
+        assert_eq!(reassembler.pending(), 1);
+    }
+
+    #[test]
+    fn reassembles_ipv6_fragments() {
+        let reassembler = FragmentReassembler::new(None);
+        let datagram = udp_datagram();
+        let fragment = |offset: usize, more: bool, payload: &[u8]| {
+            let mut raw = vec![0x60, 0x00, 0x00, 0x00];
+            raw.extend_from_slice(&(8 + payload.len() as u16).to_be_bytes());
+            raw.extend_from_slice(&[44, 64]);
+            raw.extend_from_slice(&[0x20, 0x01, 0x0d, 0xb8, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 1]);
+            raw.extend_from_slice(&[0x20, 0x01, 0x0d, 0xb8, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 2]);
+            let offset_flags = offset as u16 | more as u16;
+            raw.extend_from_slice(&[17, 0x00]);
+            raw.extend_from_slice(&offset_flags.to_be_bytes());
+            raw.extend_from_slice(&[0x12, 0x34, 0x56, 0x78]);
+            raw.extend_from_slice(payload);
+            ClonablePacket::new(raw, Linktype::RAW)
+        };
+
+        assert!(reassembler.reassemble(fragment(0, true, &datagram[..16])).is_none());
+        let packet = reassembler.reassemble(fragment(16, false, &datagram[16..])).unwrap();
+        assert_eq!(udp_payload(&packet).unwrap(), datagram[8..]);
+        let ipv6 = packet.ipv6.as_ref().unwrap();
+        assert_eq!(ipv6.extensions, vec![Ipv6Extension::Fragment]);
+        assert_eq!(ipv6.upper_protocol, 17);
+    }
+}
@@ -0,0 +1,445 @@
+use std::net::{IpAddr, Ipv4Addr, Ipv6Addr};
+use pnet::packet::ip::IpNextHeaderProtocol;
+use pnet::packet::Packet;
+use pnet::util::MacAddr;
+use serde::{Deserialize, Serialize};
+
+use crate::packet::{ClonablePacket, Ipv6Extension};
+
+// ICMPv4 messages that quote the header of the datagram that caused them (RFC 792)
+const ICMPV4_DESTINATION_UNREACHABLE: u8 = 3;
+const ICMPV4_SOURCE_QUENCH: u8 = 4;
+const ICMPV4_REDIRECT: u8 = 5;
+const ICMPV4_TIME_EXCEEDED: u8 = 11;
+const ICMPV4_PARAMETER_PROBLEM: u8 = 12;
+
+// ICMPv6 error messages, which quote as much of the invoking packet as fits (RFC 4443)
+const ICMPV6_DESTINATION_UNREACHABLE: u8 = 1;
+const ICMPV6_PACKET_TOO_BIG: u8 = 2;
+const ICMPV6_TIME_EXCEEDED: u8 = 3;
+const ICMPV6_PARAMETER_PROBLEM: u8 = 4;
+
+// Neighbor Discovery messages (RFC 4861)
+const NDP_ROUTER_SOLICITATION: u8 = 133;
+const NDP_ROUTER_ADVERTISEMENT: u8 = 134;
+const NDP_NEIGHBOR_SOLICITATION: u8 = 135;
+const NDP_NEIGHBOR_ADVERTISEMENT: u8 = 136;
+
+// Neighbor Discovery options
+const NDP_OPTION_SOURCE_LINK_ADDRESS: u8 = 1;
+const NDP_OPTION_TARGET_LINK_ADDRESS: u8 = 2;
+const NDP_OPTION_PREFIX_INFORMATION: u8 = 3;
+const NDP_OPTION_MTU: u8 = 5;
+const NDP_OPTION_RDNSS: u8 = 25; // Recursive DNS servers (RFC 8106)
+
+// Type and code of an ICMP or ICMPv6 message, with what could be read from its body
+#[derive(Debug, Clone, PartialEq, Eq, Hash, Serialize, Deserialize)]
+pub struct IcmpInfo {
+    #[serde(rename = "type")]
+    pub icmp_type: u8,
+    pub code: u8,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub quoted: Option<QuotedPacket>, // Header of the packet an error message refers to
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub ndp: Option<NdpMessage>,
+}
+
+// Addresses and ports of the packet quoted by an unreachable, time exceeded or similar error.
+// Read from the quoted bytes directly, since they are often cut short after the ports.
+#[derive(Debug, Clone, PartialEq, Eq, Hash, Serialize, Deserialize)]
+pub struct QuotedPacket {
+    pub src_ip: IpAddr,
+    pub dst_ip: IpAddr,
+    pub protocol: u8, // Upper-layer protocol, after any IPv6 extension headers
+    pub src_port: Option<u16>,
+    pub dst_port: Option<u16>,
+}
+
+#[derive(Debug, Clone, PartialEq, Eq, Hash, Serialize, Deserialize)]
+#[serde(tag = "message", rename_all = "snake_case")]
+pub enum NdpMessage {
+    RouterSolicitation {
+        #[serde(default, with = "crate::extractor::mac_string", skip_serializing_if = "Option::is_none")]
+        source_mac: Option<MacAddr>,
+    },
+    // The advertising router is the packet's source address
+    RouterAdvertisement {
+        #[serde(default, with = "crate::extractor::mac_string", skip_serializing_if = "Option::is_none")]
+        source_mac: Option<MacAddr>,
+        hop_limit: u8,
+        managed: bool,      // Addresses are available via DHCPv6
+        other_config: bool, // Other configuration is available via DHCPv6
+        router_lifetime: u16, // Seconds; 0 means the router is not a default router
+        reachable_time: u32,  // Milliseconds
+        retrans_timer: u32,   // Milliseconds
+        mtu: Option<u32>,
+        prefixes: Vec<NdpPrefix>,
+        dns_servers: Vec<Ipv6Addr>,
+    },
+    NeighborSolicitation {
+        target: Ipv6Addr,
+        #[serde(default, with = "crate::extractor::mac_string", skip_serializing_if = "Option::is_none")]
+        source_mac: Option<MacAddr>,
+    },
+    NeighborAdvertisement {
+        target: Ipv6Addr,
+        #[serde(default, with = "crate::extractor::mac_string", skip_serializing_if = "Option::is_none")]
+        target_mac: Option<MacAddr>,
+        router: bool,
+        solicited: bool,
+        override_cache: bool,
+    },
+}
+
+// Prefix Information option of a router advertisement
+#[derive(Debug, Clone, PartialEq, Eq, Hash, Serialize, Deserialize)]
+pub struct NdpPrefix {
+    pub prefix: Ipv6Addr,
+    pub length: u8,
+    pub on_link: bool,
+    pub autonomous: bool, // Usable for stateless address autoconfiguration
+    pub valid_lifetime: u32,
+    pub preferred_lifetime: u32,
+}
+
+// Decodes the ICMP or ICMPv6 layer of a packet, if it has one
+pub fn decode(packet: &ClonablePacket) -> Option<IcmpInfo> {
+    if let Some(icmp) = packet.icmp.as_ref().and_then(|p| p.parse()) {
+        let icmp_type = icmp.get_icmp_type().0;
+        // The payload starts after type, code and checksum; errors quote after 4 more bytes
+        let quoted = match icmp_type {
+            ICMPV4_DESTINATION_UNREACHABLE
+            | ICMPV4_SOURCE_QUENCH
+            | ICMPV4_REDIRECT
+            | ICMPV4_TIME_EXCEEDED
+            | ICMPV4_PARAMETER_PROBLEM => icmp.payload().get(4..).and_then(quoted_ipv4),
+            _ => None,
+        };
+        return Some(IcmpInfo { icmp_type, code: icmp.get_icmp_code().0, quoted, ndp: None });
+    }
+
+    let icmpv6 = packet.icmpv6.as_ref().and_then(|p| p.parse())?;
+    let icmp_type = icmpv6.get_icmpv6_type().0;
+    let quoted = match icmp_type {
+        ICMPV6_DESTINATION_UNREACHABLE | ICMPV6_PACKET_TOO_BIG | ICMPV6_TIME_EXCEEDED | ICMPV6_PARAMETER_PROBLEM => {
+            icmpv6.payload().get(4..).and_then(quoted_ipv6)
+        }
+        _ => None,
+    };
+    Some(IcmpInfo {
+        icmp_type,
+        code: icmpv6.get_icmpv6_code().0,
+        quoted,
+        ndp: parse_ndp(icmp_type, icmpv6.payload()),
+    })
+}
+
+fn quoted_ipv4(data: &[u8]) -> Option<QuotedPacket> {
+    if data.first()? >> 4 != 4 {
+        return None;
+    }
+    let header_len = (data[0] & 0x0f) as usize * 4;
+    let protocol = *data.get(9)?;
+    let src: [u8; 4] = data.get(12..16)?.try_into().ok()?;
+    let dst: [u8; 4] = data.get(16..20)?.try_into().ok()?;
+    // Only the first fragment starts with the transport header
+    let fragment_offset = u16::from_be_bytes([data[6], data[7]]) & 0x1fff;
+    let ports = if fragment_offset == 0 { data.get(header_len.max(20)..).and_then(|t| ports(protocol, t)) } else { None };
+    Some(QuotedPacket {
+        src_ip: IpAddr::V4(Ipv4Addr::from(src)),
+        dst_ip: IpAddr::V4(Ipv4Addr::from(dst)),
+        protocol,
+        src_port: ports.map(|(src, _)| src),
+        dst_port: ports.map(|(_, dst)| dst),
+    })
+}
+
+fn quoted_ipv6(data: &[u8]) -> Option<QuotedPacket> {
+    if data.first()? >> 4 != 6 {
+        return None;
+    }
+    let src: [u8; 16] = data.get(8..24)?.try_into().ok()?;
+    let dst: [u8; 16] = data.get(24..40)?.try_into().ok()?;
+
+    // Walk the quoted extension headers as far as they were included
+    let mut protocol = data[6];
+    let mut payload = data.get(40..);
+    while let (Some(extension), Some(header)) = (Ipv6Extension::from_protocol(IpNextHeaderProtocol(protocol)), payload) {
+        let header_len = match extension {
+            Ipv6Extension::EncapsulatingSecurityPayload => break,
+            // A non-first fragment carries no transport header
+            Ipv6Extension::Fragment if header.get(2..4).is_some_and(|o| u16::from_be_bytes([o[0], o[1]]) >> 3 != 0) => {
+                payload = None;
+                break;
+            }
+            Ipv6Extension::Fragment => 8,
+            Ipv6Extension::Authentication => header.get(1).map_or(0, |len| (*len as usize + 2) * 4),
+            _ => header.get(1).map_or(0, |len| (*len as usize + 1) * 8),
+        };
+        let Some(&next) = header.first() else {
+            payload = None;
+            break;
+        };
+        protocol = next;
+        payload = header.get(header_len..).filter(|_| header_len > 0);
+    }
+
+    let ports = payload.and_then(|t| ports(protocol, t));
+    Some(QuotedPacket {
+        src_ip: IpAddr::V6(Ipv6Addr::from(src)),
+        dst_ip: IpAddr::V6(Ipv6Addr::from(dst)),
+        protocol,
+        src_port: ports.map(|(src, _)| src),
+        dst_port: ports.map(|(_, dst)| dst),
+    })
+}
+
+// Source and destination ports of TCP, UDP, SCTP and UDP-Lite, the first four bytes of each
+fn ports(protocol: u8, transport: &[u8]) -> Option<(u16, u16)> {
+    if !matches!(protocol, 6 | 17 | 132 | 136) {
+        return None;
+    }
+    let ports = transport.get(..4)?;
+    Some((u16::from_be_bytes([ports[0], ports[1]]), u16::from_be_bytes([ports[2], ports[3]])))
+}
+
+// `data` is the message body after the type, code and checksum
+fn parse_ndp(icmp_type: u8, data: &[u8]) -> Option<NdpMessage> {
+    match icmp_type {
+        NDP_ROUTER_SOLICITATION => {
+            let options = NdpOptions::parse(data.get(4..)?);
+            Some(NdpMessage::RouterSolicitation { source_mac: options.source_mac })
+        }
+        NDP_ROUTER_ADVERTISEMENT => {
+            let header = data.get(..12)?;
+            let options = NdpOptions::parse(&data[12..]);
+            Some(NdpMessage::RouterAdvertisement {
+                source_mac: options.source_mac,
+                hop_limit: header[0],
+                managed: header[1] & 0x80 != 0,
+                other_config: header[1] & 0x40 != 0,
+                router_lifetime: u16::from_be_bytes([header[2], header[3]]),
+                reachable_time: u32::from_be_bytes([header[4], header[5], header[6], header[7]]),
+                retrans_timer: u32::from_be_bytes([header[8], header[9], header[10], header[11]]),
+                mtu: options.mtu,
+                prefixes: options.prefixes,
+                dns_servers: options.dns_servers,
+            })
+        }
+        NDP_NEIGHBOR_SOLICITATION => {
+            let target: [u8; 16] = data.get(4..20)?.try_into().ok()?;
+            let options = NdpOptions::parse(&data[20..]);
+            Some(NdpMessage::NeighborSolicitation { target: Ipv6Addr::from(target), source_mac: options.source_mac })
+        }
+        NDP_NEIGHBOR_ADVERTISEMENT => {
+            let target: [u8; 16] = data.get(4..20)?.try_into().ok()?;
+            let options = NdpOptions::parse(&data[20..]);
+            Some(NdpMessage::NeighborAdvertisement {
+                target: Ipv6Addr::from(target),
+                target_mac: options.target_mac,
+                router: data[0] & 0x80 != 0,
+                solicited: data[0] & 0x40 != 0,
+                override_cache: data[0] & 0x20 != 0,
+            })
+        }
+        _ => None,
+    }
+}
+
+// The Neighbor Discovery options that are reported
+#[derive(Default)]
+struct NdpOptions {
+    source_mac: Option<MacAddr>,
+    target_mac: Option<MacAddr>,
+    mtu: Option<u32>,
+    prefixes: Vec<NdpPrefix>,
+    dns_servers: Vec<Ipv6Addr>,
+}
+
+impl NdpOptions {
+    // Options are type, length in 8 byte units (including the first two bytes), value
+    fn parse(mut data: &[u8]) -> Self {
+        let mut options = NdpOptions::default();
+        while let [kind, len, ..] = *data {
+            let len = len as usize * 8;
+            let Some(option) = data.get(..len).filter(|_| len > 0) else {
+                break;
+            };
+            match kind {
+                NDP_OPTION_SOURCE_LINK_ADDRESS => options.source_mac = link_address(option),
+                NDP_OPTION_TARGET_LINK_ADDRESS => options.target_mac = link_address(option),
+                NDP_OPTION_MTU => {
+                    options.mtu = option.get(4..8).map(|mtu| u32::from_be_bytes([mtu[0], mtu[1], mtu[2], mtu[3]]));
+                }
+                NDP_OPTION_PREFIX_INFORMATION => {
+                    if let Some(prefix) = prefix_information(option) {
+                        options.prefixes.push(prefix);
+                    }
+                }
+                NDP_OPTION_RDNSS => {
+                    let addresses = option.get(8..).unwrap_or_default().chunks_exact(16);
+                    options.dns_servers.extend(addresses.filter_map(|a| <[u8; 16]>::try_from(a).ok()).map(Ipv6Addr::from));
+                }
+                _ => {}
+            }
+            data = &data[len..];
+        }
+        options
+    }
+}
+
+// Link-layer address option; Ethernet addresses are the only ones reported
+fn link_address(option: &[u8]) -> Option<MacAddr> {
+    match option {
+        [_, 1, a, b, c, d, e, f] => Some(MacAddr::new(*a, *b, *c, *d, *e, *f)),
+        _ => None,
+    }
+}
+
+// Prefix Information option: prefix length, L and A flags, valid and preferred lifetimes, prefix
+fn prefix_information(option: &[u8]) -> Option<NdpPrefix> {
+    let option = option.get(..32)?;
+    let lifetime = |at: usize| u32::from_be_bytes([option[at], option[at + 1], option[at + 2], option[at + 3]]);
+    let prefix: [u8; 16] = option[16..32].try_into().ok()?;
+    Some(NdpPrefix {
+        prefix: Ipv6Addr::from(prefix),
+        length: option[2],
+        on_link: option[3] & 0x80 != 0,
+        autonomous: option[3] & 0x40 != 0,
+        valid_lifetime: lifetime(4),
+        preferred_lifetime: lifetime(8),
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use pcap::Linktype;
+
+    fn ipv4(protocol: u8, payload: &[u8]) -> ClonablePacket {
+        let mut data = vec![0x45, 0x00];
+        data.extend_from_slice(&((20 + payload.len()) as u16).to_be_bytes());
+        data.extend_from_slice(&[0x00, 0x01, 0x00, 0x00, 64, protocol, 0x00, 0x00]);
+        data.extend_from_slice(&[192, 0, 2, 1, 10, 0, 0, 1]);
+        data.extend_from_slice(payload);
+        ClonablePacket::new(data, Linktype::RAW)
+    }
+
+    fn ipv6_header(next_header: u8, payload_len: usize, src: Ipv6Addr, dst: Ipv6Addr) -> Vec<u8> {
+        let mut header = vec![0x60, 0x00, 0x00, 0x00];
+        header.extend_from_slice(&(payload_len as u16).to_be_bytes());
+        header.extend_from_slice(&[next_header, 255]);
+        header.extend_from_slice(&src.octets());
+        header.extend_from_slice(&dst.octets());
+        header
+    }
+
+    fn icmpv6(src: Ipv6Addr, message: &[u8]) -> ClonablePacket {
+        let mut data = ipv6_header(58, message.len(), src, "ff02::1".parse().unwrap());
+        data.extend_from_slice(message);
+        ClonablePacket::new(data, Linktype::RAW)
+    }
+
+    fn hex(text: &str) -> Vec<u8> {
+        let digits: Vec<u8> = text.bytes().filter(u8::is_ascii_hexdigit).collect();
+        digits.chunks(2).map(|pair| u8::from_str_radix(std::str::from_utf8(pair).unwrap(), 16).unwrap()).collect()
+    }
+
+    #[test]
+    fn reads_the_datagram_quoted_by_time_exceeded() {
+        // A traceroute probe from 10.0.0.1:33434 to 8.8.8.8:33435 that ran out of hops
+        let message = hex("0b000000 00000000 45000030 00000000 01110000 0a000001 08080808 829a829b 001c0000");
+        let info = decode(&ipv4(1, &message)).unwrap();
+        assert_eq!((info.icmp_type, info.code), (ICMPV4_TIME_EXCEEDED, 0));
+        assert_eq!(
+            info.quoted,
+            Some(QuotedPacket {
+                src_ip: IpAddr::V4(Ipv4Addr::new(10, 0, 0, 1)),
+                dst_ip: IpAddr::V4(Ipv4Addr::new(8, 8, 8, 8)),
+                protocol: 17,
+                src_port: Some(33434),
+                dst_port: Some(33435),
+            })
+        );
+
+        // Echo requests quote nothing
+        let info = decode(&ipv4(1, &hex("08000000 00010001 6162"))).unwrap();
+        assert_eq!((info.icmp_type, info.quoted), (8, None));
+    }
+
+    #[test]
+    fn walks_extension_headers_of_the_quoted_packet() {
+        let src: Ipv6Addr = "2001:db8::1".parse().unwrap();
+        let dst: Ipv6Addr = "2001:db8::2".parse().unwrap();
+        // Port unreachable for TCP 49152 -> 80, behind a hop-by-hop header padded with PadN
+        let mut message = hex("01040000 00000000");
+        message.extend(ipv6_header(0, 28, src, dst));
+        message.extend(hex("06000104 00000000 c0000050 00000001"));
+        let info = decode(&icmpv6(dst, &message)).unwrap();
+        assert_eq!((info.icmp_type, info.code), (ICMPV6_DESTINATION_UNREACHABLE, 4));
+        let quoted = info.quoted.unwrap();
+        assert_eq!((quoted.src_ip, quoted.dst_ip), (IpAddr::V6(src), IpAddr::V6(dst)));
+        assert_eq!((quoted.protocol, quoted.src_port, quoted.dst_port), (6, Some(49152), Some(80)));
+        assert_eq!(info.ndp, None);
+    }
+
+    #[test]
+    fn decodes_neighbor_advertisement() {
+        let message = hex("88000000 e0000000 fe800000 00000000 00000000 00000001 0201 001122334455");
+        let info = decode(&icmpv6("fe80::1".parse().unwrap(), &message)).unwrap();
+        assert_eq!(
+            info.ndp,
+            Some(NdpMessage::NeighborAdvertisement {
+                target: "fe80::1".parse().unwrap(),
+                target_mac: Some(MacAddr::new(0x00, 0x11, 0x22, 0x33, 0x44, 0x55)),
+                router: true,
+                solicited: true,
+                override_cache: true,
+            })
+        );
+    }
+
+    #[test]
+    fn decodes_router_advertisement_options() {
+        // Source link-layer address, MTU 1500, prefix 2001:db8:0:1::/64, DNS server 2001:db8::53
+        // and an unknown option, which is skipped
+        let message = hex(concat!(
+            "86000000 40c00708 00000000 00000000",
+            "0101 001122334455",
+            "0501 0000 000005dc",
+            "0304 40c0 00278d00 00093a80 00000000 20010db8000000010000000000000000",
+            "1903 0000 00000e10 20010db8000000000000000000000053",
+            "0e01 000000000000",
+        ));
+        let info = decode(&icmpv6("fe80::1".parse().unwrap(), &message)).unwrap();
+        assert_eq!(
+            info.ndp,
+            Some(NdpMessage::RouterAdvertisement {
+                source_mac: Some(MacAddr::new(0x00, 0x11, 0x22, 0x33, 0x44, 0x55)),
+                hop_limit: 64,
+                managed: true,
+                other_config: true,
+                router_lifetime: 1800,
+                reachable_time: 0,
+                retrans_timer: 0,
+                mtu: Some(1500),
+                prefixes: vec![NdpPrefix {
+                    prefix: "2001:db8:0:1::".parse().unwrap(),
+                    length: 64,
+                    on_link: true,
+                    autonomous: true,
+                    valid_lifetime: 2_592_000,
+                    preferred_lifetime: 604_800,
+                }],
+                dns_servers: vec!["2001:db8::53".parse().unwrap()],
+            })
+        );
+    }
+
+    #[test]
+    fn zero_length_ndp_option_stops_the_walk() {
+        let message = hex("85000000 00000000 0100 001122334455");
+        let info = decode(&icmpv6("fe80::1".parse().unwrap(), &message)).unwrap();
+        assert_eq!(info.ndp, Some(NdpMessage::RouterSolicitation { source_mac: None }));
+    }
+}
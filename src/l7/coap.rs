@@ -0,0 +1,153 @@
+use coap_lite::{CoapOption, MessageType, Packet};
+use serde::Serialize;
+
+pub const COAP_PORT: u16 = 5683;
+
+#[derive(Debug, Clone, PartialEq, Eq, Hash, Serialize)]
+pub struct CoapMessage {
+    pub message_type: String, // CON, NON, ACK or RST
+    pub code: String,         // Class and detail, e.g. "0.01" for GET or "2.05" for Content
+    pub message_id: u16,
+    pub token: String,        // Hex encoded
+    pub uri_host: Option<String>,
+    pub uri_path: Option<String>,
+    pub content_format: Option<u16>,
+}
+
+// Returns true if either port is the registered CoAP port. CoAPS (5684) runs over
+// DTLS; only its handshake is readable, and tls.rs decodes it.
+pub fn is_coap_port(src_port: u16, dst_port: u16) -> bool {
+    src_port == COAP_PORT || dst_port == COAP_PORT
+}
+
+// Decodes a CoAP message from a UDP payload on a registered CoAP port
+pub fn parse(payload: &[u8]) -> Option<CoapMessage> {
+    if !has_valid_header(payload) {
+        return None;
+    }
+    let packet = Packet::from_bytes(payload).ok()?;
+
+    let message_type = match packet.header.get_type() {
+        MessageType::Confirmable => "CON",
+        MessageType::NonConfirmable => "NON",
+        MessageType::Acknowledgement => "ACK",
+        MessageType::Reset => "RST",
+    };
+
+    let uri_path = packet.get_option(CoapOption::UriPath).map(|segments| {
+        segments
+            .iter()
+            .map(|s| format!("/{}", String::from_utf8_lossy(s)))
+            .collect::<String>()
+    });
+
+    Some(CoapMessage {
+        message_type: message_type.to_string(),
+        code: packet.header.get_code(),
+        message_id: packet.header.message_id,
+        token: packet.get_token().iter().map(|b| format!("{:02x}", b)).collect(),
+        uri_host: packet
+            .get_first_option(CoapOption::UriHost)
+            .map(|h| String::from_utf8_lossy(h).into_owned()),
+        uri_path,
+        // Content-Format is a variable length unsigned integer of up to two bytes
+        content_format: packet
+            .get_first_option(CoapOption::ContentFormat)
+            .filter(|v| v.len() <= 2)
+            .map(|v| v.iter().fold(0u16, |acc, b| (acc << 8) | *b as u16)),
+    })
+}
+
+// Stricter variant used for UDP traffic on unregistered ports, where random
+// payloads would otherwise be mistaken for CoAP
+pub fn parse_heuristic(payload: &[u8]) -> Option<CoapMessage> {
+    // Empty messages (code 0.00) are too short to tell apart from noise
+    if payload.len() < 5 || payload[1] == 0 {
+        return None;
+    }
+    parse(payload)
+}
+
+// Checks the version, token length and code class of the fixed header
+fn has_valid_header(payload: &[u8]) -> bool {
+    if payload.len() < 4 {
+        return false;
+    }
+    let version = payload[0] >> 6;
+    let token_length = (payload[0] & 0x0f) as usize;
+    let class = payload[1] >> 5;
+    let detail = payload[1] & 0x1f;
+
+    let code_valid = match class {
+        0 => detail <= 7 && (detail != 0 || payload.len() == 4), // Requests, or a bare empty message
+        2 | 4 | 5 => true,                                       // Responses
+        _ => false,
+    };
+
+    version == 1 && token_length <= 8 && payload.len() >= 4 + token_length && code_valid
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    // CON GET coap://dev/sensors/temp, message ID 0x1234, token ab
+    const GET: &[u8] = &[
+        0x41, 0x01, 0x12, 0x34, 0xab,
+        0x33, b'd', b'e', b'v',
+        0x87, b's', b'e', b'n', b's', b'o', b'r', b's',
+        0x04, b't', b'e', b'm', b'p',
+    ];
+
+    // ACK 2.05 Content with Content-Format 50 (application/json)
+    const CONTENT: &[u8] = &[0x61, 0x45, 0x12, 0x34, 0xab, 0xc1, 0x32, 0xff, b'{', b'}'];
+
+    #[test]
+    fn decodes_request_options() {
+        let message = parse(GET).unwrap();
+        assert_eq!(
+            message,
+            CoapMessage {
+                message_type: "CON".to_string(),
+                code: "0.01".to_string(),
+                message_id: 0x1234,
+                token: "ab".to_string(),
+                uri_host: Some("dev".to_string()),
+                uri_path: Some("/sensors/temp".to_string()),
+                content_format: None,
+            }
+        );
+    }
+
+    #[test]
+    fn decodes_response_content_format() {
+        let message = parse(CONTENT).unwrap();
+        assert_eq!(message.message_type, "ACK");
+        assert_eq!(message.code, "2.05");
+        assert_eq!(message.content_format, Some(50));
+    }
+
+    #[test]
+    fn rejects_invalid_headers() {
+        // Version 2
+        assert!(parse(&[0x81, 0x01, 0x12, 0x34, 0xab]).is_none());
+        // Token longer than the message
+        assert!(parse(&[0x44, 0x01, 0x12, 0x34, 0xab]).is_none());
+        // Reserved code class 1
+        assert!(parse(&[0x40, 0x21, 0x12, 0x34]).is_none());
+    }
+
+    #[test]
+    fn heuristic_skips_empty_messages() {
+        let ping = [0x40, 0x00, 0x12, 0x34];
+        assert!(parse(&ping).is_some());
+        assert!(parse_heuristic(&ping).is_none());
+        assert!(parse_heuristic(GET).is_some());
+    }
+
+    #[test]
+    fn coaps_port_is_not_coap() {
+        assert!(is_coap_port(40000, COAP_PORT));
+        assert!(!is_coap_port(40000, 5684));
+    }
+}
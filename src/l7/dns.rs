@@ -0,0 +1,357 @@
+use std::net::{Ipv4Addr, Ipv6Addr};
+use serde::Serialize;
+use super::{Framed, Layer7Message, StreamDecoder};
+
+pub const DNS_PORT: u16 = 53;
+pub const MDNS_PORT: u16 = 5353;
+
+// Guards against compression pointer loops in malicious packets
+const MAX_POINTER_JUMPS: usize = 16;
+// Caps the number of records parsed from a single message
+const MAX_RECORDS_PER_SECTION: u16 = 64;
+
+pub const TYPE_A: u16 = 1;
+pub const TYPE_CNAME: u16 = 5;
+pub const TYPE_PTR: u16 = 12;
+pub const TYPE_MX: u16 = 15;
+pub const TYPE_TXT: u16 = 16;
+pub const TYPE_AAAA: u16 = 28;
+pub const TYPE_SRV: u16 = 33;
+pub const TYPE_OPT: u16 = 41;
+
+#[derive(Debug, Clone, PartialEq, Eq, Hash, Serialize)]
+pub struct DnsQuestion {
+    pub name: String,
+    pub qtype: u16,
+    pub qclass: u16,
+}
+
+#[derive(Debug, Clone, PartialEq, Eq, Hash, Serialize)]
+pub enum DnsRData {
+    A(Ipv4Addr),
+    Aaaa(Ipv6Addr),
+    Cname(String),
+    Ptr(String),
+    Txt(Vec<String>),
+    Srv { priority: u16, weight: u16, port: u16, target: String },
+    Mx { preference: u16, exchange: String },
+    Other(Vec<u8>), // Raw rdata for record types we do not decode
+}
+
+#[derive(Debug, Clone, PartialEq, Eq, Hash, Serialize)]
+pub struct DnsRecord {
+    pub name: String,
+    pub rtype: u16,
+    pub class: u16,
+    pub ttl: u32,
+    pub rdata: DnsRData,
+}
+
+#[derive(Debug, Clone, PartialEq, Eq, Hash, Serialize)]
+pub struct DnsMessage {
+    pub id: u16,
+    pub is_response: bool,
+    pub opcode: u8,
+    pub rcode: u8,
+    pub questions: Vec<DnsQuestion>,
+    pub answers: Vec<DnsRecord>,
+    pub additional: Vec<DnsRecord>, // mDNS responders put SRV/TXT/A records here
+}
+
+// Returns true if either port indicates DNS or mDNS traffic
+pub fn is_dns_port(src_port: u16, dst_port: u16) -> bool {
+    [DNS_PORT, MDNS_PORT].iter().any(|p| *p == src_port || *p == dst_port)
+}
+
+// Parses a DNS message carried in a UDP datagram
+pub fn parse_udp(payload: &[u8]) -> Option<DnsMessage> {
+    parse_message(payload)
+}
+
+// Frames DNS messages carried over TCP, each prefixed with a two byte length
+pub struct DnsStreamDecoder;
+
+impl StreamDecoder for DnsStreamDecoder {
+    fn accepts(&self, src_port: u16, dst_port: u16, _data: &[u8]) -> bool {
+        is_dns_port(src_port, dst_port)
+    }
+
+    fn decode(&self, data: &[u8]) -> Framed {
+        if data.len() < 2 {
+            return Framed::Incomplete;
+        }
+        let length = u16::from_be_bytes([data[0], data[1]]) as usize;
+        match data.get(2..2 + length) {
+            // A message that does not parse is skipped; the length prefix keeps the stream in sync
+            Some(message) => Framed::Frame {
+                message: parse_message(message).map(Layer7Message::Dns),
+                len: 2 + length,
+            },
+            None => Framed::Incomplete,
+        }
+    }
+}
+
+fn parse_message(data: &[u8]) -> Option<DnsMessage> {
+    let mut reader = Reader { data, pos: 0 };
+
+    let id = reader.u16()?;
+    let flags = reader.u16()?;
+    let qdcount = reader.u16()?;
+    let ancount = reader.u16()?;
+    let nscount = reader.u16()?;
+    let arcount = reader.u16()?;
+
+    // Anything claiming more records than we are willing to walk is unlikely to be DNS
+    if [qdcount, ancount, nscount, arcount].iter().any(|c| *c > MAX_RECORDS_PER_SECTION) {
+        return None;
+    }
+
+    let mut questions = Vec::with_capacity(qdcount as usize);
+    for _ in 0..qdcount {
+        questions.push(DnsQuestion {
+            name: reader.name()?,
+            qtype: reader.u16()?,
+            // The top bit of the class is the mDNS unicast-response flag
+            qclass: reader.u16()? & 0x7fff,
+        });
+    }
+
+    let mut answers = Vec::with_capacity(ancount as usize);
+    for _ in 0..ancount {
+        answers.push(reader.record()?);
+    }
+
+    // Authority records are walked only to reach the additional section
+    for _ in 0..nscount {
+        reader.record()?;
+    }
+
+    let mut additional = Vec::new();
+    for _ in 0..arcount {
+        let record = reader.record()?;
+        if record.rtype != TYPE_OPT {
+            additional.push(record);
+        }
+    }
+
+    Some(DnsMessage {
+        id,
+        is_response: flags & 0x8000 != 0,
+        opcode: ((flags >> 11) & 0x0f) as u8,
+        rcode: (flags & 0x000f) as u8,
+        questions,
+        answers,
+        additional,
+    })
+}
+
+struct Reader<'a> {
+    data: &'a [u8],
+    pos: usize,
+}
+
+impl<'a> Reader<'a> {
+    fn bytes(&mut self, len: usize) -> Option<&'a [u8]> {
+        let slice = self.data.get(self.pos..self.pos.checked_add(len)?)?;
+        self.pos += len;
+        Some(slice)
+    }
+
+    fn u8(&mut self) -> Option<u8> {
+        Some(self.bytes(1)?[0])
+    }
+
+    fn u16(&mut self) -> Option<u16> {
+        let b = self.bytes(2)?;
+        Some(u16::from_be_bytes([b[0], b[1]]))
+    }
+
+    fn u32(&mut self) -> Option<u32> {
+        let b = self.bytes(4)?;
+        Some(u32::from_be_bytes([b[0], b[1], b[2], b[3]]))
+    }
+
+    // Reads a possibly compressed domain name starting at the current position
+    fn name(&mut self) -> Option<String> {
+        let (name, end) = read_name(self.data, self.pos)?;
+        self.pos = end;
+        Some(name)
+    }
+
+    fn record(&mut self) -> Option<DnsRecord> {
+        let name = self.name()?;
+        let rtype = self.u16()?;
+        let class = self.u16()? & 0x7fff; // Strip the mDNS cache-flush bit
+        let ttl = self.u32()?;
+        let rdlength = self.u16()? as usize;
+        let rdata_start = self.pos;
+        let raw = self.bytes(rdlength)?;
+
+        let rdata = match rtype {
+            TYPE_A if rdlength == 4 => DnsRData::A(Ipv4Addr::new(raw[0], raw[1], raw[2], raw[3])),
+            TYPE_AAAA if rdlength == 16 => {
+                let mut octets = [0u8; 16];
+                octets.copy_from_slice(raw);
+                DnsRData::Aaaa(Ipv6Addr::from(octets))
+            }
+            TYPE_CNAME => DnsRData::Cname(read_name(self.data, rdata_start)?.0),
+            TYPE_PTR => DnsRData::Ptr(read_name(self.data, rdata_start)?.0),
+            TYPE_MX if rdlength >= 3 => DnsRData::Mx {
+                preference: u16::from_be_bytes([raw[0], raw[1]]),
+                exchange: read_name(self.data, rdata_start + 2)?.0,
+            },
+            TYPE_SRV if rdlength >= 7 => DnsRData::Srv {
+                priority: u16::from_be_bytes([raw[0], raw[1]]),
+                weight: u16::from_be_bytes([raw[2], raw[3]]),
+                port: u16::from_be_bytes([raw[4], raw[5]]),
+                target: read_name(self.data, rdata_start + 6)?.0,
+            },
+            TYPE_TXT => {
+                let mut strings = Vec::new();
+                let mut txt = Reader { data: raw, pos: 0 };
+                while txt.pos < raw.len() {
+                    let len = txt.u8()? as usize;
+                    strings.push(String::from_utf8_lossy(txt.bytes(len)?).into_owned());
+                }
+                DnsRData::Txt(strings)
+            }
+            _ => DnsRData::Other(raw.to_vec()),
+        };
+
+        Some(DnsRecord { name, rtype, class, ttl, rdata })
+    }
+}
+
+// Decodes the name at `start`, following compression pointers.
+// Returns the dotted name and the offset just past the name in the original position.
+fn read_name(data: &[u8], start: usize) -> Option<(String, usize)> {
+    let mut labels: Vec<String> = Vec::new();
+    let mut pos = start;
+    let mut end = None;
+    let mut jumps = 0;
+
+    loop {
+        let len = *data.get(pos)? as usize;
+        match len & 0xc0 {
+            0x00 => {
+                if len == 0 {
+                    pos += 1;
+                    break;
+                }
+                let label = data.get(pos + 1..pos + 1 + len)?;
+                labels.push(String::from_utf8_lossy(label).into_owned());
+                pos += 1 + len;
+            }
+            0xc0 => {
+                let offset = ((len & 0x3f) << 8) | *data.get(pos + 1)? as usize;
+                if end.is_none() {
+                    end = Some(pos + 2);
+                }
+                jumps += 1;
+                if jumps > MAX_POINTER_JUMPS {
+                    return None;
+                }
+                pos = offset;
+            }
+            _ => return None, // Extended label types are obsolete
+        }
+    }
+
+    Some((labels.join("."), end.unwrap_or(pos)))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    // Query for example.com A, id 0x1234, recursion desired
+    const QUERY: &[u8] = &[
+        0x12, 0x34, 0x01, 0x00, 0x00, 0x01, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00,
+        7, b'e', b'x', b'a', b'm', b'p', b'l', b'e', 3, b'c', b'o', b'm', 0,
+        0x00, 0x01, 0x00, 0x01,
+    ];
+
+    // Response to www.example.com: a CNAME to example.com and its A record,
+    // both names compressed against the question
+    const RESPONSE: &[u8] = &[
+        0xab, 0xcd, 0x81, 0x80, 0x00, 0x01, 0x00, 0x02, 0x00, 0x00, 0x00, 0x00,
+        3, b'w', b'w', b'w', 7, b'e', b'x', b'a', b'm', b'p', b'l', b'e', 3, b'c', b'o', b'm', 0,
+        0x00, 0x01, 0x00, 0x01,
+        0xc0, 0x0c, 0x00, 0x05, 0x00, 0x01, 0x00, 0x00, 0x0e, 0x10, 0x00, 0x02, 0xc0, 0x10,
+        0xc0, 0x10, 0x00, 0x01, 0x00, 0x01, 0x00, 0x00, 0x00, 0x3c, 0x00, 0x04, 93, 184, 216, 34,
+    ];
+
+    #[test]
+    fn parses_query() {
+        let message = parse_udp(QUERY).unwrap();
+        assert_eq!(message.id, 0x1234);
+        assert!(!message.is_response);
+        assert_eq!(
+            message.questions,
+            vec![DnsQuestion { name: "example.com".to_string(), qtype: TYPE_A, qclass: 1 }]
+        );
+        assert!(message.answers.is_empty());
+    }
+
+    #[test]
+    fn follows_compression_pointers() {
+        let message = parse_udp(RESPONSE).unwrap();
+        assert!(message.is_response);
+        assert_eq!(message.rcode, 0);
+        assert_eq!(message.answers.len(), 2);
+        assert_eq!(message.answers[0].name, "www.example.com");
+        assert_eq!(message.answers[0].ttl, 3600);
+        assert_eq!(message.answers[0].rdata, DnsRData::Cname("example.com".to_string()));
+        assert_eq!(message.answers[1].name, "example.com");
+        assert_eq!(message.answers[1].rdata, DnsRData::A(Ipv4Addr::new(93, 184, 216, 34)));
+    }
+
+    #[test]
+    fn reads_mdns_additional_records() {
+        let mut data = vec![
+            0x00, 0x00, 0x84, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x02,
+        ];
+        // printer._ipp._tcp.local SRV 0 0 631 pointing back at its own name, cache-flush bit set
+        data.extend_from_slice(&[7, b'p', b'r', b'i', b'n', b't', b'e', b'r']);
+        data.extend_from_slice(&[4, b'_', b'i', b'p', b'p', 4, b'_', b't', b'c', b'p']);
+        data.extend_from_slice(&[5, b'l', b'o', b'c', b'a', b'l', 0]);
+        data.extend_from_slice(&[0x00, 0x21, 0x80, 0x01, 0x00, 0x00, 0x00, 0x78, 0x00, 0x08]);
+        data.extend_from_slice(&[0x00, 0x00, 0x00, 0x00, 0x02, 0x77, 0xc0, 0x0c]);
+        // TXT "rp=ipp" for the same name
+        data.extend_from_slice(&[0xc0, 0x0c, 0x00, 0x10, 0x80, 0x01, 0x00, 0x00, 0x00, 0x78, 0x00, 0x07]);
+        data.extend_from_slice(&[6, b'r', b'p', b'=', b'i', b'p', b'p']);
+
+        let message = parse_udp(&data).unwrap();
+        assert_eq!(message.additional.len(), 2);
+        assert_eq!(message.additional[0].class, 1);
+        assert_eq!(
+            message.additional[0].rdata,
+            DnsRData::Srv { priority: 0, weight: 0, port: 631, target: "printer._ipp._tcp.local".to_string() }
+        );
+        assert_eq!(message.additional[1].rdata, DnsRData::Txt(vec!["rp=ipp".to_string()]));
+    }
+
+    #[test]
+    fn rejects_pointer_loops_and_truncation() {
+        let mut looped = QUERY[..12].to_vec();
+        looped.extend_from_slice(&[0xc0, 0x0c, 0x00, 0x01, 0x00, 0x01]);
+        assert!(parse_udp(&looped).is_none());
+        assert!(parse_udp(&QUERY[..QUERY.len() - 1]).is_none());
+    }
+
+    #[test]
+    fn frames_tcp_messages_by_length_prefix() {
+        let mut stream = (QUERY.len() as u16).to_be_bytes().to_vec();
+        stream.extend_from_slice(QUERY);
+
+        assert!(matches!(DnsStreamDecoder.decode(&stream[..10]), Framed::Incomplete));
+        match DnsStreamDecoder.decode(&stream) {
+            Framed::Frame { message: Some(Layer7Message::Dns(message)), len } => {
+                assert_eq!(len, stream.len());
+                assert_eq!(message.id, 0x1234);
+            }
+            _ => panic!("expected a DNS frame"),
+        }
+    }
+}
@@ -0,0 +1,191 @@
+use serde::Serialize;
+use super::{Framed, Layer7Message, StreamDecoder};
+
+// Upper bound on headers examined per message
+const MAX_HEADERS: usize = 64;
+
+// Request methods recognised at the start of a TCP payload
+const METHODS: [&[u8]; 9] = [
+    b"GET ", b"POST ", b"PUT ", b"DELETE ", b"HEAD ", b"OPTIONS ", b"PATCH ", b"CONNECT ", b"TRACE ",
+];
+
+#[derive(Debug, Clone, PartialEq, Eq, Hash, Serialize)]
+#[serde(tag = "kind", rename_all = "lowercase")]
+pub enum HttpMessage {
+    Request {
+        method: String,
+        path: String,
+        version: String,
+        host: Option<String>,
+        user_agent: Option<String>,
+        content_type: Option<String>,
+    },
+    Response {
+        version: String,
+        status: u16,
+        reason: Option<String>,
+        server: Option<String>,
+        content_type: Option<String>,
+    },
+}
+
+// Returns true if the payload looks like the start of an HTTP/1.x request or response
+pub fn looks_like_http(payload: &[u8]) -> bool {
+    payload.starts_with(b"HTTP/1.") || METHODS.iter().any(|m| payload.starts_with(m))
+}
+
+// True while the payload is too short to tell but still matches a start line prefix
+fn may_become_http(payload: &[u8]) -> bool {
+    b"HTTP/1.".starts_with(payload) || METHODS.iter().any(|m| m.starts_with(payload))
+}
+
+// Frames HTTP/1.0 and HTTP/1.1 messages by their header block, decoding the start
+// line and interesting headers, and skips Content-Length bodies. A message whose
+// body length is not given (chunked, or read until close) ends the decoding of
+// that direction once its body arrives.
+pub struct HttpStreamDecoder;
+
+impl StreamDecoder for HttpStreamDecoder {
+    // HTTP is recognised by its start line rather than by port
+    fn accepts(&self, _src_port: u16, _dst_port: u16, data: &[u8]) -> bool {
+        looks_like_http(data)
+    }
+
+    fn decode(&self, data: &[u8]) -> Framed {
+        if !looks_like_http(data) {
+            return if may_become_http(data) { Framed::Incomplete } else { Framed::Invalid };
+        }
+
+        let mut headers = [httparse::EMPTY_HEADER; MAX_HEADERS];
+        if data.starts_with(b"HTTP/") {
+            let mut response = httparse::Response::new(&mut headers);
+            let header_len = match response.parse(data) {
+                Ok(httparse::Status::Complete(len)) => len,
+                Ok(httparse::Status::Partial) => return Framed::Incomplete,
+                Err(_) => return Framed::Invalid,
+            };
+            let (Some(version), Some(status)) = (response.version, response.code) else {
+                return Framed::Invalid;
+            };
+            // Informational, No Content and Not Modified responses never have a body
+            let body_len = match status {
+                100..=199 | 204 | 304 => 0,
+                _ => content_length(response.headers),
+            };
+            Framed::Frame {
+                message: Some(Layer7Message::Http(HttpMessage::Response {
+                    version: format_version(version),
+                    status,
+                    reason: response.reason.filter(|r| !r.is_empty()).map(str::to_string),
+                    server: header(response.headers, "Server"),
+                    content_type: header(response.headers, "Content-Type"),
+                })),
+                len: header_len + body_len,
+            }
+        } else {
+            let mut request = httparse::Request::new(&mut headers);
+            let header_len = match request.parse(data) {
+                Ok(httparse::Status::Complete(len)) => len,
+                Ok(httparse::Status::Partial) => return Framed::Incomplete,
+                Err(_) => return Framed::Invalid,
+            };
+            let (Some(method), Some(path), Some(version)) = (request.method, request.path, request.version) else {
+                return Framed::Invalid;
+            };
+            Framed::Frame {
+                message: Some(Layer7Message::Http(HttpMessage::Request {
+                    method: method.to_string(),
+                    path: path.to_string(),
+                    version: format_version(version),
+                    host: header(request.headers, "Host"),
+                    user_agent: header(request.headers, "User-Agent"),
+                    content_type: header(request.headers, "Content-Type"),
+                })),
+                len: header_len + content_length(request.headers),
+            }
+        }
+    }
+}
+
+fn format_version(minor: u8) -> String {
+    format!("HTTP/1.{}", minor)
+}
+
+fn header(headers: &[httparse::Header], name: &str) -> Option<String> {
+    headers
+        .iter()
+        .find(|h| h.name.eq_ignore_ascii_case(name))
+        .map(|h| String::from_utf8_lossy(h.value).trim().to_string())
+}
+
+// Body length from Content-Length, 0 when absent or malformed
+fn content_length(headers: &[httparse::Header]) -> usize {
+    header(headers, "Content-Length").and_then(|len| len.parse().ok()).unwrap_or(0)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn frame(data: &[u8]) -> (HttpMessage, usize) {
+        match HttpStreamDecoder.decode(data) {
+            Framed::Frame { message: Some(Layer7Message::Http(message)), len } => (message, len),
+            _ => panic!("expected an HTTP frame"),
+        }
+    }
+
+    #[test]
+    fn decodes_request_headers() {
+        let request = b"GET /index.html HTTP/1.1\r\nHost: example.com\r\nUser-Agent: curl/8.0\r\n\r\n";
+        let (message, len) = frame(request);
+        assert_eq!(len, request.len());
+        assert_eq!(
+            message,
+            HttpMessage::Request {
+                method: "GET".to_string(),
+                path: "/index.html".to_string(),
+                version: "HTTP/1.1".to_string(),
+                host: Some("example.com".to_string()),
+                user_agent: Some("curl/8.0".to_string()),
+                content_type: None,
+            }
+        );
+    }
+
+    #[test]
+    fn skips_content_length_bodies() {
+        let ok = b"HTTP/1.1 200 OK\r\nServer: nginx\r\nContent-Type: text/plain\r\nContent-Length: 5\r\n\r\nhello";
+        let not_modified = b"HTTP/1.1 304 Not Modified\r\nContent-Length: 5\r\n\r\n";
+        let stream = [&ok[..], &not_modified[..]].concat();
+
+        let (message, len) = frame(&stream);
+        assert_eq!(len, ok.len());
+        assert_eq!(
+            message,
+            HttpMessage::Response {
+                version: "HTTP/1.1".to_string(),
+                status: 200,
+                reason: Some("OK".to_string()),
+                server: Some("nginx".to_string()),
+                content_type: Some("text/plain".to_string()),
+            }
+        );
+
+        // 304 never has a body, whatever Content-Length says
+        let (message, len) = frame(&stream[ok.len()..]);
+        assert!(matches!(message, HttpMessage::Response { status: 304, .. }));
+        assert_eq!(len, not_modified.len());
+    }
+
+    #[test]
+    fn waits_for_partial_messages() {
+        assert!(matches!(HttpStreamDecoder.decode(b"PO"), Framed::Incomplete));
+        assert!(matches!(HttpStreamDecoder.decode(b"GET / HTTP/1.1\r\nHost: a"), Framed::Incomplete));
+    }
+
+    #[test]
+    fn rejects_other_protocols() {
+        assert!(!HttpStreamDecoder.accepts(80, 50000, b"\x16\x03\x01"));
+        assert!(matches!(HttpStreamDecoder.decode(b"SSH-2.0-OpenSSH"), Framed::Invalid));
+    }
+}
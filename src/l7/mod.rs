@@ -0,0 +1,78 @@
+pub mod coap;
+pub mod dns;
+pub mod http;
+pub mod mqtt;
+pub mod tls;
+
+use pnet::packet::Packet;
+use serde::Serialize;
+use crate::packet::ClonablePacket;
+use coap::CoapMessage;
+use dns::DnsMessage;
+use http::HttpMessage;
+use mqtt::MqttMessage;
+use tls::TlsMessage;
+
+// A decoded application layer message, tagged with the protocol it was parsed as
+#[derive(Debug, Clone, PartialEq, Eq, Hash, Serialize)]
+#[serde(tag = "protocol", rename_all = "lowercase")]
+pub enum Layer7Message {
+    Dns(DnsMessage),
+    Http(HttpMessage),
+    Coap(CoapMessage),
+    Mqtt(MqttMessage),
+    Tls(TlsMessage),
+}
+
+// Runs the passive decoders over the UDP payload of a packet. TCP payloads are
+// reassembled by StreamTable and handed to the stream decoders instead.
+pub fn decode(packet: &ClonablePacket) -> Option<Layer7Message> {
+    let udp = packet.udp.as_ref().and_then(|u| u.parse())?;
+    if dns::is_dns_port(udp.get_source(), udp.get_destination()) {
+        return dns::parse_udp(udp.payload()).map(Layer7Message::Dns);
+    }
+    // CoAPS runs over DTLS, only its handshake is readable
+    if tls::is_dtls_port(udp.get_source(), udp.get_destination()) {
+        return tls::parse_dtls(udp.payload()).map(Layer7Message::Tls);
+    }
+    if coap::is_coap_port(udp.get_source(), udp.get_destination()) {
+        return coap::parse(udp.payload()).map(Layer7Message::Coap);
+    }
+    // Devices often run CoAP on vendor specific ports
+    coap::parse_heuristic(udp.payload()).map(Layer7Message::Coap)
+}
+
+// Result of framing the bytes at the start of a reassembled TCP stream
+pub enum Framed {
+    // A whole message, `len` bytes long. `len` may run past the bytes given, e.g.
+    // for a body that is skipped as it arrives. `message` is None for messages
+    // that keep the stream in sync but are not reported.
+    Frame { message: Option<Layer7Message>, len: usize },
+    Incomplete, // More bytes are needed
+    Invalid,    // The stream does not hold this protocol; decoding of the direction stops
+}
+
+// A layer 7 decoder fed the ordered bytes of one direction of a TCP connection.
+// Decoders are stateless; StreamTable keeps the per-direction buffers.
+pub trait StreamDecoder: Sync {
+    // Whether a direction should be decoded by this decoder, given its ports and
+    // the first bytes sent. Called until a decoder accepts the direction.
+    fn accepts(&self, src_port: u16, dst_port: u16, data: &[u8]) -> bool;
+
+    // Frames the message at the start of `data`
+    fn decode(&self, data: &[u8]) -> Framed;
+}
+
+// Consulted in order; port based decoders come before content based ones, except
+// TLS, which is recognised first so TLS on a plaintext protocol's port is not misread
+static STREAM_DECODERS: &[&dyn StreamDecoder] = &[
+    &tls::TlsStreamDecoder,
+    &dns::DnsStreamDecoder,
+    &mqtt::MqttStreamDecoder,
+    &http::HttpStreamDecoder,
+];
+
+// Picks the decoder for a direction of a TCP connection
+pub fn stream_decoder(src_port: u16, dst_port: u16, data: &[u8]) -> Option<&'static dyn StreamDecoder> {
+    STREAM_DECODERS.iter().copied().find(|decoder| decoder.accepts(src_port, dst_port, data))
+}
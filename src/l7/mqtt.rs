@@ -0,0 +1,262 @@
+use serde::Serialize;
+use super::{Framed, Layer7Message, StreamDecoder};
+
+pub const MQTT_PORT: u16 = 1883;
+
+const CONNECT: u8 = 1;
+const CONNACK: u8 = 2;
+const PUBLISH: u8 = 3;
+const SUBSCRIBE: u8 = 8;
+
+// Protocol level used by MQTT 5.0 in the CONNECT variable header
+const MQTT_V5: u8 = 5;
+
+#[derive(Debug, Clone, PartialEq, Eq, Hash, Serialize)]
+#[serde(tag = "packet", rename_all = "lowercase")]
+pub enum MqttMessage {
+    Connect {
+        protocol_level: u8, // 3 = 3.1, 4 = 3.1.1, 5 = 5.0
+        client_id: String,
+        has_username: bool,
+        has_password: bool,
+        keep_alive: u16,
+    },
+    ConnAck {
+        session_present: bool,
+        return_code: u8, // Reason code for MQTT 5.0
+    },
+    Publish {
+        topic: String,
+        qos: u8,
+        retain: bool,
+    },
+    Subscribe {
+        topics: Vec<String>,
+    },
+}
+
+// Returns true if either port is the registered MQTT port. MQTT over TLS (8883)
+// is left to the TLS decoder, which reads its handshake.
+pub fn is_mqtt_port(src_port: u16, dst_port: u16) -> bool {
+    src_port == MQTT_PORT || dst_port == MQTT_PORT
+}
+
+// Frames MQTT control packets and decodes CONNECT, CONNACK, PUBLISH and SUBSCRIBE.
+// Other control packets (PINGREQ, acknowledgements, ...) are skipped over.
+pub struct MqttStreamDecoder;
+
+impl StreamDecoder for MqttStreamDecoder {
+    fn accepts(&self, src_port: u16, dst_port: u16, _data: &[u8]) -> bool {
+        is_mqtt_port(src_port, dst_port)
+    }
+
+    fn decode(&self, data: &[u8]) -> Framed {
+        let Some(&first) = data.first() else {
+            return Framed::Incomplete;
+        };
+        let packet_type = first >> 4;
+        let flags = first & 0x0f;
+        // Reserved packet type, this is not MQTT
+        if packet_type == 0 {
+            return Framed::Invalid;
+        }
+        let (remaining_length, header_length) = match read_varint(&data[1..]) {
+            Some(varint) => varint,
+            // The remaining length takes at most four bytes
+            None if data.len() > 4 => return Framed::Invalid,
+            None => return Framed::Incomplete,
+        };
+        let start = 1 + header_length;
+        let len = start + remaining_length;
+
+        // Both QoS bits set is malformed (MQTT 3.1.1 section 3.3.1.2)
+        if packet_type == PUBLISH && flags & 0x06 == 0x06 {
+            return Framed::Invalid;
+        }
+        // PUBLISH payloads can be large; the topic at their start is all that is reported
+        if packet_type == PUBLISH {
+            return match read_string(&data[start..], &mut 0) {
+                Some(topic) => Framed::Frame {
+                    message: Some(Layer7Message::Mqtt(MqttMessage::Publish {
+                        topic,
+                        qos: (flags >> 1) & 0x03,
+                        retain: flags & 0x01 != 0,
+                    })),
+                    len,
+                },
+                None if data.len() >= len => Framed::Frame { message: None, len },
+                None => Framed::Incomplete,
+            };
+        }
+        let Some(body) = data.get(start..len) else {
+            return Framed::Incomplete;
+        };
+        let message = match packet_type {
+            CONNECT if flags == 0 => parse_connect(body),
+            CONNACK if flags == 0 && body.len() >= 2 => Some(MqttMessage::ConnAck {
+                session_present: body[0] & 0x01 != 0,
+                return_code: body[1],
+            }),
+            SUBSCRIBE if flags == 0x02 => parse_subscribe(body),
+            _ => None,
+        };
+        Framed::Frame { message: message.map(Layer7Message::Mqtt), len }
+    }
+}
+
+fn parse_connect(body: &[u8]) -> Option<MqttMessage> {
+    let mut pos = 0;
+    let protocol_name = read_string(body, &mut pos)?;
+    if protocol_name != "MQTT" && protocol_name != "MQIsdp" {
+        return None;
+    }
+    let protocol_level = *body.get(pos)?;
+    let connect_flags = *body.get(pos + 1)?;
+    let keep_alive = read_u16(body, pos + 2)?;
+    pos += 4;
+
+    if protocol_level == MQTT_V5 {
+        let (properties_length, length_size) = read_varint(body.get(pos..)?)?;
+        pos += length_size + properties_length;
+    }
+
+    Some(MqttMessage::Connect {
+        protocol_level,
+        client_id: read_string(body, &mut pos)?,
+        has_username: connect_flags & 0x80 != 0,
+        has_password: connect_flags & 0x40 != 0,
+        keep_alive,
+    })
+}
+
+// SUBSCRIBE carries no protocol level, so try the 3.1.1 layout first and
+// fall back to 5.0, which adds a properties block after the packet identifier
+fn parse_subscribe(body: &[u8]) -> Option<MqttMessage> {
+    read_topic_filters(body, 2).or_else(|| {
+        let (properties_length, length_size) = read_varint(body.get(2..)?)?;
+        read_topic_filters(body, 2 + length_size + properties_length)
+    })
+    .map(|topics| MqttMessage::Subscribe { topics })
+}
+
+// Reads (topic filter, options) pairs that must exactly fill the rest of the body
+fn read_topic_filters(body: &[u8], mut pos: usize) -> Option<Vec<String>> {
+    let mut topics = Vec::new();
+    while pos < body.len() {
+        topics.push(read_string(body, &mut pos)?);
+        body.get(pos)?; // Subscription options byte
+        pos += 1;
+    }
+    if topics.is_empty() || pos != body.len() {
+        return None;
+    }
+    Some(topics)
+}
+
+fn read_u16(data: &[u8], pos: usize) -> Option<u16> {
+    Some(u16::from_be_bytes([*data.get(pos)?, *data.get(pos + 1)?]))
+}
+
+// Reads a UTF-8 string prefixed with a two byte length and advances `pos` past it
+fn read_string(data: &[u8], pos: &mut usize) -> Option<String> {
+    let length = read_u16(data, *pos)? as usize;
+    let bytes = data.get(*pos + 2..*pos + 2 + length)?;
+    *pos += 2 + length;
+    std::str::from_utf8(bytes).ok().map(str::to_string)
+}
+
+// Decodes a variable byte integer, returning the value and the number of bytes used
+fn read_varint(data: &[u8]) -> Option<(usize, usize)> {
+    let mut value = 0usize;
+    for (i, byte) in data.iter().take(4).enumerate() {
+        value |= ((byte & 0x7f) as usize) << (7 * i);
+        if byte & 0x80 == 0 {
+            return Some((value, i + 1));
+        }
+    }
+    None
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn frame(data: &[u8]) -> (MqttMessage, usize) {
+        match MqttStreamDecoder.decode(data) {
+            Framed::Frame { message: Some(Layer7Message::Mqtt(message)), len } => (message, len),
+            _ => panic!("expected an MQTT frame"),
+        }
+    }
+
+    #[test]
+    fn decodes_connect() {
+        // MQTT 3.1.1, username and password flags, keep alive 60s, client ID "cli"
+        let connect = [
+            0x10, 21, 0x00, 0x04, b'M', b'Q', b'T', b'T', 0x04, 0xc2, 0x00, 0x3c,
+            0x00, 0x03, b'c', b'l', b'i', 0x00, 0x01, b'u', 0x00, 0x01, b'p',
+        ];
+        let (message, len) = frame(&connect);
+        assert_eq!(len, connect.len());
+        assert_eq!(
+            message,
+            MqttMessage::Connect {
+                protocol_level: 4,
+                client_id: "cli".to_string(),
+                has_username: true,
+                has_password: true,
+                keep_alive: 60,
+            }
+        );
+    }
+
+    #[test]
+    fn skips_mqtt5_connect_properties() {
+        // Session Expiry Interval property (0x11) ahead of the client ID
+        let connect = [
+            0x10, 21, 0x00, 0x04, b'M', b'Q', b'T', b'T', 0x05, 0x02, 0x00, 0x0a,
+            0x05, 0x11, 0x00, 0x00, 0x00, 0x78, 0x00, 0x03, b'c', b'l', b'i',
+        ];
+        let (message, _) = frame(&connect);
+        assert!(matches!(message, MqttMessage::Connect { protocol_level: 5, ref client_id, .. } if client_id == "cli"));
+    }
+
+    #[test]
+    fn decodes_publish_topic_before_payload_arrives() {
+        // QoS 1, retained, topic "a/b/c", packet ID 1, 100 byte payload of which none is here yet
+        let publish = [0x33, 109, 0x00, 0x05, b'a', b'/', b'b', b'/', b'c', 0x00, 0x01];
+        let (message, len) = frame(&publish);
+        assert_eq!(len, 111);
+        assert_eq!(message, MqttMessage::Publish { topic: "a/b/c".to_string(), qos: 1, retain: true });
+    }
+
+    #[test]
+    fn rejects_qos_3_publish() {
+        let publish = [0x36, 9, 0x00, 0x05, b'a', b'/', b'b', b'/', b'c', 0x00, 0x01];
+        assert!(matches!(MqttStreamDecoder.decode(&publish), Framed::Invalid));
+    }
+
+    #[test]
+    fn decodes_subscribe() {
+        let subscribe = [
+            0x82, 14, 0x00, 0x0a, 0x00, 0x03, b'a', b'/', b'#', 0x01, 0x00, 0x03, b'b', b'/', b'+', 0x00,
+        ];
+        let (message, _) = frame(&subscribe);
+        assert_eq!(message, MqttMessage::Subscribe { topics: vec!["a/#".to_string(), "b/+".to_string()] });
+    }
+
+    #[test]
+    fn frames_unreported_packets_and_waits_for_more() {
+        // PINGREQ
+        assert!(matches!(MqttStreamDecoder.decode(&[0xc0, 0x00]), Framed::Frame { message: None, len: 2 }));
+        // Remaining length continues past the bytes seen so far
+        assert!(matches!(MqttStreamDecoder.decode(&[0x30, 0x80]), Framed::Incomplete));
+        // Reserved packet type 0
+        assert!(matches!(MqttStreamDecoder.decode(&[0x00, 0x00]), Framed::Invalid));
+    }
+
+    #[test]
+    fn mqtts_port_is_left_to_tls() {
+        assert!(is_mqtt_port(50000, MQTT_PORT));
+        assert!(!is_mqtt_port(50000, 8883));
+    }
+}
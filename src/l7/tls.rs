@@ -0,0 +1,733 @@
+use openssl::hash::{hash, MessageDigest};
+use serde::Serialize;
+use super::{Framed, Layer7Message, StreamDecoder};
+
+pub const COAPS_PORT: u16 = 5684; // CoAP over DTLS
+
+const CONTENT_HANDSHAKE: u8 = 22;
+
+const CLIENT_HELLO: u8 = 1;
+const SERVER_HELLO: u8 = 2;
+const CERTIFICATE: u8 = 11;
+// Handshake types that are sent in the clear; anything else in a handshake
+// record is an encrypted Finished message
+const PLAINTEXT_HANDSHAKES: [u8; 8] = [CLIENT_HELLO, SERVER_HELLO, 4, CERTIFICATE, 12, 13, 14, 16];
+
+const EXT_SERVER_NAME: u16 = 0;
+const EXT_SUPPORTED_GROUPS: u16 = 10;
+const EXT_EC_POINT_FORMATS: u16 = 11;
+const EXT_SIGNATURE_ALGORITHMS: u16 = 13;
+const EXT_ALPN: u16 = 16;
+const EXT_SUPPORTED_VERSIONS: u16 = 43;
+
+// Largest handshake flight gathered across records; certificate chains stay well below it
+const MAX_HANDSHAKE_BYTES: usize = 64 * 1024;
+
+// Object identifiers of the distinguished name attributes that are reported (2.5.4.x)
+const NAME_ATTRIBUTES: [(u8, &str); 7] = [(3, "CN"), (6, "C"), (7, "L"), (8, "ST"), (10, "O"), (11, "OU"), (5, "serialNumber")];
+
+#[derive(Debug, Clone, PartialEq, Eq, Hash, Serialize)]
+#[serde(tag = "handshake", rename_all = "snake_case")]
+pub enum TlsMessage {
+    ClientHello {
+        dtls: bool,
+        sni: Option<String>,
+        alpn: Vec<String>,
+        versions: Vec<String>,   // Offered versions, from supported_versions when present
+        cipher_suites: Vec<u16>, // In the client's order, GREASE values removed
+        ja3: String,             // MD5 of the JA3 string
+        ja4: String,
+    },
+    ServerHello {
+        dtls: bool,
+        version: String, // Negotiated version
+        cipher_suite: u16,
+        alpn: Option<String>,
+        ja3s: String, // MD5 of the JA3S string
+        certificate: Option<Certificate>, // Leaf certificate when sent in the clear (TLS 1.2 and earlier)
+    },
+    // A certificate that arrived after the ServerHello had been reported
+    Certificate(Certificate),
+}
+
+#[derive(Debug, Clone, PartialEq, Eq, Hash, Serialize)]
+pub struct Certificate {
+    pub subject: String, // e.g. "CN=example.com, O=Example"
+    pub issuer: String,
+}
+
+// Handshake details kept on a flow for its lifetime, merged from both directions
+#[derive(Debug, Clone, Default, PartialEq, Eq, Serialize)]
+pub struct TlsSession {
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub sni: Option<String>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub version: Option<String>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub cipher_suite: Option<u16>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub ja3: Option<String>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub ja3s: Option<String>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub ja4: Option<String>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub certificate: Option<Certificate>,
+}
+
+impl TlsSession {
+    pub fn update(&mut self, message: &TlsMessage) {
+        match message {
+            TlsMessage::ClientHello { sni, ja3, ja4, .. } => {
+                self.sni = sni.clone();
+                self.ja3 = Some(ja3.clone());
+                self.ja4 = Some(ja4.clone());
+            }
+            TlsMessage::ServerHello { version, cipher_suite, ja3s, certificate, .. } => {
+                self.version = Some(version.clone());
+                self.cipher_suite = Some(*cipher_suite);
+                self.ja3s = Some(ja3s.clone());
+                if certificate.is_some() {
+                    self.certificate = certificate.clone();
+                }
+            }
+            TlsMessage::Certificate(certificate) => self.certificate = Some(certificate.clone()),
+        }
+    }
+}
+
+// Returns true if the payload starts with a TLS handshake record header
+fn looks_like_tls(data: &[u8]) -> bool {
+    data.len() >= 3 && data[0] == CONTENT_HANDSHAKE && data[1] == 0x03 && data[2] <= 0x04
+}
+
+// Returns true if either port is CoAP over DTLS
+pub fn is_dtls_port(src_port: u16, dst_port: u16) -> bool {
+    src_port == COAPS_PORT || dst_port == COAPS_PORT
+}
+
+// Frames TLS records. Consecutive handshake records are gathered into one flight so
+// messages split across records are decoded; other records are skipped.
+pub struct TlsStreamDecoder;
+
+impl StreamDecoder for TlsStreamDecoder {
+    // TLS is recognised by its first record, whatever the port
+    fn accepts(&self, _src_port: u16, _dst_port: u16, data: &[u8]) -> bool {
+        looks_like_tls(data)
+    }
+
+    fn decode(&self, data: &[u8]) -> Framed {
+        if data.len() < 5 {
+            return Framed::Incomplete;
+        }
+        if data[1] != 0x03 {
+            return Framed::Invalid;
+        }
+        let record_len = 5 + u16::from_be_bytes([data[3], data[4]]) as usize;
+        if data[0] != CONTENT_HANDSHAKE {
+            return Framed::Frame { message: None, len: record_len };
+        }
+
+        // Gather the handshake records at hand, remembering where the last one that
+        // completed a message ended, so a ServerHello is reported with its certificate
+        let mut handshake = Vec::new();
+        let mut pos = 0;
+        let mut boundary = None;
+        while let Some(header) = data.get(pos..pos + 5) {
+            if header[0] != CONTENT_HANDSHAKE || header[1] != 0x03 {
+                // Another record type before the flight ended: it was not plaintext handshake messages
+                if boundary.is_none() {
+                    return Framed::Frame { message: None, len: record_len };
+                }
+                break;
+            }
+            let end = pos + 5 + u16::from_be_bytes([header[3], header[4]]) as usize;
+            let Some(fragment) = data.get(pos + 5..end) else {
+                break;
+            };
+            handshake.extend_from_slice(fragment);
+            pos = end;
+            if !handshake.first().is_some_and(|t| PLAINTEXT_HANDSHAKES.contains(t)) {
+                return Framed::Frame { message: None, len: record_len };
+            }
+            if split_handshakes(&handshake).is_some() {
+                boundary = Some((pos, handshake.len()));
+            }
+            if handshake.len() > MAX_HANDSHAKE_BYTES {
+                break;
+            }
+        }
+
+        match boundary {
+            Some((len, handshake_len)) => {
+                let messages = split_handshakes(&handshake[..handshake_len]).unwrap_or_default();
+                Framed::Frame { message: flight_message(&messages, false).map(Layer7Message::Tls), len }
+            }
+            None if handshake.len() > MAX_HANDSHAKE_BYTES => Framed::Frame { message: None, len: pos },
+            None => Framed::Incomplete,
+        }
+    }
+}
+
+// Decodes the unfragmented handshake messages in a DTLS datagram
+pub fn parse_dtls(payload: &[u8]) -> Option<TlsMessage> {
+    let mut messages = Vec::new();
+    let mut rest = payload;
+    // Record header: type, version, epoch, sequence number, length
+    while rest.len() >= 13 {
+        let end = 13 + u16::from_be_bytes([rest[11], rest[12]]) as usize;
+        let fragment = rest.get(13..end)?;
+        // Epoch 0 records are the only ones in the clear
+        if rest[0] == CONTENT_HANDSHAKE && rest[1] == 0xfe && rest[3..5] == [0, 0] {
+            // Handshake header: type, length, message sequence, fragment offset and length
+            let mut handshake = fragment;
+            while handshake.len() >= 12 {
+                let length = u24(&handshake[1..4]);
+                let fragment_offset = u24(&handshake[6..9]);
+                let fragment_length = u24(&handshake[9..12]);
+                let body = handshake.get(12..12 + fragment_length)?;
+                if fragment_offset == 0 && fragment_length == length {
+                    messages.push((handshake[0], body));
+                }
+                handshake = &handshake[12 + fragment_length..];
+            }
+        }
+        rest = &rest[end..];
+    }
+    flight_message(&messages, true)
+}
+
+// Splits a flight into (type, body) handshake messages; None unless it ends on a message boundary
+fn split_handshakes(mut data: &[u8]) -> Option<Vec<(u8, &[u8])>> {
+    let mut messages = Vec::new();
+    while !data.is_empty() {
+        let header = data.get(..4)?;
+        let end = 4 + u24(&header[1..4]);
+        messages.push((header[0], data.get(4..end)?));
+        data = &data[end..];
+    }
+    Some(messages)
+}
+
+// The message reported for a flight: a ClientHello, a ServerHello with the certificate
+// that follows it, or a certificate on its own
+fn flight_message(messages: &[(u8, &[u8])], dtls: bool) -> Option<TlsMessage> {
+    let certificate = messages
+        .iter()
+        .find(|(kind, _)| *kind == CERTIFICATE)
+        .and_then(|(_, body)| parse_certificate(body));
+    for (kind, body) in messages {
+        match *kind {
+            CLIENT_HELLO => return parse_client_hello(body, dtls),
+            SERVER_HELLO => return parse_server_hello(body, dtls, certificate),
+            _ => {}
+        }
+    }
+    certificate.map(TlsMessage::Certificate)
+}
+
+fn parse_client_hello(body: &[u8], dtls: bool) -> Option<TlsMessage> {
+    let mut reader = Reader { data: body, pos: 0 };
+    let legacy_version = reader.u16()?;
+    reader.skip(32)?; // Random
+    let session_id_len = reader.u8()? as usize;
+    reader.skip(session_id_len)?;
+    if dtls {
+        let cookie_len = reader.u8()? as usize;
+        reader.skip(cookie_len)?;
+    }
+    let cipher_suites: Vec<u16> = u16_list(reader.vec16()?).into_iter().filter(|c| !is_grease(*c)).collect();
+    let compression_len = reader.u8()? as usize;
+    reader.skip(compression_len)?;
+
+    let mut extension_types = Vec::new();
+    let mut sni = None;
+    let mut alpn = Vec::new();
+    let mut groups = Vec::new();
+    let mut point_formats = Vec::new();
+    let mut signature_algorithms = Vec::new();
+    let mut supported_versions = Vec::new();
+    for (kind, data) in extensions(&mut reader) {
+        if is_grease(kind) {
+            continue;
+        }
+        extension_types.push(kind);
+        let mut ext = Reader { data, pos: 0 };
+        match kind {
+            EXT_SERVER_NAME => sni = parse_server_name(&mut ext),
+            EXT_ALPN => alpn = parse_alpn(&mut ext).unwrap_or_default(),
+            EXT_SUPPORTED_GROUPS => groups = ext.vec16().map(u16_list).unwrap_or_default(),
+            EXT_EC_POINT_FORMATS => point_formats = ext.vec8().map(<[u8]>::to_vec).unwrap_or_default(),
+            EXT_SIGNATURE_ALGORITHMS => signature_algorithms = ext.vec16().map(u16_list).unwrap_or_default(),
+            EXT_SUPPORTED_VERSIONS => supported_versions = ext.vec8().map(u16_list).unwrap_or_default(),
+            _ => {}
+        }
+    }
+    groups.retain(|g| !is_grease(*g));
+    supported_versions.retain(|v| !is_grease(*v));
+    signature_algorithms.retain(|a| !is_grease(*a));
+
+    let ja3 = format!(
+        "{},{},{},{},{}",
+        legacy_version,
+        join(&cipher_suites, "-"),
+        join(&extension_types, "-"),
+        join(&groups, "-"),
+        join(&point_formats, "-"),
+    );
+    let hello = ClientHelloFields { dtls, legacy_version, supported_versions, sni, cipher_suites, extension_types, alpn, signature_algorithms };
+    let ja4 = ja4(&hello);
+    let ClientHelloFields { sni, alpn, cipher_suites, supported_versions, .. } = hello;
+    let versions = if supported_versions.is_empty() { vec![legacy_version] } else { supported_versions };
+
+    Some(TlsMessage::ClientHello {
+        dtls,
+        sni,
+        alpn,
+        versions: versions.into_iter().map(version_name).collect(),
+        cipher_suites,
+        ja3: hex(&hash(MessageDigest::md5(), ja3.as_bytes()).ok()?),
+        ja4,
+    })
+}
+
+fn parse_server_hello(body: &[u8], dtls: bool, certificate: Option<Certificate>) -> Option<TlsMessage> {
+    let mut reader = Reader { data: body, pos: 0 };
+    let legacy_version = reader.u16()?;
+    reader.skip(32)?; // Random
+    let session_id_len = reader.u8()? as usize;
+    reader.skip(session_id_len)?;
+    let cipher_suite = reader.u16()?;
+    reader.skip(1)?; // Compression method
+
+    let mut extension_types = Vec::new();
+    let mut version = legacy_version;
+    let mut alpn = None;
+    for (kind, data) in extensions(&mut reader) {
+        extension_types.push(kind);
+        let mut ext = Reader { data, pos: 0 };
+        match kind {
+            // The server answers with the single version it selected
+            EXT_SUPPORTED_VERSIONS => version = ext.u16().unwrap_or(version),
+            EXT_ALPN => alpn = parse_alpn(&mut ext).and_then(|protocols| protocols.into_iter().next()),
+            _ => {}
+        }
+    }
+
+    let ja3s = format!("{},{},{}", legacy_version, cipher_suite, join(&extension_types, "-"));
+    Some(TlsMessage::ServerHello {
+        dtls,
+        version: version_name(version),
+        cipher_suite,
+        alpn,
+        ja3s: hex(&hash(MessageDigest::md5(), ja3s.as_bytes()).ok()?),
+        certificate,
+    })
+}
+
+// The parts of a ClientHello that go into its JA4 fingerprint, GREASE values removed
+struct ClientHelloFields {
+    dtls: bool,
+    legacy_version: u16,
+    supported_versions: Vec<u16>,
+    sni: Option<String>,
+    cipher_suites: Vec<u16>,
+    extension_types: Vec<u16>,
+    alpn: Vec<String>,
+    signature_algorithms: Vec<u16>,
+}
+
+// JA4 client fingerprint: protocol, version, SNI, counts and ALPN, then truncated
+// SHA-256 hashes of the sorted cipher suites and of the sorted extensions followed
+// by the signature algorithms
+fn ja4(hello: &ClientHelloFields) -> String {
+    let ClientHelloFields { dtls, legacy_version, supported_versions, sni, cipher_suites, extension_types, alpn, signature_algorithms } = hello;
+    // DTLS numbers its versions downwards from 0xfeff, so those rank by their complement
+    let version = supported_versions
+        .iter()
+        .copied()
+        .max_by_key(|v| if v >> 8 == 0xfe { !v } else { *v })
+        .unwrap_or(*legacy_version);
+    let version = match version {
+        0x0304 => "13",
+        0x0303 => "12",
+        0x0302 => "11",
+        0x0301 => "10",
+        0x0300 => "s3",
+        0xfeff => "d1",
+        0xfefd => "d2",
+        0xfefc => "d3",
+        _ => "00",
+    };
+    let alpn = match alpn.first().map(String::as_bytes) {
+        Some([first, .., last]) if first.is_ascii_alphanumeric() && last.is_ascii_alphanumeric() => {
+            format!("{}{}", *first as char, *last as char)
+        }
+        Some([only]) if only.is_ascii_alphanumeric() => format!("{}{}", *only as char, *only as char),
+        Some(bytes @ [_, ..]) => {
+            let hex = hex(bytes);
+            format!("{}{}", &hex[..1], &hex[hex.len() - 1..])
+        }
+        _ => "00".to_string(),
+    };
+
+    let mut ciphers: Vec<String> = cipher_suites.iter().map(|c| format!("{:04x}", c)).collect();
+    ciphers.sort();
+    let mut extensions: Vec<String> = extension_types
+        .iter()
+        .filter(|e| **e != EXT_SERVER_NAME && **e != EXT_ALPN)
+        .map(|e| format!("{:04x}", e))
+        .collect();
+    extensions.sort();
+    let mut extension_input = extensions.join(",");
+    if !signature_algorithms.is_empty() {
+        let algorithms: Vec<String> = signature_algorithms.iter().map(|a| format!("{:04x}", a)).collect();
+        extension_input = format!("{}_{}", extension_input, algorithms.join(","));
+    }
+
+    format!(
+        "{}{}{}{:02}{:02}{}_{}_{}",
+        if *dtls { 'd' } else { 't' },
+        version,
+        if sni.is_some() { 'd' } else { 'i' },
+        cipher_suites.len().min(99),
+        extension_types.len().min(99),
+        alpn,
+        truncated_sha256(&ciphers.join(","), ciphers.is_empty()),
+        truncated_sha256(&extension_input, extensions.is_empty()),
+    )
+}
+
+// First 12 hex digits of the SHA-256 of `input`, or zeros when there was nothing to hash
+fn truncated_sha256(input: &str, empty: bool) -> String {
+    match hash(MessageDigest::sha256(), input.as_bytes()) {
+        Ok(digest) if !empty => hex(&digest)[..12].to_string(),
+        _ => "000000000000".to_string(),
+    }
+}
+
+// Reads the extensions block that may end a hello message
+fn extensions<'a>(reader: &mut Reader<'a>) -> Vec<(u16, &'a [u8])> {
+    let mut extensions = Vec::new();
+    let Some(block) = reader.vec16() else {
+        return extensions;
+    };
+    let mut block = Reader { data: block, pos: 0 };
+    while let (Some(kind), Some(data)) = (block.u16(), block.vec16()) {
+        extensions.push((kind, data));
+    }
+    extensions
+}
+
+fn parse_server_name(ext: &mut Reader) -> Option<String> {
+    let mut list = Reader { data: ext.vec16()?, pos: 0 };
+    while let Some(name_type) = list.u8() {
+        let name = list.vec16()?;
+        // Type 0 is a DNS host name
+        if name_type == 0 {
+            return std::str::from_utf8(name).ok().map(str::to_string);
+        }
+    }
+    None
+}
+
+fn parse_alpn(ext: &mut Reader) -> Option<Vec<String>> {
+    let mut list = Reader { data: ext.vec16()?, pos: 0 };
+    let mut protocols = Vec::new();
+    while let Some(protocol) = list.vec8() {
+        protocols.push(String::from_utf8_lossy(protocol).into_owned());
+    }
+    Some(protocols)
+}
+
+// Reads the subject and issuer of the first (leaf) certificate of a Certificate message
+fn parse_certificate(body: &[u8]) -> Option<Certificate> {
+    let mut reader = Reader { data: body, pos: 0 };
+    let list_len = u24(reader.bytes(3)?);
+    let mut list = Reader { data: reader.bytes(list_len)?, pos: 0 };
+    let cert_len = u24(list.bytes(3)?);
+    let der = list.bytes(cert_len)?;
+
+    // Certificate ::= SEQUENCE { tbsCertificate SEQUENCE { [0] version OPTIONAL,
+    // serialNumber, signature, issuer, validity, subject, ... }, ... }
+    let (_, certificate) = der_read(der)?;
+    let (_, tbs) = der_read(certificate)?;
+    let mut fields = tbs;
+    let mut next = || {
+        let (tag, content) = der_read(fields)?;
+        let consumed = der_len(fields)?;
+        fields = &fields[consumed..];
+        Some((tag, content))
+    };
+    let (mut tag, _) = next()?;
+    if tag == 0xa0 {
+        // Explicit version, the serial number follows
+        (tag, _) = next()?;
+    }
+    if tag != 0x02 {
+        return None;
+    }
+    next()?; // Signature algorithm
+    let (_, issuer) = next()?;
+    next()?; // Validity
+    let (_, subject) = next()?;
+    Some(Certificate { subject: distinguished_name(subject), issuer: distinguished_name(issuer) })
+}
+
+// Renders an X.501 Name (SEQUENCE OF SET OF AttributeTypeAndValue) as "CN=..., O=..."
+fn distinguished_name(mut name: &[u8]) -> String {
+    let mut parts = Vec::new();
+    while let (Some((_, set)), Some(len)) = (der_read(name), der_len(name)) {
+        name = &name[len..];
+        let Some((_, attribute)) = der_read(set) else {
+            continue;
+        };
+        let (Some((_, oid)), Some(oid_len)) = (der_read(attribute), der_len(attribute)) else {
+            continue;
+        };
+        let Some((_, value)) = der_read(&attribute[oid_len..]) else {
+            continue;
+        };
+        let label = match oid {
+            [0x55, 0x04, id] => NAME_ATTRIBUTES.iter().find(|(known, _)| known == id).map(|(_, label)| *label),
+            _ => None,
+        };
+        if let Some(label) = label {
+            parts.push(format!("{}={}", label, String::from_utf8_lossy(value)));
+        }
+    }
+    parts.join(", ")
+}
+
+// Reads one DER element, returning its tag and contents
+fn der_read(data: &[u8]) -> Option<(u8, &[u8])> {
+    let (header, len) = der_header(data)?;
+    Some((data[0], data.get(header..header + len)?))
+}
+
+// Total encoded length of the DER element at the start of `data`
+fn der_len(data: &[u8]) -> Option<usize> {
+    let (header, len) = der_header(data)?;
+    (data.len() >= header + len).then_some(header + len)
+}
+
+// Length of the tag and length octets, and the length of the contents
+fn der_header(data: &[u8]) -> Option<(usize, usize)> {
+    let first = *data.get(1)?;
+    if first & 0x80 == 0 {
+        return Some((2, first as usize));
+    }
+    let octets = (first & 0x7f) as usize;
+    if octets == 0 || octets > 4 {
+        return None;
+    }
+    let len = data.get(2..2 + octets)?.iter().fold(0usize, |len, byte| (len << 8) | *byte as usize);
+    Some((2 + octets, len))
+}
+
+// GREASE values (RFC 8701) are 0x?a?a with both bytes equal
+fn is_grease(value: u16) -> bool {
+    value & 0x0f0f == 0x0a0a && value >> 8 == value & 0xff
+}
+
+fn version_name(version: u16) -> String {
+    match version {
+        0x0300 => "SSL 3.0".to_string(),
+        0x0301 => "TLS 1.0".to_string(),
+        0x0302 => "TLS 1.1".to_string(),
+        0x0303 => "TLS 1.2".to_string(),
+        0x0304 => "TLS 1.3".to_string(),
+        0xfeff => "DTLS 1.0".to_string(),
+        0xfefd => "DTLS 1.2".to_string(),
+        0xfefc => "DTLS 1.3".to_string(),
+        other => format!("0x{:04x}", other),
+    }
+}
+
+fn u24(bytes: &[u8]) -> usize {
+    (bytes[0] as usize) << 16 | (bytes[1] as usize) << 8 | bytes[2] as usize
+}
+
+fn u16_list(data: &[u8]) -> Vec<u16> {
+    data.chunks_exact(2).map(|pair| u16::from_be_bytes([pair[0], pair[1]])).collect()
+}
+
+fn join<T: ToString>(values: &[T], separator: &str) -> String {
+    values.iter().map(T::to_string).collect::<Vec<_>>().join(separator)
+}
+
+fn hex(bytes: &[u8]) -> String {
+    bytes.iter().map(|b| format!("{:02x}", b)).collect()
+}
+
+// Bounds checked cursor over a handshake message
+struct Reader<'a> {
+    data: &'a [u8],
+    pos: usize,
+}
+
+impl<'a> Reader<'a> {
+    fn bytes(&mut self, len: usize) -> Option<&'a [u8]> {
+        let bytes = self.data.get(self.pos..self.pos.checked_add(len)?)?;
+        self.pos += len;
+        Some(bytes)
+    }
+
+    fn skip(&mut self, len: usize) -> Option<()> {
+        self.bytes(len).map(|_| ())
+    }
+
+    fn u8(&mut self) -> Option<u8> {
+        self.bytes(1).map(|b| b[0])
+    }
+
+    fn u16(&mut self) -> Option<u16> {
+        self.bytes(2).map(|b| u16::from_be_bytes([b[0], b[1]]))
+    }
+
+    // A vector prefixed with a one byte length
+    fn vec8(&mut self) -> Option<&'a [u8]> {
+        let len = self.u8()? as usize;
+        self.bytes(len)
+    }
+
+    // A vector prefixed with a two byte length
+    fn vec16(&mut self) -> Option<&'a [u8]> {
+        let len = self.u16()? as usize;
+        self.bytes(len)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const GREASE: u16 = 0x0a0a;
+
+    fn u16_bytes(values: &[u16]) -> Vec<u8> {
+        values.iter().flat_map(|v| v.to_be_bytes()).collect()
+    }
+
+    // Prefixes `data` with its length in `size` bytes
+    fn prefixed(size: usize, data: &[u8]) -> Vec<u8> {
+        let mut out = data.len().to_be_bytes()[8 - size..].to_vec();
+        out.extend_from_slice(data);
+        out
+    }
+
+    fn extension(kind: u16, data: &[u8]) -> Vec<u8> {
+        let mut out = kind.to_be_bytes().to_vec();
+        out.extend(prefixed(2, data));
+        out
+    }
+
+    // A handshake message in a single TLS record
+    fn record(kind: u8, body: &[u8]) -> Vec<u8> {
+        let handshake = [&[kind][..], &prefixed(3, body)].concat();
+        [&[CONTENT_HANDSHAKE, 0x03, 0x01][..], &prefixed(2, &handshake)].concat()
+    }
+
+    // A Chrome-like TLS 1.3 ClientHello with GREASE values sprinkled through it. It offers
+    // the cipher suites, extensions and signature algorithms of the JA4 specification's
+    // example, whose fingerprint is t13d1516h2_8daaf6152771_e5627efa2ab1.
+    fn client_hello() -> Vec<u8> {
+        let ciphers = [
+            GREASE, 0x1301, 0x1302, 0x1303, 0xc02b, 0xc02f, 0xc02c, 0xc030, 0xcca9, 0xcca8, 0xc013, 0xc014,
+            0x009c, 0x009d, 0x002f, 0x0035,
+        ];
+        let server_name = prefixed(2, &[&[0][..], &prefixed(2, b"example.com")].concat());
+        let alpn = prefixed(2, &[prefixed(1, b"h2"), prefixed(1, b"http/1.1")].concat());
+        let algorithms = [0x3a3a, 0x0403, 0x0804, 0x0401, 0x0503, 0x0805, 0x0501, 0x0806, 0x0601];
+        let extensions = [
+            extension(GREASE, b""),
+            extension(EXT_SERVER_NAME, &server_name),
+            extension(0x0017, b""),
+            extension(0xff01, &[0]),
+            extension(EXT_SUPPORTED_GROUPS, &prefixed(2, &u16_bytes(&[0x2a2a, 0x001d, 0x0017, 0x0018]))),
+            extension(EXT_EC_POINT_FORMATS, &prefixed(1, &[0])),
+            extension(0x0023, b""),
+            extension(EXT_ALPN, &alpn),
+            extension(0x0005, &[1, 0, 0, 0, 0]),
+            extension(EXT_SIGNATURE_ALGORITHMS, &prefixed(2, &u16_bytes(&algorithms))),
+            extension(0x0012, b""),
+            extension(0x0033, &prefixed(2, b"")),
+            extension(0x002d, &prefixed(1, &[1])),
+            extension(EXT_SUPPORTED_VERSIONS, &prefixed(1, &u16_bytes(&[0x1a1a, 0x0304, 0x0303]))),
+            extension(0x001b, &prefixed(1, &[0, 2])),
+            extension(0x0015, b""),
+            extension(0x4469, b""),
+            extension(0x1a1a, &[0]),
+        ]
+        .concat();
+
+        let mut body = vec![0x03, 0x03];
+        body.extend_from_slice(&[0; 32]); // Random
+        body.push(0); // Session ID
+        body.extend(prefixed(2, &u16_bytes(&ciphers)));
+        body.extend_from_slice(&[1, 0]); // Null compression
+        body.extend(prefixed(2, &extensions));
+        record(CLIENT_HELLO, &body)
+    }
+
+    fn decode(data: &[u8]) -> TlsMessage {
+        match TlsStreamDecoder.decode(data) {
+            Framed::Frame { message: Some(Layer7Message::Tls(message)), len } if len == data.len() => message,
+            _ => panic!("expected a TLS handshake frame"),
+        }
+    }
+
+    #[test]
+    fn fingerprints_client_hello_without_grease() {
+        let TlsMessage::ClientHello { sni, alpn, versions, cipher_suites, ja3, ja4, .. } = decode(&client_hello()) else {
+            panic!("expected a ClientHello");
+        };
+        assert_eq!(sni.as_deref(), Some("example.com"));
+        assert_eq!(alpn, ["h2", "http/1.1"]);
+        assert_eq!(versions, ["TLS 1.3", "TLS 1.2"]);
+        assert_eq!(cipher_suites.len(), 15);
+        // MD5 of "771,4865-4866-4867-49195-49199-49196-49200-52393-52392-49171-49172-156-157-47-53,
+        // 0-23-65281-10-11-35-16-5-13-18-51-45-43-27-21-17513,29-23-24,0"
+        assert_eq!(ja3, "50a0e1f8c13ee9e5521e3f374a63a021");
+        assert_eq!(ja4, "t13d1516h2_8daaf6152771_e5627efa2ab1");
+    }
+
+    #[test]
+    fn ja4_ranks_dtls_versions_by_protocol_order() {
+        let hello = |supported_versions: Vec<u16>| ClientHelloFields {
+            dtls: true,
+            legacy_version: 0xfefd,
+            supported_versions,
+            sni: None,
+            cipher_suites: Vec::new(),
+            extension_types: Vec::new(),
+            alpn: Vec::new(),
+            signature_algorithms: Vec::new(),
+        };
+        assert!(ja4(&hello(vec![0xfefc, 0xfefd])).starts_with("dd3i"));
+        assert!(ja4(&hello(vec![0xfeff, 0xfefd])).starts_with("dd2i"));
+        assert_eq!(ja4(&hello(Vec::new())), "dd2i000000_000000000000_000000000000");
+    }
+
+    #[test]
+    fn server_hello_reports_the_selected_version() {
+        let mut body = vec![0x03, 0x03];
+        body.extend_from_slice(&[0; 32]);
+        body.push(0);
+        body.extend_from_slice(&[0x13, 0x01, 0]); // TLS_AES_128_GCM_SHA256, null compression
+        body.extend(prefixed(2, &extension(EXT_SUPPORTED_VERSIONS, &[0x03, 0x04])));
+        let TlsMessage::ServerHello { version, cipher_suite, ja3s, .. } = decode(&record(SERVER_HELLO, &body)) else {
+            panic!("expected a ServerHello");
+        };
+        assert_eq!(version, "TLS 1.3");
+        assert_eq!(cipher_suite, 0x1301);
+        // MD5 of "771,4865,43"
+        assert_eq!(ja3s, "cce84e7a8b742462e40afb585a3e3ccc");
+    }
+
+    #[test]
+    fn waits_for_the_rest_of_the_record() {
+        let hello = client_hello();
+        assert!(matches!(TlsStreamDecoder.decode(&hello[..hello.len() - 1]), Framed::Incomplete));
+        assert!(!TlsStreamDecoder.accepts(50000, 443, b"GET / HTTP/1.1"));
+    }
+}
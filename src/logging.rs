@@ -0,0 +1,84 @@
+use std::sync::OnceLock;
+use clap::ValueEnum;
+use serde::Deserialize;
+use tracing_subscriber::{filter::LevelFilter, fmt, prelude::*, reload, EnvFilter, Registry};
+
+// Used when neither the command line, the config file nor RUST_LOG sets a level
+const DEFAULT_LOG_LEVEL: &str = "info";
+
+// Lets the filter be replaced after startup, e.g. when the config file is reloaded
+static FILTER: OnceLock<reload::Handle<EnvFilter, Registry>> = OnceLock::new();
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default, Deserialize, ValueEnum)]
+#[serde(rename_all = "lowercase")]
+pub enum LogFormat {
+    #[default]
+    Text, // Human readable lines
+    Json, // One JSON object per line, fields included
+}
+
+// Installs the global log subscriber. `level` is a filter such as "info" or
+// "warn,NetFlex::probe=debug" for per-module levels. Logs are written to stderr
+// so stdout only carries discoveries.
+pub fn init(level: Option<&str>, format: LogFormat) -> Result<(), String> {
+    let (filter, handle) = reload::Layer::new(filter(level)?);
+    let registry = tracing_subscriber::registry().with(filter);
+    let layer = fmt::layer().with_writer(std::io::stderr);
+    match format {
+        LogFormat::Text => registry.with(layer).init(),
+        LogFormat::Json => registry.with(layer.json()).init(),
+    }
+    let _ = FILTER.set(handle);
+    Ok(())
+}
+
+// Replaces the filter installed by init(); the format cannot change at runtime
+pub fn set_level(level: Option<&str>) -> Result<(), String> {
+    let filter = filter(level)?;
+    match FILTER.get() {
+        Some(handle) => handle.reload(filter).map_err(|e| e.to_string()),
+        None => Ok(()),
+    }
+}
+
+fn filter(level: Option<&str>) -> Result<EnvFilter, String> {
+    match level {
+        Some(level) => parse_filter(level).map_err(|e| format!("Invalid log level {:?}: {}", level, e)),
+        None => Ok(EnvFilter::try_from_default_env().unwrap_or_else(|_| EnvFilter::new(DEFAULT_LOG_LEVEL))),
+    }
+}
+
+// Parses a log filter. A directive without "=" must be a level: EnvFilter would take a
+// typo such as "infoo" for a target name and quietly log nothing else.
+pub fn parse_filter(level: &str) -> Result<EnvFilter, String> {
+    let directives: Vec<&str> = level.split(',').map(str::trim).filter(|d| !d.is_empty()).collect();
+    if directives.is_empty() {
+        return Err("no level given".to_string());
+    }
+    if let Some(bare) = directives.iter().find(|d| !d.contains('=') && d.parse::<LevelFilter>().is_err()) {
+        return Err(format!("{:?} is not a level (trace, debug, info, warn, error or off)", bare));
+    }
+    EnvFilter::try_new(level).map_err(|e| e.to_string())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn accepts_levels_and_per_module_directives() {
+        for level in ["info", "WARN", "warn,NetFlex::probe=debug", "NetFlex=trace", "off"] {
+            assert!(parse_filter(level).is_ok(), "{}", level);
+        }
+    }
+
+    #[test]
+    fn rejects_invalid_levels() {
+        for level in ["verbose", "infoo,NetFlex=debug", "NetFlex=loud", "", " , "] {
+            assert!(parse_filter(level).is_err(), "{}", level);
+        }
+        // Checked before anything is installed
+        assert!(init(Some("verbose"), LogFormat::Text).unwrap_err().contains("verbose"));
+        assert!(set_level(Some("verbose")).is_err());
+    }
+}
@@ -3,57 +3,289 @@ mod pipeline;
 mod config;  // Import the config module
 mod packet;
 mod extractor;
+mod flow;
+mod fragment;
+mod stream;
+mod icmp;
+mod l7;
 
 mod probe;
+mod scheduler;
+mod cli;
+mod output;
+mod logging;
+mod storage;
+mod supervisor;
+mod reload;
 
+use std::path::Path;
+use std::process;
 use std::sync::Arc;
+use clap::Parser;
+use cli::{Cli, Command, DEFAULT_CONFIG_PATH};
 use extractor::InformationExtractor;
-use tokio::task::JoinSet;
-use pipeline::TrafficPipeline;
-use monitors::InterfaceMonitor;
-use config::{load_config, Config};  // Import load_config function and Config struct
+use output::{Inventory, Output};
+use probe::ProtocolProber;
+use scheduler::ProbeScheduler;
+use storage::Storage;
+use supervisor::Supervisor;
+use config::{config_from_env, load_config, Config, ConfigError};  // Import the config loaders
+use tracing::{error, info, warn};
 
 #[tokio::main]
 async fn main() {
-    // Load config from a custom file
-    let config: Config = load_config("custom_config.json").await;
+    let cli = Cli::parse();
 
-    let info_extractor = Arc::new(InformationExtractor::new());
+    if cli.list_interfaces {
+        list_interfaces();
+        return;
+    }
+
+    // Load config from the given file; without --config the default file is optional
+    let config = match &cli.config {
+        Some(path) => load_config(path).await,
+        None if Path::new(DEFAULT_CONFIG_PATH).exists() => load_config(Path::new(DEFAULT_CONFIG_PATH)).await,
+        None => config_from_env(),
+    };
+    let mut config = config.unwrap_or_else(|e| {
+        eprintln!("{}", e);
+        process::exit(1);
+    });
+    cli.apply_overrides(&mut config);
+
+    if let Err(errors) = config.validate() {
+        for e in &errors {
+            eprintln!("{}", e);
+        }
+        process::exit(1);
+    }
+    if let Some(Command::CheckConfig) = cli.command {
+        for variable in &config.ignored_env {
+            eprintln!("{}: ignored, not a config field", variable);
+        }
+        println!("Configuration is valid");
+        return;
+    }
+
+    // Command line log options win over the settings section
+    let settings = config.settings.as_ref();
+    let log_level = cli.log_level.as_deref().or(settings.and_then(|s| s.log_level.as_deref()));
+    let log_format = cli.log_format.or(settings.and_then(|s| s.log_format)).unwrap_or_default();
+    if let Err(e) = logging::init(log_level, log_format) {
+        eprintln!("{}", e);
+        process::exit(1);
+    }
+    // The environment does not change, so this is only reported at startup and not on reloads
+    for variable in &config.ignored_env {
+        warn!(%variable, "ignored, not a config field");
+    }
+
+    let output = match Output::new(cli.output.as_deref()) {
+        Ok(output) => Arc::new(output),
+        Err(e) => {
+            error!(error = %e, "failed to open output file");
+            process::exit(1);
+        }
+    };
+    // An inventory is reported once when capture ends instead of as events stream in
+    let inventory_mode = matches!(cli.command, Some(Command::Inventory { .. }));
+    let events = if inventory_mode { Arc::new(Output::Discard) } else { Arc::clone(&output) };
+
+    // Discoveries are persisted only when a storage path is configured
+    let storage = match settings.and_then(|s| s.storage_path.as_deref().map(|path| (s, path))) {
+        Some((settings, path)) => match Storage::open(Path::new(path), settings) {
+            Ok(storage) => Some(Arc::new(storage)),
+            Err(e) => {
+                error!(storage_path = path, error = %e, "failed to open storage");
+                process::exit(1);
+            }
+        },
+        None => None,
+    };
+
+    let info_extractor = Arc::new(InformationExtractor::new(config.flows.as_ref(), config.reassembly.as_ref()));
+    let prober = Arc::new(ProtocolProber::new());
+    let scheduler = if cli.no_probe {
+        None
+    } else {
+        let scheduler = Arc::new(ProbeScheduler::new(config.probe.as_ref()));
+        tokio::spawn(Arc::clone(&scheduler).run(Arc::clone(&prober), Arc::clone(&events), storage.clone()));
+        Some(scheduler)
+    };
+
+    // Reload the inventory of earlier runs; known endpoints are probed again to refresh it.
+    // Packets were stored addressed to the server side, as the pipeline observes them.
+    if let Some(storage) = &storage {
+        match storage.load() {
+            Ok((packets, services)) => {
+                prober.restore(services);
+                for packet in &packets {
+                    info_extractor.flows.restore(packet);
+                    if let Some(scheduler) = &scheduler {
+                        scheduler.observe(packet);
+                    }
+                }
+                let services = prober.find_responses(None, None, None);
+                info!(packets = packets.len(), services = services.len(), "restored stored inventory");
+                if let Err(e) = storage.compact(&packets, &services) {
+                    warn!(error = %e, "failed to compact storage");
+                }
+            }
+            Err(e) => warn!(error = %e, "failed to load stored inventory"),
+        }
+    }
 
-    // Optional: Access future settings (log level, etc.)
-    if let Some(settings) = &config.settings {
-        if let Some(log_level) = &settings.log_level {
-            println!("Log level set to: {}", log_level);
-            // Set up logging if needed
+    // Start one pipeline per live interface and per capture file to replay
+    let mut supervisor = Supervisor::new(
+        Arc::clone(&info_extractor),
+        scheduler.clone(),
+        Arc::clone(&events),
+        storage.clone(),
+    );
+    supervisor.apply(&config.network);
+
+    // A config read from a file is reloaded when the file changes or on SIGHUP
+    let config_path = config.origin.as_ref().map(|origin| origin.path.clone());
+    let mut reloads = reload::watch(config_path.clone());
+
+    // Once every source has ended, a watched config file can still bring new ones, so
+    // only replays and inventories, which run until their sources end, stop there
+    let one_shot = matches!(cli.command, Some(Command::Replay { .. }) | Some(Command::Inventory { .. }));
+    let exit_when_idle = one_shot || config_path.is_none();
+
+    // Process tasks as they complete, applying config reloads in between
+    let pipelines = async {
+        let mut idle = false;
+        loop {
+            tokio::select! {
+                running = supervisor.join_next(), if !idle => {
+                    if !running {
+                        if exit_when_idle {
+                            break;
+                        }
+                        info!("no capture running, waiting for a config reload");
+                        idle = true;
+                    }
+                }
+                Some(()) = reloads.recv() => {
+                    let Some(path) = &config_path else {
+                        info!("no config file to reload");
+                        continue;
+                    };
+                    match reload_config(&cli, path).await {
+                        Ok(new_config) => {
+                            apply_reload(
+                                &cli,
+                                &mut config,
+                                new_config,
+                                &mut supervisor,
+                                &info_extractor,
+                                scheduler.as_deref(),
+                            );
+                            idle = false;
+                        }
+                        Err(errors) => {
+                            for e in &errors {
+                                error!(error = %e, "invalid configuration, keeping the running one");
+                            }
+                        }
+                    }
+                }
+                // Idle with the watcher gone: nothing can start a source any more
+                else => break,
+            }
         }
-        if let Some(storage_path) = &settings.storage_path {
-            println!("Storage path set to: {}", storage_path);
-            // Use the storage path if needed
+    };
+
+    match &cli.command {
+        Some(Command::Inventory { ip, port, protocol }) => {
+            // Live captures never end on their own, Ctrl-C stops collecting
+            tokio::select! {
+                _ = pipelines => {}
+                _ = tokio::signal::ctrl_c() => {}
+            }
+            output.emit(&Inventory {
+                flows: info_extractor.flows.find_flows(*ip, *port),
+                services: prober.find_responses(*ip, *port, protocol.as_deref()),
+            });
         }
+        _ => pipelines.await,
     }
+}
 
-    // Create a JoinSet to manage tasks
-    let mut join_set = JoinSet::new();
+// Reads the config file again with the same command line overrides and validation as at startup
+async fn reload_config(cli: &Cli, path: &Path) -> Result<Config, Vec<ConfigError>> {
+    let mut config = load_config(path).await.map_err(|e| vec![e])?;
+    cli.apply_overrides(&mut config);
+    config.validate()?;
+    Ok(config)
+}
 
-    // Start pipelines for each interface in parallel
-    for interface in config.network.interfaces {
-        let interface_monitor = Arc::new(InterfaceMonitor { device_name: interface.clone() });
-        let pipeline = TrafficPipeline {
-            interface_monitor,
-            info_extractor: Arc::clone(&info_extractor),
-        };
+// Applies the sections that changed to the running components and makes
+// `new_config` the running config
+fn apply_reload(
+    cli: &Cli,
+    running: &mut Config,
+    new_config: Config,
+    supervisor: &mut Supervisor,
+    info_extractor: &InformationExtractor,
+    scheduler: Option<&ProbeScheduler>,
+) {
+    if new_config.network != running.network {
+        supervisor.apply(&new_config.network);
+    }
+    if new_config.flows != running.flows {
+        info_extractor.flows.reconfigure(new_config.flows.as_ref());
+        info!("flow limits updated");
+    }
+    if new_config.reassembly != running.reassembly {
+        info_extractor.fragments.reconfigure(new_config.reassembly.as_ref());
+        info_extractor.streams.reconfigure(new_config.reassembly.as_ref());
+        info!("reassembly limits updated");
+    }
+    if new_config.probe != running.probe {
+        if let Some(scheduler) = scheduler {
+            scheduler.reconfigure(new_config.probe.as_ref());
+        }
+    }
+
+    // --log-level pins the level for the life of the process
+    let log_level = |config: &Config| config.settings.as_ref().and_then(|s| s.log_level.clone());
+    if cli.log_level.is_none() && log_level(&new_config) != log_level(running) {
+        match logging::set_level(log_level(&new_config).as_deref()) {
+            Ok(()) => info!("log level updated"),
+            Err(e) => warn!(error = %e, "failed to apply log level"),
+        }
+    }
 
-        // Spawn each pipeline and add to JoinSet
-        join_set.spawn(async move {
-            pipeline.process_pipeline().await;
-        });
+    // The log format and the storage are set up once at startup
+    let restart_only = |config: &Config| {
+        config.settings.as_ref().map(|s| {
+            (s.log_format, s.storage_path.clone(), s.storage_max_file_bytes, s.storage_max_files)
+        })
+    };
+    if restart_only(&new_config) != restart_only(running) {
+        warn!("log_format and storage settings take effect after a restart");
     }
 
-    // Process tasks as they complete
-    while let Some(result) = join_set.join_next().await {
-        match result {
-            Ok(_) => println!("Pipeline task completed successfully."),
-            Err(e) => eprintln!("Pipeline task failed: {:?}", e),
+    *running = new_config;
+    info!("configuration reloaded");
+}
+
+// Prints the capture devices pcap can open, with their addresses
+fn list_interfaces() {
+    let devices = match pcap::Device::list() {
+        Ok(devices) => devices,
+        Err(e) => {
+            eprintln!("Failed to list interfaces: {}", e);
+            process::exit(1);
+        }
+    };
+    for device in devices {
+        let addresses: Vec<String> = device.addresses.iter().map(|a| a.addr.to_string()).collect();
+        match &device.desc {
+            Some(desc) => println!("{}\t{}\t{}", device.name, desc, addresses.join(", ")),
+            None => println!("{}\t\t{}", device.name, addresses.join(", ")),
         }
     }
 }
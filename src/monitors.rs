@@ -1,30 +1,256 @@
-use pcap::Capture;
+use std::pin::Pin;
+use std::sync::{Arc, Mutex};
+use std::time::{Duration, Instant, SystemTime, UNIX_EPOCH};
+use pcap::{Capture, Linktype, PacketCodec, Stat};
 use async_trait::async_trait;
+use futures::{stream, Stream, StreamExt};
+use tokio::sync::mpsc;
 use tokio::task;
+use tokio::time::interval;
 use crate::packet::ClonablePacket as Packet;
 
+// How often the pcap counters are refreshed, whether or not packets are flowing
+const STATS_REFRESH_INTERVAL: Duration = Duration::from_secs(1);
+
+// Number of decoded packets a file reader may run ahead of the pipeline
+const REPLAY_QUEUE_DEPTH: usize = 1024;
+
+pub type PacketStream = Pin<Box<dyn Stream<Item = Result<Packet, pcap::Error>> + Send>>;
+
 #[async_trait]
 pub trait TrafficMonitor {
-    async fn capture_traffic(&self) -> Packet;
+    // Interface name or capture file path, used to label logs
+    fn name(&self) -> &str;
+
+    // Opens the source once and yields every captured packet until it is exhausted
+    async fn capture_traffic(&self) -> Result<PacketStream, pcap::Error>;
+
+    // Latest received/dropped counters reported by pcap, if any have been collected
+    fn stats(&self) -> Option<Stat>;
+}
+
+// Turns raw pcap frames into our layered packet representation
+struct ClonablePacketCodec {
+    link_type: Linktype, // Datalink of the capture, e.g. Ethernet or Linux cooked capture for "any"
+}
+
+impl PacketCodec for ClonablePacketCodec {
+    type Item = Packet;
+
+    fn decode(&mut self, packet: pcap::Packet<'_>) -> Self::Item {
+        let mut decoded = Packet::new(packet.data.to_vec(), self.link_type);
+        decoded.timestamp = capture_time(packet.header);
+        decoded
+    }
+}
+
+// Converts the timestamp of a pcap record header into wall clock time
+fn capture_time(header: &pcap::PacketHeader) -> SystemTime {
+    UNIX_EPOCH + Duration::new(header.ts.tv_sec as u64, header.ts.tv_usec as u32 * 1000)
 }
 
 pub struct InterfaceMonitor {
     pub device_name: String,
+    pub filter: Option<String>, // BPF expression applied in the kernel, e.g. "not port 22"
+    stats: Arc<Mutex<Option<Stat>>>,
+}
+
+impl InterfaceMonitor {
+    pub fn new(device_name: String, filter: Option<String>) -> Self {
+        InterfaceMonitor {
+            device_name,
+            filter,
+            stats: Arc::new(Mutex::new(None)),
+        }
+    }
 }
 
 #[async_trait]
 impl TrafficMonitor for InterfaceMonitor {
-    async fn capture_traffic(&self) -> Packet {
-        task::spawn_blocking({
-            let device_name = self.device_name.clone();
-            move || {
-                let mut cap = Capture::from_device(&device_name[..]).unwrap()
-                    .immediate_mode(true)
-                    .open().unwrap();
-                let packet = cap.next_packet().unwrap();
-                // Parsing the raw packet
-                Packet::new(packet.data.to_vec())
+    fn name(&self) -> &str {
+        &self.device_name
+    }
+
+    async fn capture_traffic(&self) -> Result<PacketStream, pcap::Error> {
+        // The device is opened a single time; the stream keeps the handle alive
+        let mut capture = Capture::from_device(&self.device_name[..])?
+            .immediate_mode(true)
+            .open()?
+            .setnonblock()?;
+        if let Some(filter) = &self.filter {
+            capture.filter(filter, true)?;
+        }
+        let codec = ClonablePacketCodec { link_type: capture.get_datalink() };
+        let packets = capture.stream(codec)?;
+
+        // The counters are refreshed on a timer while waiting for packets, so an idle
+        // or fully dropping interface still reports current numbers
+        let stats = Arc::clone(&self.stats);
+        let packets = stream::unfold((packets, interval(STATS_REFRESH_INTERVAL)), move |(mut packets, mut refresh)| {
+            let stats = Arc::clone(&stats);
+            async move {
+                loop {
+                    tokio::select! {
+                        packet = packets.next() => return Some((packet?, (packets, refresh))),
+                        _ = refresh.tick() => {
+                            if let Ok(stat) = packets.capture_mut().stats() {
+                                *stats.lock().unwrap() = Some(stat);
+                            }
+                        }
+                    }
+                }
             }
-        }).await.unwrap()
+        });
+
+        Ok(Box::pin(packets))
+    }
+
+    fn stats(&self) -> Option<Stat> {
+        *self.stats.lock().unwrap()
     }
-}
\ No newline at end of file
+}
+
+// Replays a saved .pcap or .pcapng capture as if it were live traffic
+pub struct FileMonitor {
+    pub path: String,
+    pub realtime: bool, // Honour the original inter-packet gaps instead of reading as fast as possible
+    pub filter: Option<String>, // BPF expression, packets that do not match are skipped
+}
+
+impl FileMonitor {
+    pub fn new(path: String, realtime: bool, filter: Option<String>) -> Self {
+        FileMonitor { path, realtime, filter }
+    }
+}
+
+#[async_trait]
+impl TrafficMonitor for FileMonitor {
+    fn name(&self) -> &str {
+        &self.path
+    }
+
+    async fn capture_traffic(&self) -> Result<PacketStream, pcap::Error> {
+        // Open up front so a missing or corrupt file is reported to the caller
+        let mut capture = Capture::from_file(&self.path)?;
+        if let Some(filter) = &self.filter {
+            capture.filter(filter, true)?;
+        }
+        let realtime = self.realtime;
+        let mut codec = ClonablePacketCodec { link_type: capture.get_datalink() };
+        let (tx, rx) = mpsc::channel(REPLAY_QUEUE_DEPTH);
+
+        task::spawn_blocking(move || {
+            let mut clock: Option<(SystemTime, Instant)> = None;
+
+            loop {
+                let item = match capture.next_packet() {
+                    Ok(packet) => {
+                        if realtime {
+                            let ts = capture_time(packet.header);
+                            let (first_ts, started) = *clock.get_or_insert((ts, Instant::now()));
+                            let due = ts.duration_since(first_ts).unwrap_or_default();
+                            let elapsed = started.elapsed();
+                            if due > elapsed {
+                                std::thread::sleep(due - elapsed);
+                            }
+                        }
+                        Ok(codec.decode(packet))
+                    }
+                    Err(pcap::Error::NoMorePackets) => break,
+                    Err(e) => Err(e),
+                };
+                let failed = item.is_err();
+                // The receiver is gone once the pipeline stops consuming
+                if tx.blocking_send(item).is_err() || failed {
+                    break;
+                }
+            }
+        });
+
+        let packets = stream::unfold(rx, |mut rx| async move {
+            rx.recv().await.map(|packet| (packet, rx))
+        });
+
+        Ok(Box::pin(packets))
+    }
+
+    fn stats(&self) -> Option<Stat> {
+        // pcap does not keep drop counters for savefiles
+        None
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::net::Ipv4Addr;
+
+    // Ethernet + IPv4 + UDP frame carrying `payload`
+    fn udp_frame(src_port: u16, dst_port: u16, payload: &[u8]) -> Vec<u8> {
+        let mut frame = vec![
+            0x02, 0x00, 0x00, 0x00, 0x00, 0x02, // Destination MAC
+            0x02, 0x00, 0x00, 0x00, 0x00, 0x01, // Source MAC
+            0x08, 0x00,
+        ];
+        let total_length = (20 + 8 + payload.len()) as u16;
+        frame.extend_from_slice(&[0x45, 0x00]);
+        frame.extend_from_slice(&total_length.to_be_bytes());
+        frame.extend_from_slice(&[0x00, 0x01, 0x00, 0x00, 0x40, 17, 0x00, 0x00]);
+        frame.extend_from_slice(&[192, 168, 1, 10, 192, 168, 1, 1]);
+        frame.extend_from_slice(&src_port.to_be_bytes());
+        frame.extend_from_slice(&dst_port.to_be_bytes());
+        frame.extend_from_slice(&((8 + payload.len()) as u16).to_be_bytes());
+        frame.extend_from_slice(&[0x00, 0x00]);
+        frame.extend_from_slice(payload);
+        frame
+    }
+
+    // Classic microsecond pcap savefile with an Ethernet link type
+    fn savefile(frames: &[(u32, u32, Vec<u8>)]) -> Vec<u8> {
+        let mut file = Vec::new();
+        for field in [0xa1b2c3d4u32, 0x0004_0002, 0, 0, 65535, 1] {
+            file.extend_from_slice(&field.to_ne_bytes());
+        }
+        for (secs, micros, frame) in frames {
+            for field in [*secs, *micros, frame.len() as u32, frame.len() as u32] {
+                file.extend_from_slice(&field.to_ne_bytes());
+            }
+            file.extend_from_slice(frame);
+        }
+        file
+    }
+
+    #[tokio::test]
+    async fn replays_every_packet_of_a_savefile() {
+        let path = std::env::temp_dir().join(format!("netflex-replay-{}.pcap", std::process::id()));
+        let frames = vec![
+            (1_700_000_000, 250_000, udp_frame(49152, 53, b"query")),
+            (1_700_000_001, 0, udp_frame(53, 49152, b"answer")),
+        ];
+        std::fs::write(&path, savefile(&frames)).unwrap();
+
+        let monitor = FileMonitor::new(path.to_string_lossy().into_owned(), false, None);
+        let packets: Vec<_> = monitor.capture_traffic().await.unwrap().collect().await;
+        std::fs::remove_file(&path).unwrap();
+
+        assert_eq!(packets.len(), 2);
+        let first = packets[0].as_ref().unwrap();
+        assert_eq!(first.raw, frames[0].2);
+        assert_eq!(first.timestamp, UNIX_EPOCH + Duration::new(1_700_000_000, 250_000_000));
+        let ipv4 = first.ipv4.as_ref().and_then(|ip| ip.parse()).unwrap();
+        assert_eq!(ipv4.get_source(), Ipv4Addr::new(192, 168, 1, 10));
+        let udp = first.udp.as_ref().and_then(|udp| udp.parse()).unwrap();
+        assert_eq!((udp.get_source(), udp.get_destination()), (49152, 53));
+
+        let second = packets[1].as_ref().unwrap();
+        let udp = second.udp.as_ref().and_then(|udp| udp.parse()).unwrap();
+        assert_eq!(udp.get_source(), 53);
+        assert!(monitor.stats().is_none());
+    }
+
+    #[tokio::test]
+    async fn missing_file_is_reported_up_front() {
+        let monitor = FileMonitor::new("/nonexistent/netflex.pcap".to_string(), false, None);
+        assert!(monitor.capture_traffic().await.is_err());
+    }
+}
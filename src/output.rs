@@ -0,0 +1,68 @@
+use std::fs::{File, OpenOptions};
+use std::io::{self, LineWriter, Write};
+use std::path::Path;
+use std::sync::Mutex;
+use serde::Serialize;
+use tracing::error;
+
+use crate::extractor::PacketInfo;
+use crate::flow::Flow;
+use crate::l7::Layer7Message;
+use crate::probe::ValidResponse;
+
+// A discovery worth reporting, written as one JSON object per line
+#[derive(Serialize)]
+#[serde(tag = "event", rename_all = "snake_case")]
+pub enum Event<'a> {
+    Layer7 { packet: &'a PacketInfo, message: &'a Layer7Message },
+    Icmp { packet: &'a PacketInfo }, // ICMP errors and Neighbor Discovery; echo traffic is not reported
+    Service { service: &'a ValidResponse },
+}
+
+// Snapshot of everything discovered so far
+#[derive(Serialize)]
+pub struct Inventory {
+    pub flows: Vec<Flow>,
+    pub services: Vec<ValidResponse>,
+}
+
+// Destination for discoveries: stdout by default, or a file opened in append mode
+pub enum Output {
+    Stdout,
+    File(Mutex<LineWriter<File>>),
+    Discard, // Used while collecting an inventory, which is reported once at the end
+}
+
+impl Output {
+    pub fn new(path: Option<&Path>) -> io::Result<Self> {
+        match path {
+            Some(path) => {
+                let file = OpenOptions::new().create(true).append(true).open(path)?;
+                Ok(Output::File(Mutex::new(LineWriter::new(file))))
+            }
+            None => Ok(Output::Stdout),
+        }
+    }
+
+    pub fn emit<T: Serialize>(&self, record: &T) {
+        if let Output::Discard = self {
+            return;
+        }
+        let line = match serde_json::to_string(record) {
+            Ok(line) => line,
+            Err(e) => {
+                error!(error = %e, "failed to serialize output record");
+                return;
+            }
+        };
+        match self {
+            Output::Stdout => println!("{}", line),
+            Output::File(file) => {
+                if let Err(e) = writeln!(file.lock().unwrap(), "{}", line) {
+                    error!(error = %e, "failed to write output record");
+                }
+            }
+            Output::Discard => {}
+        }
+    }
+}
@@ -1,7 +1,13 @@
+use std::net::IpAddr;
+use std::time::SystemTime;
+use pcap::Linktype;
+use serde::{Deserialize, Serialize};
 use pnet::packet::{
-    arp::ArpPacket, ethernet::{EtherTypes, EthernetPacket}, icmp::IcmpPacket, icmpv6::Icmpv6Packet, 
-    ipv4::Ipv4Packet, ipv6::Ipv6Packet, tcp::TcpPacket, udp::UdpPacket, Packet, ip::IpNextHeaderProtocols
+    arp::ArpPacket, ethernet::{EtherType, EtherTypes, EthernetPacket}, icmp::IcmpPacket, icmpv6::Icmpv6Packet, 
+    ipv4::{Ipv4Flags, Ipv4Packet}, ipv6::Ipv6Packet, tcp::TcpPacket, udp::UdpPacket, Packet,
+    ip::{IpNextHeaderProtocol, IpNextHeaderProtocols}
 };
+use pnet::util::MacAddr;
 
 #[derive(Debug, Clone)]
 pub struct ClonableEthernetPacket {
@@ -40,12 +46,16 @@ impl ClonableIpv4Packet {
 #[derive(Debug, Clone)]
 pub struct ClonableIpv6Packet {
     pub data: Vec<u8>,
+    pub extensions: Vec<Ipv6Extension>, // Extension headers in the order they appear
+    pub upper_protocol: u8, // Next header after the extension chain, e.g. 6 for TCP
 }
 
 impl ClonableIpv6Packet {
     pub fn new(data: &[u8]) -> Option<Self> {
         Some(ClonableIpv6Packet {
             data: data.to_vec(),
+            extensions: Vec::new(),
+            upper_protocol: data.get(6).copied().unwrap_or_default(),
         })
     }
 
@@ -139,10 +149,116 @@ impl ClonableArpPacket {
     }
 }
 
+// Encapsulations that can be unwrapped before the network layer are limited to
+// this many headers so a malformed frame cannot keep the decoder looping
+const MAX_ENCAPSULATION_DEPTH: usize = 8;
+
+const VXLAN_PORT: u16 = 4789;
+const GENEVE_PORT: u16 = 6081;
+
+// GRE protocol types that are not plain EtherTypes
+const GRE_TRANSPARENT_ETHERNET: u16 = 0x6558;
+const GRE_ERSPAN_II: u16 = 0x88be;
+const GRE_ERSPAN_III: u16 = 0x22eb;
+
+// DLT_RAW as reported for live captures; savefiles use LINKTYPE_RAW (101)
+const DLT_RAW: Linktype = Linktype(12);
+const DLT_RAW_OPENBSD: Linktype = Linktype(14);
+
+// LLC/SNAP header that precedes the EtherType in 802.11 data frames
+const LLC_SNAP: [u8; 6] = [0xaa, 0xaa, 0x03, 0x00, 0x00, 0x00];
+
+// Addresses of an 802.11 data frame, resolved from the ToDS/FromDS flags
+#[derive(Debug, Clone, Copy)]
+pub struct Ieee80211Header {
+    pub src: MacAddr,
+    pub dst: MacAddr,
+    pub bssid: Option<MacAddr>, // Absent for frames relayed between access points
+}
+
+// IPv6 extension headers (RFC 8200 section 4 and later additions)
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash, Serialize, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum Ipv6Extension {
+    HopByHop,
+    Routing,
+    Fragment,
+    DestinationOptions,
+    Authentication,
+    EncapsulatingSecurityPayload, // Ends the walk; what follows is encrypted
+    Mobility,
+    HostIdentity,
+    Shim6,
+}
+
+impl Ipv6Extension {
+    pub fn from_protocol(protocol: IpNextHeaderProtocol) -> Option<Self> {
+        match protocol {
+            IpNextHeaderProtocols::Hopopt => Some(Ipv6Extension::HopByHop),
+            IpNextHeaderProtocols::Ipv6Route => Some(Ipv6Extension::Routing),
+            IpNextHeaderProtocols::Ipv6Frag => Some(Ipv6Extension::Fragment),
+            IpNextHeaderProtocols::Ipv6Opts => Some(Ipv6Extension::DestinationOptions),
+            IpNextHeaderProtocols::Ah => Some(Ipv6Extension::Authentication),
+            IpNextHeaderProtocols::Esp => Some(Ipv6Extension::EncapsulatingSecurityPayload),
+            IpNextHeaderProtocols::MobilityHeader => Some(Ipv6Extension::Mobility),
+            IpNextHeaderProtocols::Hip => Some(Ipv6Extension::HostIdentity),
+            IpNextHeaderProtocols::Shim6 => Some(Ipv6Extension::Shim6),
+            _ => None,
+        }
+    }
+}
+
+// Identifies the datagram a fragment belongs to (RFC 791 / RFC 8200)
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash)]
+pub struct FragmentKey {
+    pub src: IpAddr,
+    pub dst: IpAddr,
+    pub id: u32,      // IPv4 identification or IPv6 fragment header identification
+    pub protocol: u8, // Protocol of the reassembled payload
+}
+
+// One fragment of an IPv4 or IPv6 datagram. The transport layer of a fragmented
+// datagram is only decoded once FragmentReassembler has all of its pieces.
+#[derive(Debug, Clone)]
+pub struct Fragment {
+    pub key: FragmentKey,
+    pub offset: usize, // Position of the payload in the reassembled datagram, in bytes
+    pub more: bool,    // More fragments follow; false on the last one
+    pub payload: Vec<u8>,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash, Serialize, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum TunnelKind {
+    Gre,
+    Erspan,
+    Vxlan,
+    Geneve,
+    IpInIp,
+}
+
+// Outer layers of one tunnel the packet was carried in. The Ethernet header is
+// only moved here when the tunnel carries a whole frame (VXLAN, GENEVE, ERSPAN,
+// GRE bridging); for tunnels carrying bare IP it stays on the packet.
+#[derive(Debug, Clone)]
+pub struct Tunnel {
+    pub kind: TunnelKind,
+    pub id: Option<u32>, // GRE key, ERSPAN session ID or VXLAN/GENEVE VNI
+    pub ethernet: Option<ClonableEthernetPacket>,
+    pub ipv4: Option<ClonableIpv4Packet>,
+    pub ipv6: Option<ClonableIpv6Packet>,
+}
+
 #[derive(Debug, Clone)]
 pub struct ClonablePacket {
     pub raw: Vec<u8>,
+    pub timestamp: SystemTime, // Capture time; set from the pcap header when available
     pub ethernet: Option<ClonableEthernetPacket>,
+    pub ieee80211: Option<Ieee80211Header>, // Set instead of ethernet for wireless captures
+    pub vlan_ids: Vec<u16>,    // 802.1Q/802.1ad VLAN IDs, outermost tag first
+    pub mpls_labels: Vec<u32>, // MPLS label stack, top label first
+    pub tunnels: Vec<Tunnel>,  // Outermost first; the other layers are those of the innermost packet
+    pub fragment: Option<Fragment>, // Set while the innermost IP datagram is an unreassembled fragment
     pub ipv4: Option<ClonableIpv4Packet>,
     pub ipv6: Option<ClonableIpv6Packet>,
     pub tcp: Option<ClonableTcpPacket>,
@@ -153,69 +269,723 @@ pub struct ClonablePacket {
 }
 
 impl ClonablePacket {
-    pub fn new(raw_data: Vec<u8>) -> Self {
-        let ethernet = ClonableEthernetPacket::new(&raw_data);
-        let mut ipv4 = None;
-        let mut ipv6 = None;
-        let mut arp = None;
-        let mut tcp = None;
-        let mut udp = None;
-        let mut icmp = None;
-        let mut icmpv6 = None;
-
-        if let Some(eth_packet) = ethernet.as_ref().and_then(|e| e.parse()) {
-            match eth_packet.get_ethertype() {
-                EtherTypes::Ipv4 => {
-                    ipv4 = ClonableIpv4Packet::new(eth_packet.payload());
-                    if let Some(ipv4_packet) = ipv4.as_ref().and_then(|p| p.parse()) {
-                        match ipv4_packet.get_next_level_protocol() {
-                            IpNextHeaderProtocols::Tcp => {
-                                tcp = ClonableTcpPacket::new(ipv4_packet.payload());
-                            }
-                            IpNextHeaderProtocols::Udp => {
-                                udp = ClonableUdpPacket::new(ipv4_packet.payload());
-                            }
-                            IpNextHeaderProtocols::Icmp => {
-                                icmp = ClonableIcmpPacket::new(ipv4_packet.payload());
-                            }
-                            _ => {}
-                        }
+    // Decodes a captured frame; `link_type` is the datalink of the capture it came from
+    pub fn new(raw_data: Vec<u8>, link_type: Linktype) -> Self {
+        let mut packet = ClonablePacket {
+            raw: Vec::new(),
+            timestamp: SystemTime::now(),
+            ethernet: None,
+            ieee80211: None,
+            vlan_ids: Vec::new(),
+            mpls_labels: Vec::new(),
+            tunnels: Vec::new(),
+            fragment: None,
+            ipv4: None,
+            ipv6: None,
+            tcp: None,
+            udp: None,
+            icmp: None,
+            icmpv6: None,
+            arp: None,
+        };
+
+        packet.decode_link(link_type, &raw_data);
+        packet.raw = raw_data;
+        packet
+    }
+
+    fn decode_link(&mut self, link_type: Linktype, data: &[u8]) {
+        match link_type {
+            Linktype::ETHERNET => self.decode_ethernet(data),
+            Linktype::RAW | DLT_RAW | DLT_RAW_OPENBSD | Linktype::IPV4 | Linktype::IPV6 => self.decode_raw_ip(data),
+            // A 4 byte address family whose values and byte order differ between
+            // systems, so the IP version nibble is used instead
+            Linktype::NULL | Linktype::LOOP if data.len() > 4 => self.decode_raw_ip(&data[4..]),
+            // Linux cooked capture: packet type, ARPHRD type, address length,
+            // 8 byte address, then the EtherType
+            Linktype::LINUX_SLL if data.len() >= 16 => {
+                let protocol = u16::from_be_bytes([data[14], data[15]]);
+                self.decode_ethertype(EtherType(protocol), &data[16..]);
+            }
+            // Version 2 moves the EtherType to the front and adds the interface index
+            Linktype::LINUX_SLL2 if data.len() >= 20 => {
+                let protocol = u16::from_be_bytes([data[0], data[1]]);
+                self.decode_ethertype(EtherType(protocol), &data[20..]);
+            }
+            Linktype::IEEE802_11_RADIOTAP if data.len() >= 4 => {
+                // The radiotap header length is little endian
+                let len = u16::from_le_bytes([data[2], data[3]]) as usize;
+                if let Some(frame) = data.get(len..) {
+                    self.decode_ieee80211(frame);
+                }
+            }
+            Linktype::IEEE802_11 => self.decode_ieee80211(data),
+            _ => {}
+        }
+    }
+
+    fn decode_raw_ip(&mut self, data: &[u8]) {
+        match data.first().map(|b| b >> 4) {
+            Some(4) => self.decode_ipv4(data),
+            Some(6) => self.decode_ipv6(data),
+            _ => {}
+        }
+    }
+
+    // Decodes unencrypted 802.11 data frames; management and control frames carry no packets
+    fn decode_ieee80211(&mut self, data: &[u8]) {
+        if data.len() < 24 {
+            return;
+        }
+        let frame_type = (data[0] >> 2) & 0x03;
+        let subtype = data[0] >> 4;
+        let flags = data[1];
+        // Data frames only, skipping the null function subtypes and protected payloads
+        if frame_type != 2 || subtype & 0x04 != 0 || flags & 0x40 != 0 {
+            return;
+        }
+        let address = |offset: usize| {
+            MacAddr::new(data[offset], data[offset + 1], data[offset + 2], data[offset + 3], data[offset + 4], data[offset + 5])
+        };
+        let to_ds = flags & 0x01 != 0;
+        let from_ds = flags & 0x02 != 0;
+        let mut header_len = if to_ds && from_ds { 30 } else { 24 };
+        if data.len() < header_len {
+            return;
+        }
+        // Address 1 is the receiver and address 2 the transmitter; which of them are
+        // the source, destination and BSSID depends on the direction
+        self.ieee80211 = Some(match (to_ds, from_ds) {
+            (false, false) => Ieee80211Header { dst: address(4), src: address(10), bssid: Some(address(16)) },
+            (true, false) => Ieee80211Header { bssid: Some(address(4)), src: address(10), dst: address(16) },
+            (false, true) => Ieee80211Header { dst: address(4), bssid: Some(address(10)), src: address(16) },
+            (true, true) => Ieee80211Header { dst: address(16), src: address(24), bssid: None },
+        });
+        // QoS data subtypes add a QoS control field, and an HT control field when the order flag is set
+        if subtype & 0x08 != 0 {
+            header_len += 2;
+            if flags & 0x80 != 0 {
+                header_len += 4;
+            }
+        }
+        let body = match data.get(header_len..) {
+            Some(body) if body.len() >= 8 && body[..6] == LLC_SNAP => body,
+            _ => return,
+        };
+        self.decode_ethertype(EtherType(u16::from_be_bytes([body[6], body[7]])), &body[8..]);
+    }
+
+    fn decode_ethernet(&mut self, data: &[u8]) {
+        self.ethernet = ClonableEthernetPacket::new(data);
+        if let Some(eth_packet) = EthernetPacket::new(data) {
+            self.decode_ethertype(eth_packet.get_ethertype(), eth_packet.payload());
+        }
+    }
+
+    // Unwraps VLAN tags and MPLS labels, then decodes the network layer they carry
+    fn decode_ethertype(&mut self, mut ethertype: EtherType, mut payload: &[u8]) {
+        for _ in 0..MAX_ENCAPSULATION_DEPTH {
+            match ethertype {
+                EtherTypes::Vlan | EtherTypes::PBridge | EtherTypes::QinQ => {
+                    // Tag control information (PCP, DEI, 12-bit VLAN ID) then the inner EtherType
+                    if payload.len() < 4 {
+                        return;
                     }
+                    self.vlan_ids.push(u16::from_be_bytes([payload[0], payload[1]]) & 0x0fff);
+                    ethertype = EtherType(u16::from_be_bytes([payload[2], payload[3]]));
+                    payload = &payload[4..];
                 }
-                EtherTypes::Ipv6 => {
-                    ipv6 = ClonableIpv6Packet::new(eth_packet.payload());
-                    if let Some(ipv6_packet) = ipv6.as_ref().and_then(|p| p.parse()) {
-                        match ipv6_packet.get_next_header() {
-                            IpNextHeaderProtocols::Tcp => {
-                                tcp = ClonableTcpPacket::new(ipv6_packet.payload());
-                            }
-                            IpNextHeaderProtocols::Udp => {
-                                udp = ClonableUdpPacket::new(ipv6_packet.payload());
-                            }
-                            IpNextHeaderProtocols::Icmpv6 => {
-                                icmpv6 = ClonableIcmpv6Packet::new(ipv6_packet.payload());
-                            }
-                            _ => {}
+                EtherTypes::Mpls | EtherTypes::MplsMcast => {
+                    // 20-bit label, traffic class, bottom of stack flag and TTL per entry
+                    loop {
+                        if payload.len() < 4 {
+                            return;
+                        }
+                        let entry = u32::from_be_bytes([payload[0], payload[1], payload[2], payload[3]]);
+                        self.mpls_labels.push(entry >> 12);
+                        payload = &payload[4..];
+                        if entry & 0x100 != 0 {
+                            break;
                         }
                     }
+                    // MPLS does not say what it carries; IP is recognised by its version nibble
+                    ethertype = match payload.first().map(|b| b >> 4) {
+                        Some(4) => EtherTypes::Ipv4,
+                        Some(6) => EtherTypes::Ipv6,
+                        _ => return,
+                    };
                 }
-                EtherTypes::Arp => {
-                    arp = ClonableArpPacket::new(eth_packet.payload());
+                _ => break,
+            }
+        }
+
+        match ethertype {
+            EtherTypes::Ipv4 => self.decode_ipv4(payload),
+            EtherTypes::Ipv6 => self.decode_ipv6(payload),
+            EtherTypes::Arp => self.arp = ClonableArpPacket::new(payload),
+            _ => {}
+        }
+    }
+
+    fn decode_ipv4(&mut self, data: &[u8]) {
+        self.ipv4 = ClonableIpv4Packet::new(data);
+        if let Some(ipv4_packet) = Ipv4Packet::new(data) {
+            let protocol = ipv4_packet.get_next_level_protocol();
+            let more = ipv4_packet.get_flags() & Ipv4Flags::MoreFragments != 0;
+            let offset = ipv4_packet.get_fragment_offset() as usize * 8;
+            if more || offset != 0 {
+                self.fragment = Some(Fragment {
+                    key: FragmentKey {
+                        src: IpAddr::V4(ipv4_packet.get_source()),
+                        dst: IpAddr::V4(ipv4_packet.get_destination()),
+                        id: ipv4_packet.get_identification() as u32,
+                        protocol: protocol.0,
+                    },
+                    offset,
+                    more,
+                    payload: ipv4_packet.payload().to_vec(),
+                });
+                return;
+            }
+            self.decode_transport(protocol, ipv4_packet.payload());
+        }
+    }
+
+    fn decode_ipv6(&mut self, data: &[u8]) {
+        self.ipv6 = ClonableIpv6Packet::new(data);
+        if let Some(ipv6_packet) = Ipv6Packet::new(data) {
+            self.decode_ipv6_payload(ipv6_packet.get_next_header(), ipv6_packet.payload());
+        }
+    }
+
+    // Walks the IPv6 extension header chain, recording each header, up to the
+    // upper-layer payload. A non-atomic Fragment header ends the walk; the rest of
+    // the chain is walked again once the datagram has been reassembled.
+    fn decode_ipv6_payload(&mut self, mut next: IpNextHeaderProtocol, mut payload: &[u8]) {
+        while let Some(extension) = Ipv6Extension::from_protocol(next) {
+            if let Some(ipv6) = self.ipv6.as_mut() {
+                ipv6.extensions.push(extension);
+            }
+            let header_len = match extension {
+                Ipv6Extension::EncapsulatingSecurityPayload => break,
+                Ipv6Extension::Fragment => 8,
+                // Length in 4 byte units, not counting the first two
+                Ipv6Extension::Authentication => payload.get(1).map_or(0, |len| (*len as usize + 2) * 4),
+                // Length in 8 byte units, not counting the first
+                _ => payload.get(1).map_or(0, |len| (*len as usize + 1) * 8),
+            };
+            if header_len == 0 || payload.len() < header_len {
+                return;
+            }
+            if extension == Ipv6Extension::Fragment && self.decode_ipv6_fragment(payload) {
+                return;
+            }
+            next = IpNextHeaderProtocol(payload[0]);
+            payload = &payload[header_len..];
+        }
+
+        if let Some(ipv6) = self.ipv6.as_mut() {
+            ipv6.upper_protocol = next.0;
+        }
+        self.decode_transport(next, payload);
+    }
+
+    // Fragment header: next header, reserved, 13-bit offset in 8 byte units with the
+    // M flag in the lowest bit, then the identification. Returns false for an atomic
+    // fragment (RFC 6946), which is a whole datagram.
+    fn decode_ipv6_fragment(&mut self, header: &[u8]) -> bool {
+        let offset_flags = u16::from_be_bytes([header[2], header[3]]);
+        let offset = (offset_flags >> 3) as usize * 8;
+        let more = offset_flags & 0x0001 != 0;
+        if !more && offset == 0 {
+            return false;
+        }
+        let Some(ipv6_packet) = self.ipv6.as_ref().and_then(|ipv6| ipv6.parse()) else {
+            return true;
+        };
+        self.fragment = Some(Fragment {
+            key: FragmentKey {
+                src: IpAddr::V6(ipv6_packet.get_source()),
+                dst: IpAddr::V6(ipv6_packet.get_destination()),
+                id: u32::from_be_bytes([header[4], header[5], header[6], header[7]]),
+                protocol: header[0],
+            },
+            offset,
+            more,
+            payload: header[8..].to_vec(),
+        });
+        true
+    }
+
+    // Decodes the transport layer of a datagram rebuilt from the fragments of this packet
+    pub fn complete_fragment(&mut self, key: &FragmentKey, payload: &[u8]) {
+        self.fragment = None;
+        let protocol = IpNextHeaderProtocol(key.protocol);
+        match key.src {
+            // More extension headers may follow the Fragment header
+            IpAddr::V6(_) => self.decode_ipv6_payload(protocol, payload),
+            IpAddr::V4(_) => self.decode_transport(protocol, payload),
+        }
+    }
+
+    fn decode_transport(&mut self, protocol: IpNextHeaderProtocol, payload: &[u8]) {
+        match protocol {
+            IpNextHeaderProtocols::Tcp => {
+                self.tcp = ClonableTcpPacket::new(payload);
+            }
+            IpNextHeaderProtocols::Udp => {
+                self.udp = ClonableUdpPacket::new(payload);
+                if let Some(udp_packet) = UdpPacket::new(payload) {
+                    match udp_packet.get_destination() {
+                        VXLAN_PORT => self.decode_vxlan(udp_packet.payload()),
+                        GENEVE_PORT => self.decode_geneve(udp_packet.payload()),
+                        _ => {}
+                    }
                 }
-                _ => {}
             }
+            IpNextHeaderProtocols::Gre => self.decode_gre(payload),
+            IpNextHeaderProtocols::Ipv4 | IpNextHeaderProtocols::Ipv6 => self.decode_ip_in_ip(protocol, payload),
+            IpNextHeaderProtocols::Icmp => {
+                self.icmp = ClonableIcmpPacket::new(payload);
+            }
+            IpNextHeaderProtocols::Icmpv6 => {
+                self.icmpv6 = ClonableIcmpv6Packet::new(payload);
+            }
+            _ => {}
+        }
+    }
+
+    fn decode_ip_in_ip(&mut self, protocol: IpNextHeaderProtocol, data: &[u8]) {
+        if !self.enter_tunnel(TunnelKind::IpInIp, None) {
+            return;
+        }
+        if protocol == IpNextHeaderProtocols::Ipv4 {
+            self.decode_ipv4(data);
+        } else {
+            self.decode_ipv6(data);
+        }
+    }
+
+    // VXLAN (RFC 7348): flags, reserved, 24-bit VNI, reserved, then an Ethernet frame
+    fn decode_vxlan(&mut self, data: &[u8]) {
+        // The I flag says the VNI is valid
+        if data.len() < 8 || data[0] & 0x08 == 0 {
+            return;
+        }
+        let vni = u32::from_be_bytes([0, data[4], data[5], data[6]]);
+        if self.enter_tunnel(TunnelKind::Vxlan, Some(vni)) {
+            self.decode_ethernet_in_tunnel(&data[8..]);
         }
+    }
+
+    // GENEVE (RFC 8926): version and option length, flags, protocol type, 24-bit VNI,
+    // reserved, then variable length options
+    fn decode_geneve(&mut self, data: &[u8]) {
+        if data.len() < 8 || data[0] >> 6 != 0 {
+            return;
+        }
+        let header_len = 8 + (data[0] & 0x3f) as usize * 4;
+        if data.len() < header_len {
+            return;
+        }
+        let protocol = u16::from_be_bytes([data[2], data[3]]);
+        let vni = u32::from_be_bytes([0, data[4], data[5], data[6]]);
+        if self.enter_tunnel(TunnelKind::Geneve, Some(vni)) {
+            self.decode_ethertype_in_tunnel(EtherType(protocol), &data[header_len..]);
+        }
+    }
 
-        ClonablePacket {
-            raw: raw_data,
-            ethernet,
-            ipv4,
-            ipv6,
-            tcp,
-            udp,
-            icmp,
-            icmpv6,
-            arp,
+    // GRE (RFC 2784/2890): flags and version, protocol type, then optional checksum,
+    // key and sequence number. ERSPAN mirrors whole frames over GRE.
+    fn decode_gre(&mut self, data: &[u8]) {
+        if data.len() < 4 {
+            return;
         }
+        let flags = u16::from_be_bytes([data[0], data[1]]);
+        let protocol = u16::from_be_bytes([data[2], data[3]]);
+        // Only version 0 carries packets; version 1 is PPTP
+        if flags & 0x0007 != 0 {
+            return;
+        }
+        let mut offset = 4;
+        if flags & 0x8000 != 0 {
+            offset += 4; // Checksum and reserved
+        }
+        let mut key = None;
+        if flags & 0x2000 != 0 {
+            if data.len() < offset + 4 {
+                return;
+            }
+            key = Some(u32::from_be_bytes([data[offset], data[offset + 1], data[offset + 2], data[offset + 3]]));
+            offset += 4;
+        }
+        let sequenced = flags & 0x1000 != 0;
+        if sequenced {
+            offset += 4;
+        }
+        if data.len() < offset {
+            return;
+        }
+        let payload = &data[offset..];
+
+        match protocol {
+            // ERSPAN type I has no header of its own and no GRE sequence number
+            GRE_ERSPAN_II if !sequenced => {
+                if self.enter_tunnel(TunnelKind::Erspan, None) {
+                    self.decode_ethernet_in_tunnel(payload);
+                }
+            }
+            GRE_ERSPAN_II | GRE_ERSPAN_III => {
+                // Type II has an 8 byte header, type III 12 bytes plus an optional 8 byte subheader
+                let header_len = match protocol {
+                    GRE_ERSPAN_II => 8,
+                    _ if payload.len() >= 12 && payload[11] & 0x01 != 0 => 20,
+                    _ => 12,
+                };
+                if payload.len() < header_len {
+                    return;
+                }
+                let session = u16::from_be_bytes([payload[2], payload[3]]) & 0x03ff;
+                if self.enter_tunnel(TunnelKind::Erspan, Some(session as u32)) {
+                    self.decode_ethernet_in_tunnel(&payload[header_len..]);
+                }
+            }
+            _ => {
+                if self.enter_tunnel(TunnelKind::Gre, key) {
+                    self.decode_ethertype_in_tunnel(EtherType(protocol), payload);
+                }
+            }
+        }
+    }
+
+    // Moves the outer network layer into a new tunnel record so the inner packet can
+    // be decoded into the top level fields. Returns false once the nesting limit is
+    // reached.
+    fn enter_tunnel(&mut self, kind: TunnelKind, id: Option<u32>) -> bool {
+        if self.tunnels.len() >= MAX_ENCAPSULATION_DEPTH {
+            return false;
+        }
+        self.tunnels.push(Tunnel {
+            kind,
+            id,
+            ethernet: None,
+            ipv4: self.ipv4.take(),
+            ipv6: self.ipv6.take(),
+        });
+        // The outer UDP header of VXLAN and GENEVE only carries the well-known port
+        self.udp = None;
+        true
+    }
+
+    fn decode_ethernet_in_tunnel(&mut self, data: &[u8]) {
+        if let Some(tunnel) = self.tunnels.last_mut() {
+            tunnel.ethernet = self.ethernet.take();
+        }
+        self.decode_ethernet(data);
+    }
+
+    fn decode_ethertype_in_tunnel(&mut self, ethertype: EtherType, data: &[u8]) {
+        if ethertype == EtherType(GRE_TRANSPARENT_ETHERNET) {
+            self.decode_ethernet_in_tunnel(data);
+        } else {
+            self.decode_ethertype(ethertype, data);
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::net::Ipv4Addr;
+
+    const PROTOCOL_UDP: u8 = 17;
+
+    fn ethernet(ethertype: u16, payload: &[u8]) -> Vec<u8> {
+        let mut frame = vec![0x02, 0, 0, 0, 0, 0x02, 0x02, 0, 0, 0, 0, 0x01];
+        frame.extend_from_slice(&ethertype.to_be_bytes());
+        frame.extend_from_slice(payload);
+        frame
+    }
+
+    // 10.0.0.1 to 10.0.0.2, not fragmented
+    fn ipv4(protocol: u8, payload: &[u8]) -> Vec<u8> {
+        let mut datagram = vec![0x45, 0x00];
+        datagram.extend_from_slice(&(20 + payload.len() as u16).to_be_bytes());
+        datagram.extend_from_slice(&[0x00, 0x01, 0x00, 0x00, 0x40, protocol, 0x00, 0x00]);
+        datagram.extend_from_slice(&[10, 0, 0, 1, 10, 0, 0, 2]);
+        datagram.extend_from_slice(payload);
+        datagram
+    }
+
+    // Same as ipv4() between other addresses, for the inner packet of a tunnel
+    fn inner_ipv4(protocol: u8, payload: &[u8]) -> Vec<u8> {
+        let mut datagram = ipv4(protocol, payload);
+        datagram[12..20].copy_from_slice(&[192, 168, 0, 1, 192, 168, 0, 2]);
+        datagram
+    }
+
+    fn udp(src_port: u16, dst_port: u16, payload: &[u8]) -> Vec<u8> {
+        let mut datagram = src_port.to_be_bytes().to_vec();
+        datagram.extend_from_slice(&dst_port.to_be_bytes());
+        datagram.extend_from_slice(&(8 + payload.len() as u16).to_be_bytes());
+        datagram.extend_from_slice(&[0x00, 0x00]);
+        datagram.extend_from_slice(payload);
+        datagram
+    }
+
+    fn udp_ports(packet: &ClonablePacket) -> Option<(u16, u16)> {
+        let udp = packet.udp.as_ref()?.parse()?;
+        Some((udp.get_source(), udp.get_destination()))
+    }
+
+    fn ipv4_addresses(packet: &ClonablePacket) -> Option<(Ipv4Addr, Ipv4Addr)> {
+        let ipv4 = packet.ipv4.as_ref()?.parse()?;
+        Some((ipv4.get_source(), ipv4.get_destination()))
+    }
+
+    #[test]
+    fn unwraps_stacked_vlan_tags() {
+        // 802.1ad service tag 100 (PCP 5), then 802.1Q customer tag 200
+        let mut tags = vec![0xa0, 0x64, 0x81, 0x00, 0x00, 0xc8, 0x08, 0x00];
+        tags.extend(ipv4(PROTOCOL_UDP, &udp(5353, 5353, b"")));
+        let packet = ClonablePacket::new(ethernet(0x88a8, &tags), Linktype::ETHERNET);
+        assert_eq!(packet.vlan_ids, vec![100, 200]);
+        assert_eq!(udp_ports(&packet), Some((5353, 5353)));
+    }
+
+    #[test]
+    fn unwraps_mpls_label_stack() {
+        // Labels 16 and 17, the second with the bottom of stack flag
+        let mut labels = vec![0x00, 0x01, 0x00, 0x40, 0x00, 0x01, 0x11, 0x40];
+        labels.extend(ipv4(PROTOCOL_UDP, &udp(1000, 2000, b"")));
+        let packet = ClonablePacket::new(ethernet(0x8847, &labels), Linktype::ETHERNET);
+        assert_eq!(packet.mpls_labels, vec![16, 17]);
+        assert_eq!(ipv4_addresses(&packet), Some((Ipv4Addr::new(10, 0, 0, 1), Ipv4Addr::new(10, 0, 0, 2))));
+        assert_eq!(udp_ports(&packet), Some((1000, 2000)));
+    }
+
+    #[test]
+    fn stops_at_non_ip_mpls_payload_and_truncated_tags() {
+        // Ethernet pseudowire control word after the label stack
+        let labels = [0x00, 0x01, 0x01, 0x40, 0x00, 0x00, 0x00, 0x00];
+        let packet = ClonablePacket::new(ethernet(0x8847, &labels), Linktype::ETHERNET);
+        assert_eq!(packet.mpls_labels, vec![16]);
+        assert!(packet.ipv4.is_none() && packet.ipv6.is_none());
+
+        let packet = ClonablePacket::new(ethernet(0x8100, &[0x00, 0x64]), Linktype::ETHERNET);
+        assert!(packet.vlan_ids.is_empty());
+        assert!(packet.ipv4.is_none());
+    }
+
+    // Inner 192.168.0.1:1000 to 192.168.0.2:2000 UDP datagram
+    fn inner_datagram() -> Vec<u8> {
+        inner_ipv4(PROTOCOL_UDP, &udp(1000, 2000, b"inner"))
+    }
+
+    fn assert_inner_datagram(packet: &ClonablePacket) {
+        assert_eq!(ipv4_addresses(packet), Some((Ipv4Addr::new(192, 168, 0, 1), Ipv4Addr::new(192, 168, 0, 2))));
+        assert_eq!(udp_ports(packet), Some((1000, 2000)));
+    }
+
+    fn outer_addresses(tunnel: &Tunnel) -> Option<(Ipv4Addr, Ipv4Addr)> {
+        let ipv4 = tunnel.ipv4.as_ref()?.parse()?;
+        Some((ipv4.get_source(), ipv4.get_destination()))
+    }
+
+    #[test]
+    fn decodes_vxlan() {
+        let mut vxlan = vec![0x08, 0x00, 0x00, 0x00, 0x00, 0x30, 0x39, 0x00]; // VNI 12345
+        vxlan.extend(ethernet(0x0800, &inner_datagram()));
+        let frame = ethernet(0x0800, &ipv4(PROTOCOL_UDP, &udp(50000, VXLAN_PORT, &vxlan)));
+        let packet = ClonablePacket::new(frame, Linktype::ETHERNET);
+
+        assert_eq!(packet.tunnels.len(), 1);
+        let tunnel = &packet.tunnels[0];
+        assert_eq!((tunnel.kind, tunnel.id), (TunnelKind::Vxlan, Some(12345)));
+        assert_eq!(outer_addresses(tunnel), Some((Ipv4Addr::new(10, 0, 0, 1), Ipv4Addr::new(10, 0, 0, 2))));
+        assert!(tunnel.ethernet.is_some());
+        assert!(packet.ethernet.is_some());
+        assert_inner_datagram(&packet);
+    }
+
+    #[test]
+    fn decodes_gre_with_key() {
+        let mut gre = vec![0x20, 0x00, 0x08, 0x00, 0x00, 0x00, 0x00, 0x2a]; // Key 42
+        gre.extend(inner_datagram());
+        let packet = ClonablePacket::new(ethernet(0x0800, &ipv4(47, &gre)), Linktype::ETHERNET);
+
+        assert_eq!(packet.tunnels.len(), 1);
+        assert_eq!((packet.tunnels[0].kind, packet.tunnels[0].id), (TunnelKind::Gre, Some(42)));
+        // GRE carrying bare IP leaves the outer Ethernet header on the packet
+        assert!(packet.tunnels[0].ethernet.is_none());
+        assert!(packet.ethernet.is_some());
+        assert_inner_datagram(&packet);
+    }
+
+    #[test]
+    fn decodes_erspan_type_ii() {
+        // GRE with a sequence number, then the ERSPAN header with session 7
+        let mut gre = vec![0x10, 0x00, 0x88, 0xbe, 0x00, 0x00, 0x00, 0x01];
+        gre.extend_from_slice(&[0x10, 0x00, 0x00, 0x07, 0x00, 0x00, 0x00, 0x00]);
+        gre.extend(ethernet(0x0800, &inner_datagram()));
+        let packet = ClonablePacket::new(ethernet(0x0800, &ipv4(47, &gre)), Linktype::ETHERNET);
+
+        assert_eq!((packet.tunnels[0].kind, packet.tunnels[0].id), (TunnelKind::Erspan, Some(7)));
+        assert_inner_datagram(&packet);
+    }
+
+    #[test]
+    fn decodes_geneve_and_ip_in_ip() {
+        // One 4 byte option, carrying IPv4 directly
+        let mut geneve = vec![0x01, 0x00, 0x08, 0x00, 0x00, 0x00, 0x05, 0x00, 0x01, 0x02, 0x03, 0x04];
+        geneve.extend(inner_datagram());
+        let frame = ethernet(0x0800, &ipv4(PROTOCOL_UDP, &udp(50000, GENEVE_PORT, &geneve)));
+        let packet = ClonablePacket::new(frame, Linktype::ETHERNET);
+        assert_eq!((packet.tunnels[0].kind, packet.tunnels[0].id), (TunnelKind::Geneve, Some(5)));
+        assert_inner_datagram(&packet);
+
+        let packet = ClonablePacket::new(ethernet(0x0800, &ipv4(4, &inner_datagram())), Linktype::ETHERNET);
+        assert_eq!((packet.tunnels[0].kind, packet.tunnels[0].id), (TunnelKind::IpInIp, None));
+        assert_inner_datagram(&packet);
+    }
+
+    #[test]
+    fn limits_tunnel_nesting() {
+        let mut datagram = inner_datagram();
+        for _ in 0..MAX_ENCAPSULATION_DEPTH + 2 {
+            datagram = ipv4(4, &datagram);
+        }
+        let packet = ClonablePacket::new(ethernet(0x0800, &datagram), Linktype::ETHERNET);
+        assert_eq!(packet.tunnels.len(), MAX_ENCAPSULATION_DEPTH);
+        assert!(packet.udp.is_none());
+    }
+
+    #[test]
+    fn decodes_raw_null_and_cooked_links() {
+        let datagram = ipv4(PROTOCOL_UDP, &udp(1000, 2000, b""));
+        for link_type in [Linktype::RAW, DLT_RAW, Linktype::IPV4] {
+            let packet = ClonablePacket::new(datagram.clone(), link_type);
+            assert_eq!(udp_ports(&packet), Some((1000, 2000)), "{:?}", link_type);
+        }
+
+        // BSD loopback: AF_INET in host byte order
+        let mut null = vec![0x02, 0x00, 0x00, 0x00];
+        null.extend_from_slice(&datagram);
+        assert_eq!(udp_ports(&ClonablePacket::new(null, Linktype::NULL)), Some((1000, 2000)));
+
+        // SLL: outgoing, ARPHRD_ETHER, 6 byte address, EtherType IPv4
+        let mut sll = vec![0x00, 0x04, 0x00, 0x01, 0x00, 0x06, 0x02, 0, 0, 0, 0, 0x01, 0x00, 0x00, 0x08, 0x00];
+        sll.extend_from_slice(&datagram);
+        assert_eq!(udp_ports(&ClonablePacket::new(sll, Linktype::LINUX_SLL)), Some((1000, 2000)));
+
+        // SLL2: EtherType, reserved, interface index, ARPHRD_ETHER, packet type, address
+        let mut sll2 = vec![0x08, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x02, 0x00, 0x01, 0x00, 0x06];
+        sll2.extend_from_slice(&[0x02, 0, 0, 0, 0, 0x01, 0x00, 0x00]);
+        sll2.extend_from_slice(&datagram);
+        assert_eq!(udp_ports(&ClonablePacket::new(sll2, Linktype::LINUX_SLL2)), Some((1000, 2000)));
+    }
+
+    // 802.11 data frame to the access point (ToDS) with an LLC/SNAP encapsulated IPv4 datagram
+    fn ieee80211_frame(subtype: u8, flags: u8) -> Vec<u8> {
+        let mut frame = vec![0x08 | (subtype << 4), flags, 0x00, 0x00];
+        frame.extend_from_slice(&[0x02, 0, 0, 0, 0, 0xaa]); // Receiver: BSSID
+        frame.extend_from_slice(&[0x02, 0, 0, 0, 0, 0x01]); // Transmitter: source
+        frame.extend_from_slice(&[0x02, 0, 0, 0, 0, 0x02]); // Destination
+        frame.extend_from_slice(&[0x00, 0x00]); // Sequence control
+        if subtype & 0x08 != 0 {
+            frame.extend_from_slice(&[0x00, 0x00]); // QoS control
+        }
+        frame.extend_from_slice(&LLC_SNAP);
+        frame.extend_from_slice(&[0x08, 0x00]);
+        frame.extend(ipv4(PROTOCOL_UDP, &udp(1000, 2000, b"")));
+        frame
+    }
+
+    #[test]
+    fn decodes_radiotap_qos_data() {
+        // Minimal radiotap header: version, pad, little endian length 8, no fields present
+        let mut frame = vec![0x00, 0x00, 0x08, 0x00, 0x00, 0x00, 0x00, 0x00];
+        frame.extend(ieee80211_frame(0x08, 0x01));
+        let packet = ClonablePacket::new(frame, Linktype::IEEE802_11_RADIOTAP);
+
+        let header = packet.ieee80211.unwrap();
+        assert_eq!(header.bssid, Some(MacAddr::new(0x02, 0, 0, 0, 0, 0xaa)));
+        assert_eq!(header.src, MacAddr::new(0x02, 0, 0, 0, 0, 0x01));
+        assert_eq!(header.dst, MacAddr::new(0x02, 0, 0, 0, 0, 0x02));
+        assert_eq!(udp_ports(&packet), Some((1000, 2000)));
+    }
+
+    #[test]
+    fn skips_protected_and_null_80211_frames() {
+        // Protected frame flag
+        let packet = ClonablePacket::new(ieee80211_frame(0x00, 0x41), Linktype::IEEE802_11);
+        assert!(packet.ieee80211.is_none() && packet.ipv4.is_none());
+        // Null function subtype
+        let packet = ClonablePacket::new(ieee80211_frame(0x04, 0x01), Linktype::IEEE802_11);
+        assert!(packet.ieee80211.is_none() && packet.ipv4.is_none());
+    }
+
+    // 2001:db8::1 to 2001:db8::2 with the given first next header
+    fn ipv6(next_header: u8, payload: &[u8]) -> Vec<u8> {
+        let mut datagram = vec![0x60, 0x00, 0x00, 0x00];
+        datagram.extend_from_slice(&(payload.len() as u16).to_be_bytes());
+        datagram.extend_from_slice(&[next_header, 64]);
+        datagram.extend_from_slice(&[0x20, 0x01, 0x0d, 0xb8, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 1]);
+        datagram.extend_from_slice(&[0x20, 0x01, 0x0d, 0xb8, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 2]);
+        datagram.extend_from_slice(payload);
+        datagram
+    }
+
+    fn extensions(packet: &ClonablePacket) -> (Vec<Ipv6Extension>, u8) {
+        let ipv6 = packet.ipv6.as_ref().unwrap();
+        (ipv6.extensions.clone(), ipv6.upper_protocol)
+    }
+
+    #[test]
+    fn walks_ipv6_extension_chain() {
+        // Hop-by-hop (8 bytes), routing (24 bytes) and destination options (8 bytes), then UDP
+        let mut chain = vec![43, 0x00, 0x01, 0x04, 0x00, 0x00, 0x00, 0x00];
+        chain.extend_from_slice(&[60, 0x02, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00]);
+        chain.extend_from_slice(&[0; 16]);
+        chain.extend_from_slice(&[PROTOCOL_UDP, 0x00, 0x01, 0x04, 0x00, 0x00, 0x00, 0x00]);
+        chain.extend(udp(1000, 2000, b""));
+        let packet = ClonablePacket::new(ipv6(0, &chain), Linktype::RAW);
+
+        let expected = vec![Ipv6Extension::HopByHop, Ipv6Extension::Routing, Ipv6Extension::DestinationOptions];
+        assert_eq!(extensions(&packet), (expected, PROTOCOL_UDP));
+        assert_eq!(udp_ports(&packet), Some((1000, 2000)));
+    }
+
+    #[test]
+    fn counts_authentication_header_in_4_byte_units() {
+        // Payload length 4 means (4 + 2) * 4 = 24 bytes
+        let mut chain = vec![PROTOCOL_UDP, 0x04, 0x00, 0x00];
+        chain.extend_from_slice(&[0; 20]);
+        chain.extend(udp(1000, 2000, b""));
+        let packet = ClonablePacket::new(ipv6(51, &chain), Linktype::RAW);
+        assert_eq!(extensions(&packet), (vec![Ipv6Extension::Authentication], PROTOCOL_UDP));
+        assert_eq!(udp_ports(&packet), Some((1000, 2000)));
+    }
+
+    #[test]
+    fn stops_at_esp_and_truncated_headers() {
+        let packet = ClonablePacket::new(ipv6(50, &[0x00, 0x00, 0x01, 0x00, 0x00, 0x00, 0x00, 0x01]), Linktype::RAW);
+        assert_eq!(extensions(&packet).0, vec![Ipv6Extension::EncapsulatingSecurityPayload]);
+        assert!(packet.udp.is_none() && packet.tcp.is_none());
+
+        // Hop-by-hop header claiming 16 bytes with only 8 present
+        let packet = ClonablePacket::new(ipv6(0, &[PROTOCOL_UDP, 0x01, 0, 0, 0, 0, 0, 0]), Linktype::RAW);
+        assert_eq!(extensions(&packet).0, vec![Ipv6Extension::HopByHop]);
+        assert!(packet.udp.is_none());
+    }
+
+    #[test]
+    fn decodes_atomic_fragments_in_place() {
+        let mut chain = vec![PROTOCOL_UDP, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x2a];
+        chain.extend(udp(1000, 2000, b""));
+        let packet = ClonablePacket::new(ipv6(44, &chain), Linktype::RAW);
+        assert!(packet.fragment.is_none());
+        assert_eq!(extensions(&packet), (vec![Ipv6Extension::Fragment], PROTOCOL_UDP));
+        assert_eq!(udp_ports(&packet), Some((1000, 2000)));
     }
 }
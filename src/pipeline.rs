@@ -1,41 +1,143 @@
 use crate::monitors::TrafficMonitor;
-use crate::packet::ClonablePacket as Packet;
-use crate::probe::ProtocolProber;
-use crate::extractor::{InformationExtractor, PacketInfo}; // Assuming the InformationExtractor is defined in this module.
+use crate::scheduler::ProbeScheduler;
+use crate::extractor::InformationExtractor; // Assuming the InformationExtractor is defined in this module.
+use crate::output::{Event, Output};
+use crate::storage::Storage;
+use futures::StreamExt;
 use std::sync::Arc;
+use tokio::sync::watch;
+use tokio::time::{interval, Duration};
+use tracing::{debug, error, info, warn};
+
+// How often capture statistics are logged for each monitor
+const STATS_REPORT_INTERVAL: Duration = Duration::from_secs(30);
+// Read errors in a row after which a capture is given up
+const MAX_CONSECUTIVE_ERRORS: u32 = 100;
 
 pub struct TrafficPipeline {
     pub interface_monitor: Arc<dyn TrafficMonitor + Send + Sync>,
     pub info_extractor: Arc<InformationExtractor>,
+    pub scheduler: Option<Arc<ProbeScheduler>>, // Decides when newly seen endpoints get probed, None with --no-probe
+    pub output: Arc<Output>, // Where decoded layer 7 messages are reported
+    pub storage: Option<Arc<Storage>>, // Persists the responder PacketInfo of new flows when storage_path is set
+    pub stop: watch::Receiver<bool>, // Set to true to end the capture, e.g. when its interface leaves the config
 }
 
 impl TrafficPipeline {
-    pub async fn process_pipeline(&self) {
+    // Runs the capture to its end. Returns false if it could not be started or was
+    // given up after repeated read errors.
+    pub async fn process_pipeline(&self) -> bool {
         let info_extractor = Arc::clone(&self.info_extractor);  // Cloning the InformationExtractor reference
 
         let ethernet_handle = tokio::spawn({
             let interface_monitor = Arc::clone(&self.interface_monitor);
+            let scheduler = self.scheduler.clone();
+            let output = Arc::clone(&self.output);
+            let storage = self.storage.clone();
+            let mut stop = self.stop.clone();
 
             async move {
+                let source = interface_monitor.name().to_string();
+                let mut packets = match interface_monitor.capture_traffic().await {
+                    Ok(packets) => packets,
+                    Err(e) => {
+                        error!(%source, error = %e, "failed to start capture");
+                        return false;
+                    }
+                };
+                info!(%source, "capture started");
+                let mut stats_report = interval(STATS_REPORT_INTERVAL);
+                let mut consecutive_errors = 0;
+
                 loop {
-                    let packet: Packet = interface_monitor.capture_traffic().await;
-
-                    // Extract information from the packet
-                    info_extractor.extract_and_store(&packet);
-                    let prober = ProtocolProber::new(info_extractor.db.clone());
-    
-                    // Run active probing
-                    prober.active_probe().await;
-                    println!("proobing over");
-                    // Access valid responses
-                    for response in prober.valid_responses.iter() {
-                        println!("Valid protocol: {:?}", response.response_metadata);
+                    tokio::select! {
+                        packet = packets.next() => {
+                            let packet = match packet {
+                                Some(Ok(packet)) => {
+                                    consecutive_errors = 0;
+                                    packet
+                                }
+                                Some(Err(e)) => {
+                                    // Read errors are often transient; only a run of them ends the capture
+                                    consecutive_errors += 1;
+                                    if consecutive_errors >= MAX_CONSECUTIVE_ERRORS {
+                                        error!(%source, error = %e, "capture failing repeatedly, stopping");
+                                        return false;
+                                    }
+                                    warn!(%source, error = %e, "capture error");
+                                    continue;
+                                }
+                                None => {
+                                    info!(%source, "capture finished");
+                                    break;
+                                }
+                            };
+
+                            // Fragments are held until their datagram is complete
+                            let Some(packet) = info_extractor.fragments.reassemble(packet) else {
+                                continue;
+                            };
+
+                            // Extract information from the packet
+                            let extraction = info_extractor.extract_and_store(&packet);
+                            // New flows are stored addressed to their server side, so a restart
+                            // probes the same endpoint the scheduler is handed below
+                            if let (Some(storage), Some(responder)) = (&storage, &extraction.responder) {
+                                storage.record_packet(responder);
+                            }
+                            for message in &extraction.layer7 {
+                                debug!(%source, ?message, "decoded layer 7 message");
+                                output.emit(&Event::Layer7 { packet: &extraction.info, message });
+                            }
+                            if extraction.info.icmp.as_ref().is_some_and(|icmp| icmp.quoted.is_some() || icmp.ndp.is_some()) {
+                                output.emit(&Event::Icmp { packet: &extraction.info });
+                            }
+
+                            // Hand the server side of new flows to the probe scheduler, which ignores
+                            // endpoints it already knows; client ports are ephemeral and not worth probing
+                            if let (Some(scheduler), Some(responder)) = (&scheduler, &extraction.responder) {
+                                scheduler.observe(responder);
+                            }
+                        }
+                        Ok(()) = stop.changed() => {
+                            if *stop.borrow() {
+                                info!(%source, "capture stopped");
+                                break;
+                            }
+                        }
+                        _ = stats_report.tick() => {
+                            if let Some(stats) = interface_monitor.stats() {
+                                info!(
+                                    %source,
+                                    received = stats.received,
+                                    dropped = stats.dropped,
+                                    if_dropped = stats.if_dropped,
+                                    "capture stats"
+                                );
+                            }
+                            info!(
+                                active_flows = info_extractor.flows.len(),
+                                tcp_streams = info_extractor.streams.len(),
+                                "flow table"
+                            );
+                            let fragments = info_extractor.fragments.stats();
+                            info!(
+                                pending = info_extractor.fragments.pending(),
+                                reassembled = fragments.reassembled,
+                                timed_out = fragments.timed_out,
+                                evicted = fragments.evicted,
+                                overlapping = fragments.overlapping,
+                                conflicting = fragments.conflicting,
+                                invalid = fragments.invalid,
+                                "fragment reassembly"
+                            );
+                        }
                     }
                 }
+                true
             }
         });
 
-        let _ = tokio::join!(ethernet_handle);
+        ethernet_handle.await.unwrap_or(false)
     }
 }
-
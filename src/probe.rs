@@ -1,17 +1,19 @@
 use std::sync::Arc;
-use dashmap::DashSet;
+use dashmap::DashMap;
+use serde::{Deserialize, Serialize};
 use tokio::time::{timeout, Duration};
+use tracing::debug;
 
 use crate::extractor::PacketInfo;
 
 use tokio::net::UdpSocket;
-use std::net::SocketAddr;
+use std::net::{IpAddr, SocketAddr};
 use coap_lite::{Packet, CoapRequest};
 
 use reqwest::Client; // For HTTP requests
 use rumqttc::{MqttOptions, AsyncClient, QoS}; // For MQTT
 
-#[derive(Debug, Clone, PartialEq, Eq, Hash)]
+#[derive(Debug, Clone, PartialEq, Eq, Hash, Serialize, Deserialize)]
 pub struct ValidResponse {
     pub protocol: String, // e.g., "HTTP", "MQTT", "COAP"
     pub version: Option<String>, // Version of the protocol, if applicable
@@ -19,43 +21,71 @@ pub struct ValidResponse {
     pub packet_info: PacketInfo, // The original packet information
 }
 
+// Identifies a probed service: destination IP, destination port and protocol
+pub type ProbeKey = (IpAddr, u16, String);
+
 pub struct ProtocolProber {
-    db: Arc<DashSet<PacketInfo>>, // Shared DB with unique PacketInfo
-    pub valid_responses: Arc<DashSet<ValidResponse>>, // Store the valid responses here
+    pub valid_responses: Arc<DashMap<ProbeKey, ValidResponse>>, // Latest valid response per service
 }
 
 impl ProtocolProber {
-    pub fn new(db: Arc<DashSet<PacketInfo>>) -> Self {
+    pub fn new() -> Self {
         Self {
-            db,
-            valid_responses: Arc::new(DashSet::new()),
+            valid_responses: Arc::new(DashMap::new()),
         }
     }
 
-    // The main function that iterates over PacketInfo and probes different protocols
-    pub async fn active_probe(&self) {
-        let tasks: Vec<_> = self.db.iter().map(|packet| {
-            let probe_http = self.probe_http(packet.clone());
-            let probe_coap = self.probe_coap(packet.clone());
-            let probe_mqtt = self.probe_mqtt(packet.clone());
+    // Probes a single endpoint for every supported protocol concurrently.
+    // Returns the responses it produced, which are also recorded in valid_responses.
+    pub async fn probe_target(&self, packet: PacketInfo) -> Vec<ValidResponse> {
+        let (http, coap, mqtt) = tokio::join!(
+            self.probe_http(packet.clone()),
+            self.probe_coap(packet.clone()),
+            self.probe_mqtt(packet)
+        );
 
-            async move {
-                tokio::join!(probe_http, probe_coap, probe_mqtt);
-            }
-        }).collect();
+        let responses: Vec<ValidResponse> = [http, coap, mqtt].into_iter().flatten().collect();
+        for response in &responses {
+            self.record(response.clone());
+        }
+        responses
+    }
+
+    // Reinstates responses recorded by an earlier run
+    pub fn restore(&self, responses: Vec<ValidResponse>) {
+        for response in responses {
+            self.record(response);
+        }
+    }
 
-        // Run all probes concurrently
-        for task in tasks {
-            task.await; // Await each task to ensure they complete
+    // Stores a response, replacing any earlier one for the same service
+    fn record(&self, response: ValidResponse) {
+        if let (Some(ip), Some(port)) = (response.packet_info.dst_ip, response.packet_info.dst_port) {
+            let key = (ip, port, response.protocol.clone());
+            self.valid_responses.insert(key, response);
         }
     }
 
+    // Returns the recorded responses matching every filter that is set
+    pub fn find_responses(&self, ip: Option<IpAddr>, port: Option<u16>, protocol: Option<&str>) -> Vec<ValidResponse> {
+        self.valid_responses
+            .iter()
+            .filter(|entry| {
+                let (entry_ip, entry_port, entry_protocol) = entry.key();
+                ip.is_none_or(|ip| ip == *entry_ip)
+                    && port.is_none_or(|port| port == *entry_port)
+                    && protocol.is_none_or(|protocol| protocol.eq_ignore_ascii_case(entry_protocol))
+            })
+            .map(|entry| entry.value().clone())
+            .collect()
+    }
+
     // Example HTTP probe function (using reqwest)
     async fn probe_http(&self, packet: PacketInfo) -> Option<ValidResponse> {
         if let Some(ip) = &packet.dst_ip {
             if let Some(port) = packet.dst_port {
                 let client = Client::new();
-                let url = format!("http://{}:{}/", ip, port);
+                let url = format!("http://{}/", SocketAddr::new(*ip, port)); // Brackets IPv6 hosts
                 
                 // Timeout and handle request asynchronously
                 if let Ok(response) = timeout(Duration::from_secs(2), client.get(&url).send()).await {
@@ -85,7 +115,7 @@ impl ProtocolProber {
                 let payload: Vec<u8> = match coap_request.message.to_bytes() {
                     Ok(bytes) => bytes,
                     Err(e) => {
-                        println!("Error converting CoAP request to bytes: {:?}", e);
+                        debug!(error = ?e, "failed to encode CoAP request");
                         return None;
                     }
                 };
@@ -94,14 +124,14 @@ impl ProtocolProber {
                 let socket: UdpSocket = match UdpSocket::bind("0.0.0.0:0").await {
                     Ok(s) => s,
                     Err(e) => {
-                        println!("Error binding UDP socket: {:?}", e);
+                        debug!(error = %e, "failed to bind UDP socket for CoAP probe");
                         return None;
                     }
                 };
     
                 // Attempt to send the CoAP request to the target IP and port
-                if let Err(e) = socket.send_to(&payload, (ip.as_str(), port)).await {
-                    println!("Error sending CoAP request: {:?}", e);
+                if let Err(e) = socket.send_to(&payload, (*ip, port)).await {
+                    debug!(%ip, port, error = %e, "failed to send CoAP request");
                     return None;
                 }
     
@@ -124,17 +154,17 @@ impl ProtocolProber {
                                 });
                             }
                             Err(e) => {
-                                println!("Error parsing CoAP response packet: {:?}", e);
+                                debug!(%ip, port, error = ?e, "unparseable CoAP response");
                             }
                         }
                     }
                     // Timeout case
                     Ok(Err(e)) => {
-                        println!("Error receiving CoAP response: {:?}", e);
+                        debug!(%ip, port, error = %e, "failed to receive CoAP response");
                     }
                     // Timeout exceeded
                     Err(_) => {
-                        println!("Timed out waiting for CoAP response");
+                        debug!(%ip, port, "timed out waiting for CoAP response");
                     }
                 }
             }
@@ -147,7 +177,7 @@ impl ProtocolProber {
     async fn probe_mqtt(&self, packet: PacketInfo) -> Option<ValidResponse> {
         if let Some(ip) = &packet.dst_ip {
             if let Some(port) = packet.dst_port {
-                let mut mqttoptions = MqttOptions::new("test-client", ip.as_str(), port);
+                let mut mqttoptions = MqttOptions::new("test-client", ip.to_string(), port);
                 mqttoptions.set_keep_alive(Duration::from_secs(5));
                 
                 let (client,eventloop) = AsyncClient::new(mqttoptions, 10);
@@ -166,3 +196,83 @@ impl ProtocolProber {
         None
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::net::Ipv4Addr;
+
+    fn response(ip: [u8; 4], port: u16, protocol: &str) -> ValidResponse {
+        ValidResponse {
+            protocol: protocol.to_string(),
+            version: None,
+            response_metadata: None,
+            packet_info: PacketInfo {
+                src_mac: None,
+                dst_mac: None,
+                bssid: None,
+                src_ip: None,
+                dst_ip: Some(IpAddr::V4(Ipv4Addr::from(ip))),
+                protocol: Some(6),
+                src_port: None,
+                dst_port: Some(port),
+                vlan_ids: Vec::new(),
+                mpls_labels: Vec::new(),
+                ipv6_extensions: Vec::new(),
+                tunnels: Vec::new(),
+                icmp: None,
+            },
+        }
+    }
+
+    fn sorted(responses: Vec<ValidResponse>) -> Vec<ProbeKey> {
+        let mut keys: Vec<_> = responses
+            .into_iter()
+            .map(|r| (r.packet_info.dst_ip.unwrap(), r.packet_info.dst_port.unwrap(), r.protocol))
+            .collect();
+        keys.sort();
+        keys
+    }
+
+    #[test]
+    fn finds_responses_by_ip_port_and_protocol() {
+        let prober = ProtocolProber::new();
+        prober.restore(vec![
+            response([10, 0, 0, 1], 80, "HTTP"),
+            response([10, 0, 0, 1], 1883, "MQTT"),
+            response([10, 0, 0, 2], 80, "HTTP"),
+            response([10, 0, 0, 2], 5683, "COAP"),
+        ]);
+        let host = IpAddr::V4(Ipv4Addr::new(10, 0, 0, 1));
+
+        assert_eq!(prober.find_responses(None, None, None).len(), 4);
+        assert_eq!(
+            sorted(prober.find_responses(Some(host), None, None)),
+            [(host, 80, "HTTP".to_string()), (host, 1883, "MQTT".to_string())]
+        );
+        assert_eq!(prober.find_responses(None, Some(80), None).len(), 2);
+        // Protocols match regardless of case
+        assert_eq!(prober.find_responses(None, None, Some("coap")).len(), 1);
+        assert_eq!(prober.find_responses(Some(host), Some(80), Some("http")).len(), 1);
+        assert!(prober.find_responses(Some(host), Some(5683), None).is_empty());
+    }
+
+    #[test]
+    fn restores_stored_responses() {
+        let prober = ProtocolProber::new();
+        let mut unaddressed = response([10, 0, 0, 3], 80, "HTTP");
+        unaddressed.packet_info.dst_port = None;
+        let mut latest = response([10, 0, 0, 1], 80, "HTTP");
+        latest.version = Some("HTTP/1.1".to_string());
+        let responses = [response([10, 0, 0, 1], 80, "HTTP"), latest.clone(), unaddressed, response([10, 0, 0, 1], 80, "MQTT")];
+
+        // Round trip through the stored NDJSON form
+        let lines: Vec<String> = responses.iter().map(|r| serde_json::to_string(r).unwrap()).collect();
+        prober.restore(lines.iter().map(|line| serde_json::from_str(line).unwrap()).collect());
+
+        // A response without a destination port cannot be keyed and is dropped; the
+        // later of two responses for the same service wins
+        assert_eq!(prober.valid_responses.len(), 2);
+        assert_eq!(prober.find_responses(None, None, Some("HTTP")), vec![latest]);
+    }
+}
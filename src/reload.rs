@@ -0,0 +1,76 @@
+use std::path::PathBuf;
+use std::time::SystemTime;
+use tokio::sync::mpsc;
+use tokio::time::{interval, Duration, MissedTickBehavior};
+use tracing::debug;
+
+// How often the config file's modification time is checked
+const POLL_INTERVAL: Duration = Duration::from_secs(2);
+
+// Signals a reload whenever the config file changes on disk or the process receives
+// SIGHUP. Polling the modification time also catches editors that save by renaming
+// a new file over the old one. The SIGHUP handler is installed even without a config
+// file, so the signal does not terminate the process.
+pub fn watch(path: Option<PathBuf>) -> mpsc::Receiver<()> {
+    let (tx, rx) = mpsc::channel(1);
+
+    tokio::spawn(async move {
+        let modified = |path: &Option<PathBuf>| -> Option<SystemTime> {
+            std::fs::metadata(path.as_ref()?).ok()?.modified().ok()
+        };
+        let mut last_modified = modified(&path);
+        let mut poll = interval(POLL_INTERVAL);
+        poll.set_missed_tick_behavior(MissedTickBehavior::Delay);
+        let mut hangup = hangup_signal();
+
+        loop {
+            tokio::select! {
+                _ = poll.tick(), if path.is_some() => {
+                    let current = modified(&path);
+                    // A missing file (mid-save) is not a change; wait for it to reappear
+                    if current.is_none() || current == last_modified {
+                        continue;
+                    }
+                    last_modified = current;
+                    debug!(path = ?path, "config file changed");
+                }
+                Some(()) = next_hangup(&mut hangup) => {
+                    debug!("received SIGHUP");
+                }
+            }
+            // A full channel already holds a pending reload
+            if tx.try_send(()).is_err() && tx.is_closed() {
+                break;
+            }
+        }
+    });
+
+    rx
+}
+
+#[cfg(unix)]
+type Hangup = Option<tokio::signal::unix::Signal>;
+#[cfg(not(unix))]
+type Hangup = ();
+
+#[cfg(unix)]
+fn hangup_signal() -> Hangup {
+    use tokio::signal::unix::{signal, SignalKind};
+    signal(SignalKind::hangup()).ok()
+}
+
+#[cfg(not(unix))]
+fn hangup_signal() -> Hangup {}
+
+#[cfg(unix)]
+async fn next_hangup(hangup: &mut Hangup) -> Option<()> {
+    match hangup {
+        Some(signal) => signal.recv().await,
+        None => std::future::pending().await,
+    }
+}
+
+#[cfg(not(unix))]
+async fn next_hangup(_: &mut Hangup) -> Option<()> {
+    std::future::pending().await
+}
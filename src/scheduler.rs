@@ -0,0 +1,328 @@
+use std::collections::VecDeque;
+use std::net::IpAddr;
+use std::sync::{Arc, Mutex, RwLock};
+use dashmap::DashMap;
+use tokio::sync::{Notify, Semaphore};
+use tokio::time::{interval, sleep, Duration, Instant, Interval, MissedTickBehavior};
+use tracing::{debug, info};
+
+use crate::config::ProbeConfig;
+use crate::extractor::PacketInfo;
+use crate::output::{Event, Output};
+use crate::probe::ProtocolProber;
+use crate::storage::Storage;
+
+const DEFAULT_MAX_PROBES_PER_SECOND: f64 = 5.0;
+const DEFAULT_PER_HOST_INTERVAL_SECS: u64 = 10;
+const DEFAULT_REPROBE_INTERVAL_SECS: u64 = 3600;
+const DEFAULT_MAX_CONCURRENT: usize = 16;
+
+// Endpoints that never answered are re-probed this many times less often
+const NO_RESPONSE_BACKOFF: u32 = 4;
+// Upper bound on endpoints waiting to be probed; new ones are dropped until it drains
+const MAX_QUEUE_LEN: usize = 10_000;
+// How long the scheduler waits when every queued endpoint is rate limited
+const IDLE_WAIT: Duration = Duration::from_millis(200);
+// Upper bound on tracked endpoints; new ones are ignored until idle ones expire
+const MAX_ENDPOINTS: usize = 100_000;
+// Endpoints no new flow has been seen to for this long are forgotten
+const ENDPOINT_IDLE_TIMEOUT: Duration = Duration::from_secs(24 * 3600);
+
+// An endpoint is a destination IP and port worth probing
+type Endpoint = (IpAddr, u16);
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum ProbeOutcome {
+    Responded, // At least one protocol answered
+    NoResponse,
+}
+
+// Limits from ProbeConfig with defaults filled in
+#[derive(Debug, Clone, Copy)]
+struct ProbeLimits {
+    max_probes_per_second: f64,
+    per_host_interval: Duration,
+    reprobe_interval: Duration,
+    max_concurrent: usize,
+}
+
+impl ProbeLimits {
+    fn new(config: Option<&ProbeConfig>) -> Self {
+        let max_probes_per_second = config
+            .and_then(|c| c.max_probes_per_second)
+            .unwrap_or(DEFAULT_MAX_PROBES_PER_SECOND);
+        let per_host_interval = config
+            .and_then(|c| c.per_host_interval_secs)
+            .unwrap_or(DEFAULT_PER_HOST_INTERVAL_SECS);
+        let reprobe_interval = config
+            .and_then(|c| c.reprobe_interval_secs)
+            .unwrap_or(DEFAULT_REPROBE_INTERVAL_SECS);
+        let max_concurrent = config
+            .and_then(|c| c.max_concurrent)
+            .unwrap_or(DEFAULT_MAX_CONCURRENT);
+
+        ProbeLimits {
+            max_probes_per_second,
+            per_host_interval: Duration::from_secs(per_host_interval),
+            reprobe_interval: Duration::from_secs(reprobe_interval),
+            max_concurrent: max_concurrent.max(1),
+        }
+    }
+
+    fn rate(&self) -> Interval {
+        // A huge rate would round to a zero period, which interval() rejects
+        let period = Duration::from_secs_f64(1.0 / self.max_probes_per_second.max(0.001));
+        let mut rate = interval(period.max(Duration::from_nanos(1)));
+        rate.set_missed_tick_behavior(MissedTickBehavior::Delay);
+        rate
+    }
+
+    fn sweep(&self) -> Interval {
+        interval(self.reprobe_interval.clamp(Duration::from_secs(1), Duration::from_secs(60)))
+    }
+}
+
+struct EndpointState {
+    packet_info: PacketInfo,
+    last_seen: Instant, // Last time a new flow to the endpoint was observed
+    last_probe: Option<Instant>,
+    last_outcome: Option<ProbeOutcome>,
+    queued: bool,
+}
+
+pub struct ProbeScheduler {
+    limits: RwLock<ProbeLimits>,
+    reconfigured: Notify, // Wakes the run loop to pick up new limits
+    endpoints: DashMap<Endpoint, EndpointState>,
+    host_last_probe: DashMap<IpAddr, Instant>,
+    queue: Mutex<VecDeque<Endpoint>>,
+    notify: Notify,
+}
+
+impl ProbeScheduler {
+    pub fn new(config: Option<&ProbeConfig>) -> Self {
+        ProbeScheduler {
+            limits: RwLock::new(ProbeLimits::new(config)),
+            reconfigured: Notify::new(),
+            endpoints: DashMap::new(),
+            host_last_probe: DashMap::new(),
+            queue: Mutex::new(VecDeque::new()),
+            notify: Notify::new(),
+        }
+    }
+
+    // Applies new limits to the running scheduler without forgetting known endpoints
+    pub fn reconfigure(&self, config: Option<&ProbeConfig>) {
+        *self.limits.write().unwrap() = ProbeLimits::new(config);
+        self.reconfigured.notify_one();
+    }
+
+    fn limits(&self) -> ProbeLimits {
+        *self.limits.read().unwrap()
+    }
+
+    // Queues the packet's destination for probing if it has not been seen before
+    pub fn observe(&self, packet: &PacketInfo) {
+        let endpoint = match (packet.dst_ip, packet.dst_port) {
+            (Some(ip), Some(port)) => (ip, port),
+            _ => return,
+        };
+        let now = Instant::now();
+        if let Some(mut state) = self.endpoints.get_mut(&endpoint) {
+            state.last_seen = now;
+            return;
+        }
+
+        let mut queue = self.queue.lock().unwrap();
+        // Another pipeline may have queued the same endpoint since the check above
+        if queue.len() >= MAX_QUEUE_LEN
+            || self.endpoints.len() >= MAX_ENDPOINTS
+            || self.endpoints.contains_key(&endpoint)
+        {
+            return;
+        }
+        self.endpoints.insert(endpoint, EndpointState {
+            packet_info: packet.clone(),
+            last_seen: now,
+            last_probe: None,
+            last_outcome: None,
+            queued: true,
+        });
+        queue.push_back(endpoint);
+        self.notify.notify_one();
+    }
+
+    // Drives probing until the process exits: pops queued endpoints at the
+    // configured global rate and periodically re-queues stale ones
+    pub async fn run(self: Arc<Self>, prober: Arc<ProtocolProber>, output: Arc<Output>, storage: Option<Arc<Storage>>) {
+        let mut limits = self.limits();
+        let in_flight = Arc::new(Semaphore::new(limits.max_concurrent));
+        let mut rate = limits.rate();
+        let mut sweep = limits.sweep();
+
+        loop {
+            tokio::select! {
+                _ = self.reconfigured.notified() => {
+                    let previous = limits;
+                    limits = self.limits();
+                    if limits.max_concurrent > previous.max_concurrent {
+                        in_flight.add_permits(limits.max_concurrent - previous.max_concurrent);
+                    } else if limits.max_concurrent < previous.max_concurrent {
+                        // Permits held by running probes are retired as those probes finish
+                        let surplus = (previous.max_concurrent - limits.max_concurrent) as u32;
+                        let in_flight = Arc::clone(&in_flight);
+                        tokio::spawn(async move {
+                            if let Ok(permits) = in_flight.acquire_many_owned(surplus).await {
+                                permits.forget();
+                            }
+                        });
+                    }
+                    rate = limits.rate();
+                    sweep = limits.sweep();
+                    info!(?limits, "probe limits updated");
+                }
+                _ = sweep.tick() => self.requeue_stale(),
+                _ = rate.tick() => {
+                    let (endpoint, packet_info) = match self.next_ready() {
+                        Some(next) => next,
+                        None => {
+                            // Nothing eligible: wait for a new endpoint or for a host to cool down
+                            tokio::select! {
+                                _ = self.notify.notified() => {}
+                                _ = sleep(IDLE_WAIT) => {}
+                            }
+                            continue;
+                        }
+                    };
+
+                    let permit = Arc::clone(&in_flight).acquire_owned().await.unwrap();
+                    let scheduler = Arc::clone(&self);
+                    let prober = Arc::clone(&prober);
+                    let output = Arc::clone(&output);
+                    let storage = storage.clone();
+                    tokio::spawn(async move {
+                        let (ip, port) = endpoint;
+                        debug!(%ip, port, "probing endpoint");
+                        let responses = prober.probe_target(packet_info).await;
+                        for service in &responses {
+                            info!(%ip, port, protocol = %service.protocol, "service responded");
+                            output.emit(&Event::Service { service });
+                            if let Some(storage) = &storage {
+                                storage.record_service(service);
+                            }
+                        }
+                        let outcome = if responses.is_empty() {
+                            ProbeOutcome::NoResponse
+                        } else {
+                            ProbeOutcome::Responded
+                        };
+                        if let Some(mut state) = scheduler.endpoints.get_mut(&endpoint) {
+                            state.last_outcome = Some(outcome);
+                        }
+                        drop(permit);
+                    });
+                }
+            }
+        }
+    }
+
+    // Pops the first queued endpoint whose host is not within its per-host interval
+    fn next_ready(&self) -> Option<(Endpoint, PacketInfo)> {
+        let now = Instant::now();
+        let per_host_interval = self.limits().per_host_interval;
+        let mut queue = self.queue.lock().unwrap();
+        let position = queue.iter().position(|(ip, _)| {
+            self.host_last_probe
+                .get(ip)
+                .is_none_or(|last| now.duration_since(*last) >= per_host_interval)
+        })?;
+        let endpoint = queue.remove(position)?;
+        drop(queue);
+
+        self.host_last_probe.insert(endpoint.0, now);
+        let mut state = self.endpoints.get_mut(&endpoint)?;
+        state.queued = false;
+        state.last_probe = Some(now);
+        Some((endpoint, state.packet_info.clone()))
+    }
+
+    // Queues endpoints whose last probe is older than their re-probe interval
+    fn requeue_stale(&self) {
+        let now = Instant::now();
+        let limits = self.limits();
+        let reprobe_interval = limits.reprobe_interval;
+        let mut queue = self.queue.lock().unwrap();
+
+        // Forget endpoints that have gone quiet, and hosts whose per-host interval has passed
+        self.endpoints.retain(|_, state| {
+            state.queued || now.duration_since(state.last_seen) < ENDPOINT_IDLE_TIMEOUT
+        });
+        self.host_last_probe.retain(|_, last| now.duration_since(*last) < limits.per_host_interval);
+
+        for mut entry in self.endpoints.iter_mut() {
+            if queue.len() >= MAX_QUEUE_LEN {
+                break;
+            }
+            let state = entry.value_mut();
+            let interval = match state.last_outcome {
+                Some(ProbeOutcome::NoResponse) => reprobe_interval * NO_RESPONSE_BACKOFF,
+                _ => reprobe_interval,
+            };
+            let stale = state
+                .last_probe
+                .is_some_and(|last| now.duration_since(last) >= interval);
+            if stale && !state.queued {
+                state.queued = true;
+                queue.push_back(*entry.key());
+            }
+        }
+        if !queue.is_empty() {
+            self.notify.notify_one();
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::net::Ipv4Addr;
+
+    fn packet(dst_port: u16) -> PacketInfo {
+        PacketInfo {
+            src_mac: None,
+            dst_mac: None,
+            bssid: None,
+            src_ip: Some(IpAddr::V4(Ipv4Addr::new(10, 0, 0, 2))),
+            dst_ip: Some(IpAddr::V4(Ipv4Addr::new(10, 0, 0, 1))),
+            protocol: Some(6),
+            src_port: Some(49152),
+            dst_port: Some(dst_port),
+            vlan_ids: Vec::new(),
+            mpls_labels: Vec::new(),
+            ipv6_extensions: Vec::new(),
+            tunnels: Vec::new(),
+            icmp: None,
+        }
+    }
+
+    #[test]
+    fn queues_each_endpoint_once() {
+        let scheduler = ProbeScheduler::new(None);
+        scheduler.observe(&packet(80));
+        scheduler.observe(&packet(80));
+        scheduler.observe(&packet(443));
+        assert_eq!(scheduler.queue.lock().unwrap().len(), 2);
+        assert_eq!(scheduler.endpoints.len(), 2);
+    }
+
+    #[tokio::test]
+    async fn huge_rate_does_not_panic() {
+        let config = ProbeConfig {
+            max_probes_per_second: Some(1e300),
+            per_host_interval_secs: None,
+            reprobe_interval_secs: None,
+            max_concurrent: None,
+        };
+        ProbeLimits::new(Some(&config)).rate().tick().await;
+    }
+}
@@ -0,0 +1,274 @@
+use std::collections::HashSet;
+use std::fs::{self, File, OpenOptions};
+use std::io::{self, BufRead, BufReader, LineWriter, Write};
+use std::path::{Path, PathBuf};
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::sync::Mutex;
+use std::time::{SystemTime, UNIX_EPOCH};
+use serde::{de::DeserializeOwned, Serialize};
+use tokio::runtime::Handle;
+use tracing::{error, warn};
+
+use crate::config::SettingsConfig;
+use crate::extractor::PacketInfo;
+use crate::probe::ValidResponse;
+
+const DEFAULT_MAX_FILE_BYTES: u64 = 64 * 1024 * 1024;
+const DEFAULT_MAX_FILES: usize = 8;
+
+const PACKETS_PREFIX: &str = "packets";
+const SERVICES_PREFIX: &str = "services";
+const EXTENSION: &str = ".ndjson";
+
+// Durable record of discoveries under SettingsConfig::storage_path. PacketInfo of
+// every new flow and every probe result are appended to rotating NDJSON files.
+pub struct Storage {
+    packets: RotatingLog,
+    services: RotatingLog,
+}
+
+impl Storage {
+    // Creates the storage directory if needed; files are only created on first write
+    pub fn open(dir: &Path, settings: &SettingsConfig) -> io::Result<Self> {
+        fs::create_dir_all(dir)?;
+        let max_file_bytes = settings.storage_max_file_bytes.unwrap_or(DEFAULT_MAX_FILE_BYTES).max(1);
+        let max_files = settings.storage_max_files.unwrap_or(DEFAULT_MAX_FILES).max(1);
+        Ok(Storage {
+            packets: RotatingLog::new(dir, PACKETS_PREFIX, max_file_bytes, max_files)?,
+            services: RotatingLog::new(dir, SERVICES_PREFIX, max_file_bytes, max_files)?,
+        })
+    }
+
+    pub fn record_packet(&self, info: &PacketInfo) {
+        self.packets.append(info);
+    }
+
+    pub fn record_service(&self, response: &ValidResponse) {
+        self.services.append(response);
+    }
+
+    // Reads back everything written by earlier runs, dropping duplicate packet records
+    pub fn load(&self) -> io::Result<(Vec<PacketInfo>, Vec<ValidResponse>)> {
+        let mut seen = HashSet::new();
+        let packets = self
+            .packets
+            .load::<PacketInfo>()?
+            .into_iter()
+            .filter(|info| seen.insert(info.clone()))
+            .collect();
+        Ok((packets, self.services.load()?))
+    }
+
+    // Replaces the files of earlier runs with a single file per kind holding only
+    // the given records, so restarts do not grow the store
+    pub fn compact(&self, packets: &[PacketInfo], services: &[ValidResponse]) -> io::Result<()> {
+        self.packets.rewrite(packets)?;
+        self.services.rewrite(services)
+    }
+}
+
+struct CurrentFile {
+    path: PathBuf,
+    writer: LineWriter<File>,
+    written: u64,
+}
+
+// The <prefix>-<unix millis>.ndjson files of one record kind in the storage directory
+#[derive(Clone)]
+struct LogFiles {
+    dir: PathBuf,
+    prefix: &'static str,
+}
+
+impl LogFiles {
+    // Creation time encoded in the name of one of these files
+    fn millis(&self, path: &Path) -> Option<u64> {
+        path.file_name()?
+            .to_str()?
+            .strip_prefix(self.prefix)?
+            .strip_prefix('-')?
+            .strip_suffix(EXTENSION)?
+            .parse()
+            .ok()
+    }
+
+    // Oldest first
+    fn list(&self) -> io::Result<Vec<PathBuf>> {
+        let mut files: Vec<PathBuf> = fs::read_dir(&self.dir)?
+            .filter_map(|entry| entry.ok().map(|entry| entry.path()))
+            .filter(|path| self.millis(path).is_some())
+            .collect();
+        files.sort();
+        Ok(files)
+    }
+
+    // Removes the oldest files beyond `keep`
+    fn prune(&self, keep: usize) -> io::Result<()> {
+        let files = self.list()?;
+        for path in files.iter().take(files.len().saturating_sub(keep)) {
+            remove(path)?;
+        }
+        Ok(())
+    }
+}
+
+// Appends JSON lines to <prefix>-<unix millis>.ndjson, starting a new file once
+// max_file_bytes is reached. Beyond max_files the oldest files are removed; that
+// runs on a blocking thread so only the append itself is on the packet path.
+struct RotatingLog {
+    files: LogFiles,
+    max_file_bytes: u64,
+    max_files: usize,
+    current: Mutex<Option<CurrentFile>>,
+    newest: AtomicU64, // Name of the newest file, in unix millis; new files are named after it
+}
+
+impl RotatingLog {
+    fn new(dir: &Path, prefix: &'static str, max_file_bytes: u64, max_files: usize) -> io::Result<Self> {
+        let files = LogFiles { dir: dir.to_path_buf(), prefix };
+        let newest = files.list()?.last().and_then(|path| files.millis(path)).unwrap_or(0);
+        Ok(RotatingLog {
+            files,
+            max_file_bytes,
+            max_files,
+            current: Mutex::new(None),
+            newest: AtomicU64::new(newest),
+        })
+    }
+
+    fn append<T: Serialize>(&self, record: &T) {
+        let line = match serde_json::to_string(record) {
+            Ok(line) => line,
+            Err(e) => {
+                error!(error = %e, "failed to serialize stored record");
+                return;
+            }
+        };
+
+        let mut current = self.current.lock().unwrap();
+        if current.as_ref().is_some_and(|file| file.written >= self.max_file_bytes) {
+            *current = None;
+        }
+        if current.is_none() {
+            match self.create() {
+                Ok(file) => *current = Some(file),
+                Err(e) => {
+                    error!(dir = %self.files.dir.display(), error = %e, "failed to create storage file");
+                    return;
+                }
+            }
+        }
+        if let Some(file) = current.as_mut() {
+            match writeln!(file.writer, "{}", line) {
+                Ok(()) => file.written += line.len() as u64 + 1,
+                Err(e) => error!(path = %file.path.display(), error = %e, "failed to write stored record"),
+            }
+        }
+    }
+
+    // Starts a new file and prunes the older ones beyond max_files
+    fn create(&self) -> io::Result<CurrentFile> {
+        let now = SystemTime::now().duration_since(UNIX_EPOCH).unwrap_or_default().as_millis() as u64;
+        // Never named before an existing file, even when the clock steps back
+        let mut millis = now.max(self.newest.load(Ordering::Relaxed) + 1);
+        loop {
+            // Zero padded so that name order is creation order
+            let path = self.files.dir.join(format!("{}-{:016}{}", self.files.prefix, millis, EXTENSION));
+            match OpenOptions::new().write(true).create_new(true).open(&path) {
+                Ok(file) => {
+                    self.newest.fetch_max(millis, Ordering::Relaxed);
+                    self.prune_in_background();
+                    return Ok(CurrentFile { path, writer: LineWriter::new(file), written: 0 });
+                }
+                // Rotated twice within a millisecond
+                Err(e) if e.kind() == io::ErrorKind::AlreadyExists => millis += 1,
+                Err(e) => return Err(e),
+            }
+        }
+    }
+
+    fn prune_in_background(&self) {
+        let files = self.files.clone();
+        let keep = self.max_files;
+        let prune = move || {
+            if let Err(e) = files.prune(keep) {
+                error!(dir = %files.dir.display(), error = %e, "failed to remove old storage files");
+            }
+        };
+        // Outside a runtime, e.g. in tests, there is no packet path to keep clear
+        match Handle::try_current() {
+            Ok(runtime) => drop(runtime.spawn_blocking(prune)),
+            Err(_) => prune(),
+        }
+    }
+
+    fn load<T: DeserializeOwned>(&self) -> io::Result<Vec<T>> {
+        let mut records = Vec::new();
+        for path in self.files.list()? {
+            let mut skipped = 0;
+            for line in BufReader::new(File::open(&path)?).lines() {
+                // A crash can leave the last line half written
+                match serde_json::from_str(&line?) {
+                    Ok(record) => records.push(record),
+                    Err(_) => skipped += 1,
+                }
+            }
+            if skipped > 0 {
+                warn!(path = %path.display(), skipped, "skipped unreadable stored records");
+            }
+        }
+        Ok(records)
+    }
+
+    fn rewrite<T: Serialize>(&self, records: &[T]) -> io::Result<()> {
+        let file = self.create()?;
+        let path = file.path.clone();
+        *self.current.lock().unwrap() = Some(file);
+        for record in records {
+            self.append(record);
+        }
+        // Anything named before the new file was written by earlier runs
+        for old in self.files.list()? {
+            if old < path {
+                remove(&old)?;
+            }
+        }
+        Ok(())
+    }
+}
+
+// Removes a file unless an overlapping prune already did
+fn remove(path: &Path) -> io::Result<()> {
+    match fs::remove_file(path) {
+        Err(e) if e.kind() != io::ErrorKind::NotFound => Err(e),
+        _ => Ok(()),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn keeps_the_newest_max_files() {
+        let dir = std::env::temp_dir().join(format!("netflex-storage-{}", std::process::id()));
+        fs::create_dir_all(&dir).unwrap();
+        // Every record starts a new file
+        let log = RotatingLog::new(&dir, PACKETS_PREFIX, 1, 2).unwrap();
+        for record in ["a", "b", "c", "d"] {
+            log.append(&record);
+        }
+        let records: Vec<String> = log.load().unwrap();
+        let files = log.files.list().unwrap().len();
+
+        // A restart names its files after the newest existing one
+        let log = RotatingLog::new(&dir, PACKETS_PREFIX, 1, 2).unwrap();
+        log.append(&"e");
+        let after_restart: Vec<String> = log.load().unwrap();
+        fs::remove_dir_all(&dir).unwrap();
+
+        assert_eq!(records, ["c", "d"]);
+        assert_eq!(files, 2);
+        assert_eq!(after_restart, ["d", "e"]);
+    }
+}
@@ -0,0 +1,404 @@
+use std::collections::BTreeMap;
+use std::sync::{Mutex, RwLock};
+use std::time::{Duration, SystemTime};
+use dashmap::DashMap;
+use pnet::packet::tcp::{TcpFlags, TcpPacket};
+use pnet::packet::Packet;
+use tracing::debug;
+
+use crate::config::ReassemblyConfig;
+use crate::flow::FlowKey;
+use crate::l7::{self, Framed, Layer7Message, StreamDecoder};
+
+const DEFAULT_MAX_STREAM_BYTES: usize = 256 * 1024;
+const DEFAULT_MAX_STREAMS: usize = 10_000;
+const DEFAULT_STREAM_TIMEOUT_SECS: u64 = 600;
+
+// Minimum packet time between two sweeps for idle connections
+const SWEEP_INTERVAL: Duration = Duration::from_secs(1);
+// Bytes a direction must have sent before it is given up as undecodable, so a
+// start line split over several segments can still be recognised
+const MIN_SNIFF_BYTES: usize = 16;
+
+#[derive(Clone, Copy)]
+struct StreamLimits {
+    max_bytes: usize,
+    max_streams: usize,
+    timeout: Duration,
+}
+
+impl StreamLimits {
+    fn new(config: Option<&ReassemblyConfig>) -> Self {
+        StreamLimits {
+            max_bytes: config.and_then(|c| c.max_stream_bytes).unwrap_or(DEFAULT_MAX_STREAM_BYTES).max(1),
+            max_streams: config.and_then(|c| c.max_streams).unwrap_or(DEFAULT_MAX_STREAMS).max(1),
+            timeout: Duration::from_secs(
+                config.and_then(|c| c.stream_timeout_secs).unwrap_or(DEFAULT_STREAM_TIMEOUT_SECS),
+            ),
+        }
+    }
+}
+
+enum DecoderState {
+    Undecided,
+    Decoding(&'static dyn StreamDecoder),
+    Bypass, // No decoder wanted the direction, it lost sync, or it overran max_stream_bytes
+}
+
+// One direction of a TCP connection. Sequence numbers are turned into 64-bit stream
+// offsets relative to next_seq, so wraparound past 2^32 needs no special casing.
+struct HalfStream {
+    next_seq: Option<u32>, // Sequence number of the next byte to deliver, from the handshake or the first segment seen
+    delivered: u64,        // Stream offset of next_seq
+    buffer: Vec<u8>,       // Delivered bytes the decoder has not consumed yet
+    skip: usize,           // Bytes still to discard, e.g. the rest of a skipped body
+    out_of_order: BTreeMap<u64, Vec<u8>>, // Segments that arrived ahead of a gap, by stream offset
+    out_of_order_bytes: usize,
+    decoder: DecoderState,
+    finished: bool, // FIN seen
+}
+
+impl HalfStream {
+    fn new() -> Self {
+        HalfStream {
+            next_seq: None,
+            delivered: 0,
+            buffer: Vec::new(),
+            skip: 0,
+            out_of_order: BTreeMap::new(),
+            out_of_order_bytes: 0,
+            decoder: DecoderState::Undecided,
+            finished: false,
+        }
+    }
+
+    // Adds a segment and returns the messages it completed
+    fn segment(&mut self, tcp: &TcpPacket, max_bytes: usize) -> Vec<Layer7Message> {
+        let flags = tcp.get_flags();
+        let mut seq = tcp.get_sequence();
+        if flags & TcpFlags::SYN != 0 {
+            // The SYN takes up one sequence number
+            seq = seq.wrapping_add(1);
+            if self.next_seq.is_none() {
+                self.next_seq = Some(seq);
+            }
+        }
+        if flags & TcpFlags::FIN != 0 {
+            self.finished = true;
+        }
+        if matches!(self.decoder, DecoderState::Bypass) {
+            return Vec::new();
+        }
+
+        let payload = tcp.payload();
+        if !payload.is_empty() {
+            // Picked up mid-connection: the stream starts at the first segment seen
+            let next_seq = *self.next_seq.get_or_insert(seq);
+            // Signed distance from the next expected byte; negative for retransmissions
+            let start = self.delivered as i64 + seq.wrapping_sub(next_seq) as i32 as i64;
+            let end = start + payload.len() as i64;
+            if end > self.delivered as i64 {
+                let trim = (self.delivered as i64 - start).max(0) as usize;
+                // Non-negative once trimmed, even for a retransmission from before the pickup point
+                let start = (start + trim as i64) as u64;
+                let data = &payload[trim..];
+                // Of two segments at the same offset the longer one is kept
+                let held = self.out_of_order.get(&start).map_or(0, Vec::len);
+                if data.len() > held {
+                    self.out_of_order_bytes = self.out_of_order_bytes + data.len() - held;
+                    self.out_of_order.insert(start, data.to_vec());
+                }
+                self.deliver();
+            }
+            if self.buffer.len() + self.out_of_order_bytes > max_bytes {
+                debug!(buffered = self.buffer.len() + self.out_of_order_bytes, "TCP stream over max_stream_bytes, no longer decoded");
+                self.bypass();
+                return Vec::new();
+            }
+        }
+
+        self.decode(tcp.get_source(), tcp.get_destination(), max_bytes)
+    }
+
+    // Moves out-of-order segments that have become contiguous into the buffer
+    fn deliver(&mut self) {
+        while let Some(entry) = self.out_of_order.first_entry() {
+            let start = *entry.key();
+            if start > self.delivered {
+                break;
+            }
+            let data = entry.remove();
+            self.out_of_order_bytes -= data.len();
+            let overlap = (self.delivered - start) as usize;
+            if let Some(tail) = data.get(overlap..).filter(|tail| !tail.is_empty()) {
+                self.buffer.extend_from_slice(tail);
+                self.delivered += tail.len() as u64;
+                if let Some(next_seq) = self.next_seq.as_mut() {
+                    *next_seq = next_seq.wrapping_add(tail.len() as u32);
+                }
+            }
+        }
+    }
+
+    // Runs the direction's decoder over the buffered bytes
+    fn decode(&mut self, src_port: u16, dst_port: u16, max_bytes: usize) -> Vec<Layer7Message> {
+        let mut messages = Vec::new();
+        loop {
+            let skipped = self.skip.min(self.buffer.len());
+            self.buffer.drain(..skipped);
+            self.skip -= skipped;
+            if self.buffer.is_empty() {
+                break;
+            }
+
+            let decoder = match self.decoder {
+                DecoderState::Decoding(decoder) => decoder,
+                DecoderState::Bypass => break,
+                DecoderState::Undecided => match l7::stream_decoder(src_port, dst_port, &self.buffer) {
+                    Some(decoder) => {
+                        self.decoder = DecoderState::Decoding(decoder);
+                        decoder
+                    }
+                    None => {
+                        if self.buffer.len() >= MIN_SNIFF_BYTES || self.finished {
+                            self.bypass();
+                        }
+                        break;
+                    }
+                },
+            };
+
+            match decoder.decode(&self.buffer) {
+                Framed::Frame { message, len } => {
+                    messages.extend(message);
+                    // A frame must make progress or the stream would never advance
+                    let len = len.max(1);
+                    let consumed = len.min(self.buffer.len());
+                    self.buffer.drain(..consumed);
+                    self.skip = len - consumed;
+                }
+                Framed::Incomplete => {
+                    if self.buffer.len() >= max_bytes {
+                        self.bypass();
+                    }
+                    break;
+                }
+                Framed::Invalid => {
+                    self.bypass();
+                    break;
+                }
+            }
+        }
+        messages
+    }
+
+    fn bypass(&mut self) {
+        self.decoder = DecoderState::Bypass;
+        self.buffer = Vec::new();
+        self.out_of_order.clear();
+        self.out_of_order_bytes = 0;
+        self.skip = 0;
+    }
+}
+
+struct Connection {
+    forward: HalfStream, // Sent from the source of the connection's canonical key
+    reverse: HalfStream,
+    last_seen: SystemTime,
+}
+
+// Reassembles the payloads of TCP connections into ordered byte streams, one per
+// direction, and feeds them to the layer 7 stream decoders. Handles out-of-order
+// segments, retransmissions and sequence wraparound; connections are dropped on
+// RST, once both sides sent FIN, or when idle for stream_timeout_secs.
+pub struct StreamTable {
+    connections: DashMap<FlowKey, Connection>,
+    limits: RwLock<StreamLimits>,
+    last_sweep: Mutex<SystemTime>,
+}
+
+impl StreamTable {
+    pub fn new(config: Option<&ReassemblyConfig>) -> Self {
+        StreamTable {
+            connections: DashMap::new(),
+            limits: RwLock::new(StreamLimits::new(config)),
+            last_sweep: Mutex::new(SystemTime::UNIX_EPOCH),
+        }
+    }
+
+    // Applies new limits; buffers already over a lowered max_stream_bytes are cut with their next segment
+    pub fn reconfigure(&self, config: Option<&ReassemblyConfig>) {
+        *self.limits.write().unwrap() = StreamLimits::new(config);
+    }
+
+    // Accounts a TCP segment to its connection and returns the messages it completed
+    pub fn record(&self, key: FlowKey, tcp: &TcpPacket, now: SystemTime) -> Vec<Layer7Message> {
+        let limits = *self.limits.read().unwrap();
+        self.expire_idle(now, limits.timeout);
+
+        let flags = tcp.get_flags();
+        let forward = key == key.canonical();
+        let key = key.canonical();
+        // A reset connection is over
+        if flags & TcpFlags::RST != 0 {
+            self.connections.remove(&key);
+            return Vec::new();
+        }
+        let syn_ack = flags & (TcpFlags::SYN | TcpFlags::ACK) == TcpFlags::SYN | TcpFlags::ACK;
+        if !self.connections.contains_key(&key) {
+            // Tracking starts at the SYN-ACK or the first payload. A bare SYN creates no
+            // state, so a SYN scan of closed ports cannot fill the table.
+            if tcp.payload().is_empty() && !syn_ack {
+                return Vec::new();
+            }
+            if self.connections.len() >= limits.max_streams {
+                debug!("max_streams reached, TCP connection not reassembled");
+                return Vec::new();
+            }
+        }
+
+        let (messages, closed) = {
+            let mut connection = self.connections.entry(key).or_insert_with(|| Connection {
+                forward: HalfStream::new(),
+                reverse: HalfStream::new(),
+                last_seen: now,
+            });
+            connection.last_seen = connection.last_seen.max(now);
+            let connection = &mut *connection;
+            let (half, other) = if forward {
+                (&mut connection.forward, &mut connection.reverse)
+            } else {
+                (&mut connection.reverse, &mut connection.forward)
+            };
+            // The SYN-ACK acknowledges the initiator's SYN, whose own sequence number went untracked
+            if syn_ack {
+                other.next_seq.get_or_insert(tcp.get_acknowledgement());
+            }
+            let messages = half.segment(tcp, limits.max_bytes);
+            (messages, connection.forward.finished && connection.reverse.finished)
+        };
+        if closed {
+            self.connections.remove(&key);
+        }
+        messages
+    }
+
+    pub fn len(&self) -> usize {
+        self.connections.len()
+    }
+
+    // Drops connections that have been idle for longer than the timeout. Time is taken
+    // from packet timestamps so replayed captures age the same way live traffic does.
+    fn expire_idle(&self, now: SystemTime, timeout: Duration) {
+        {
+            let mut last_sweep = self.last_sweep.lock().unwrap();
+            if now.duration_since(*last_sweep).unwrap_or_default() < SWEEP_INTERVAL {
+                return;
+            }
+            *last_sweep = now;
+        }
+        self.connections
+            .retain(|_, connection| now.duration_since(connection.last_seen).unwrap_or_default() < timeout);
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::net::{IpAddr, Ipv4Addr};
+    use crate::l7::http::HttpMessage;
+
+    const CLIENT_PORT: u16 = 49152;
+    const SERVER_PORT: u16 = 80;
+
+    fn client_key() -> FlowKey {
+        FlowKey {
+            protocol: 6,
+            src_ip: Some(IpAddr::V4(Ipv4Addr::new(10, 0, 0, 1))),
+            src_port: Some(CLIENT_PORT),
+            dst_ip: Some(IpAddr::V4(Ipv4Addr::new(10, 0, 0, 2))),
+            dst_port: Some(SERVER_PORT),
+            vlan_id: None,
+            tunnel_id: None,
+        }
+    }
+
+    // TCP header without options followed by `payload`
+    fn segment(from_client: bool, seq: u32, ack: u32, flags: u8, payload: &[u8]) -> Vec<u8> {
+        let (src, dst) = if from_client { (CLIENT_PORT, SERVER_PORT) } else { (SERVER_PORT, CLIENT_PORT) };
+        let mut bytes = Vec::new();
+        bytes.extend_from_slice(&src.to_be_bytes());
+        bytes.extend_from_slice(&dst.to_be_bytes());
+        bytes.extend_from_slice(&seq.to_be_bytes());
+        bytes.extend_from_slice(&ack.to_be_bytes());
+        bytes.extend_from_slice(&[0x50, flags, 0xff, 0xff, 0x00, 0x00, 0x00, 0x00]);
+        bytes.extend_from_slice(payload);
+        bytes
+    }
+
+    fn record(table: &StreamTable, from_client: bool, seq: u32, ack: u32, flags: u8, payload: &[u8]) -> Vec<Layer7Message> {
+        let bytes = segment(from_client, seq, ack, flags, payload);
+        let key = if from_client { client_key() } else { client_key().reversed() };
+        table.record(key, &TcpPacket::new(&bytes).unwrap(), SystemTime::UNIX_EPOCH)
+    }
+
+    fn request_host(messages: &[Layer7Message]) -> Option<String> {
+        match messages {
+            [Layer7Message::Http(HttpMessage::Request { host, .. })] => host.clone(),
+            _ => panic!("expected one HTTP request"),
+        }
+    }
+
+    #[test]
+    fn reorders_segments_after_the_handshake() {
+        let table = StreamTable::new(None);
+        assert!(record(&table, true, 999, 0, TcpFlags::SYN, b"").is_empty());
+        record(&table, false, 5000, 1000, TcpFlags::SYN | TcpFlags::ACK, b"");
+        // The second half of the request arrives ahead of the first
+        assert!(record(&table, true, 1016, 5001, TcpFlags::ACK, b"Host: a\r\n\r\n").is_empty());
+        let messages = record(&table, true, 1000, 5001, TcpFlags::ACK, b"GET / HTTP/1.1\r\n");
+        assert_eq!(request_host(&messages).as_deref(), Some("a"));
+    }
+
+    #[test]
+    fn retransmission_from_before_the_pickup_point_is_trimmed() {
+        let table = StreamTable::new(None);
+        // Picked up mid-connection at sequence number 1000
+        assert!(record(&table, true, 1000, 1, TcpFlags::ACK, b"GET / HTTP/1.1\r\n").is_empty());
+        // Resent from 5 bytes earlier, with new bytes past what was delivered
+        let mut retransmission = b"xxxxxGET / HTTP/1.1\r\n".to_vec();
+        retransmission.extend_from_slice(b"Host: a\r\n\r\n");
+        let messages = record(&table, true, 995, 1, TcpFlags::ACK, &retransmission);
+        assert_eq!(request_host(&messages).as_deref(), Some("a"));
+    }
+
+    #[test]
+    fn bare_syns_create_no_state() {
+        let config = ReassemblyConfig {
+            fragment_timeout_secs: None,
+            max_fragment_bytes: None,
+            max_stream_bytes: None,
+            max_streams: Some(1),
+            stream_timeout_secs: None,
+        };
+        let table = StreamTable::new(Some(&config));
+        for seq in 0..100 {
+            record(&table, true, seq, 0, TcpFlags::SYN, b"");
+        }
+        assert_eq!(table.len(), 0);
+        record(&table, false, 5000, 1, TcpFlags::SYN | TcpFlags::ACK, b"");
+        assert_eq!(table.len(), 1);
+        record(&table, true, 1, 5001, TcpFlags::RST, b"");
+        assert_eq!(table.len(), 0);
+    }
+
+    #[test]
+    fn both_fins_close_the_connection() {
+        let table = StreamTable::new(None);
+        record(&table, true, 1000, 1, TcpFlags::ACK, b"GET / HTTP/1.1\r\n");
+        record(&table, true, 1016, 1, TcpFlags::FIN | TcpFlags::ACK, b"");
+        assert_eq!(table.len(), 1);
+        record(&table, false, 1, 1017, TcpFlags::FIN | TcpFlags::ACK, b"");
+        assert_eq!(table.len(), 0);
+    }
+}
@@ -0,0 +1,178 @@
+use std::collections::HashMap;
+use std::sync::Arc;
+use tokio::sync::watch;
+use tokio::task::JoinSet;
+use tokio::time::{sleep, Duration, Instant};
+use tracing::{info, warn};
+
+use crate::config::NetworkConfig;
+use crate::extractor::InformationExtractor;
+use crate::monitors::{FileMonitor, InterfaceMonitor, TrafficMonitor};
+use crate::output::Output;
+use crate::pipeline::TrafficPipeline;
+use crate::scheduler::ProbeScheduler;
+use crate::storage::Storage;
+
+// Wait before restarting a live capture that failed or ended, doubled after each
+// attempt up to the maximum. A capture that ran for the maximum starts over.
+const RETRY_INITIAL_DELAY: Duration = Duration::from_secs(1);
+const RETRY_MAX_DELAY: Duration = Duration::from_secs(300);
+
+// A configured traffic source, identified the same way across config reloads.
+// Changing an interface's filter restarts its capture. A replay is identified by
+// its path and pacing only, so a new filter does not start a finished replay again.
+#[derive(Debug, Clone, PartialEq, Eq, Hash)]
+enum Source {
+    Interface { name: String, filter: Option<String> },
+    File { path: String, realtime: bool },
+}
+
+// Runs one TrafficPipeline per configured source and starts or stops them as the
+// network section of the config changes. All pipelines share the same extractor,
+// scheduler, output and storage.
+pub struct Supervisor {
+    info_extractor: Arc<InformationExtractor>,
+    scheduler: Option<Arc<ProbeScheduler>>,
+    output: Arc<Output>,
+    storage: Option<Arc<Storage>>,
+    // Every source started so far, finished replays included so they are not restarted.
+    // Live captures are retried until stopped; failed replays are dropped and retried on
+    // the next apply.
+    sources: HashMap<Source, watch::Sender<bool>>,
+    tasks: JoinSet<(Source, bool)>,
+}
+
+impl Supervisor {
+    pub fn new(
+        info_extractor: Arc<InformationExtractor>,
+        scheduler: Option<Arc<ProbeScheduler>>,
+        output: Arc<Output>,
+        storage: Option<Arc<Storage>>,
+    ) -> Self {
+        Supervisor {
+            info_extractor,
+            scheduler,
+            output,
+            storage,
+            sources: HashMap::new(),
+            tasks: JoinSet::new(),
+        }
+    }
+
+    // Starts pipelines for sources that are new in `network` and stops the ones no longer listed
+    pub fn apply(&mut self, network: &NetworkConfig) {
+        let interfaces = network.interfaces.iter().map(|name| Source::Interface {
+            name: name.clone(),
+            filter: network.filter_for(name).map(String::from),
+        });
+        let realtime = network.replay_realtime.unwrap_or(false);
+        let files = network.pcap_files.iter().flatten().map(|path| Source::File {
+            path: path.clone(),
+            realtime,
+        });
+        let wanted: Vec<Source> = interfaces.chain(files).collect();
+
+        self.sources.retain(|source, stop| {
+            if wanted.contains(source) {
+                return true;
+            }
+            info!(?source, "stopping capture removed or changed in config");
+            // Fails only if the pipeline already finished
+            let _ = stop.send(true);
+            false
+        });
+
+        for source in wanted {
+            if self.sources.contains_key(&source) {
+                continue;
+            }
+            let interface_monitor: Arc<dyn TrafficMonitor + Send + Sync> = match &source {
+                Source::Interface { name, filter } => Arc::new(InterfaceMonitor::new(name.clone(), filter.clone())),
+                Source::File { path, realtime } => {
+                    Arc::new(FileMonitor::new(path.clone(), *realtime, network.bpf_filter.clone()))
+                }
+            };
+            let (stop_tx, stop_rx) = watch::channel(false);
+            let pipeline = TrafficPipeline {
+                interface_monitor,
+                info_extractor: Arc::clone(&self.info_extractor),
+                scheduler: self.scheduler.clone(),
+                output: Arc::clone(&self.output),
+                storage: self.storage.clone(),
+                stop: stop_rx,
+            };
+
+            let task_source = source.clone();
+            let retry = matches!(source, Source::Interface { .. });
+            self.tasks.spawn(async move {
+                let mut delay = RETRY_INITIAL_DELAY;
+                loop {
+                    let started = Instant::now();
+                    let completed = pipeline.process_pipeline().await;
+                    let mut stop = pipeline.stop.clone();
+                    if !retry || *stop.borrow() {
+                        return (task_source, completed);
+                    }
+                    if started.elapsed() >= RETRY_MAX_DELAY {
+                        delay = RETRY_INITIAL_DELAY;
+                    }
+                    warn!(source = ?task_source, ?delay, "capture ended, restarting it");
+                    tokio::select! {
+                        _ = sleep(delay) => {}
+                        _ = stop.wait_for(|stop| *stop) => return (task_source, completed),
+                    }
+                    delay = (delay * 2).min(RETRY_MAX_DELAY);
+                }
+            });
+            self.sources.insert(source, stop_tx);
+        }
+    }
+
+    // Waits for the next pipeline to end. Returns false once no pipeline is running.
+    pub async fn join_next(&mut self) -> bool {
+        match self.tasks.join_next().await {
+            Some(Ok((source, completed))) => {
+                info!(?source, completed, "pipeline task ended");
+                // Live captures only end once stopped, and stopped ones are no longer
+                // listed. A closed channel means no newer pipeline for the same source
+                // has been started since.
+                if !completed && self.sources.get(&source).is_some_and(|stop| stop.is_closed()) {
+                    self.sources.remove(&source);
+                }
+                true
+            }
+            Some(Err(e)) => {
+                warn!(error = %e, "pipeline task failed");
+                true
+            }
+            None => false,
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use tokio::time::timeout;
+
+    #[tokio::test]
+    async fn failed_interfaces_are_retried_until_removed() {
+        let mut supervisor = Supervisor::new(
+            Arc::new(InformationExtractor::new(None, None)),
+            None,
+            Arc::new(Output::new(None).unwrap()),
+            None,
+        );
+        let mut network = NetworkConfig { interfaces: vec!["netflex-missing0".to_string()], ..Default::default() };
+        supervisor.apply(&network);
+
+        // The capture cannot start, but the source stays up waiting to retry
+        assert!(timeout(Duration::from_millis(300), supervisor.join_next()).await.is_err());
+        assert_eq!(supervisor.sources.len(), 1);
+
+        network.interfaces.clear();
+        supervisor.apply(&network);
+        assert!(timeout(Duration::from_secs(1), supervisor.join_next()).await.unwrap());
+        assert!(!supervisor.join_next().await);
+    }
+}